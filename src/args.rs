@@ -0,0 +1,439 @@
+use crate::console::OnEof;
+use crate::calls;
+use crate::isa::IsaProfile;
+use crate::sanitizer::{Fill, OnViolation};
+use crate::monitor::OnInterrupt;
+use crate::terminal::DEFAULT_BREAK_KEY;
+use crate::traps::TrapMode;
+
+pub enum Command {
+    Run(Box<Args>),
+    Convert { input: String, output: String },
+    Link { inputs: Vec<String>, output: String, origin: Option<u16> },
+    Assemble { input: String, output: String, listing: Option<String> },
+    Test { specs: Vec<String> },
+}
+impl Command {
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut args = args.peekable();
+
+        match args.peek().map(String::as_str) {
+            Some("convert") => {
+                args.next();
+                let input = args.next().ok_or("missing input file")?;
+                let output = args.next().ok_or("missing output file")?;
+                if let Some(arg) = args.next() {
+                    return Err(format!("unexpected argument: {}", arg));
+                }
+
+                Ok(Command::Convert { input, output })
+            },
+            Some("asm") => {
+                args.next();
+                let mut input = None;
+                let mut output = None;
+                let mut listing = None;
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "-o" | "--output" => output = Some(value(&arg, &mut args)?),
+                        "--listing" => listing = Some(value(&arg, &mut args)?),
+                        option if option.starts_with("--") => return Err(format!("unknown option: {}", option)),
+                        _ if input.is_none() => input = Some(arg),
+                        _ => return Err(format!("unexpected argument: {}", arg)),
+                    }
+                }
+
+                let input = input.ok_or("missing input file")?;
+                let output = output.unwrap_or_else(|| std::path::Path::new(&input).with_extension("obj").display().to_string());
+                Ok(Command::Assemble { input, output, listing })
+            },
+            Some("link") => {
+                args.next();
+                let mut inputs = Vec::new();
+                let mut output = None;
+                let mut origin = None;
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "-o" | "--output" => output = Some(value(&arg, &mut args)?),
+                        "--origin" => {
+                            let address = value(&arg, &mut args)?;
+                            origin = Some(crate::loader::parse_address(&address).ok_or(format!("invalid origin: {}", address))?);
+                        },
+                        option if option.starts_with("--") => return Err(format!("unknown option: {}", option)),
+                        _ => inputs.push(arg),
+                    }
+                }
+
+                if inputs.is_empty() {
+                    return Err("missing input file".to_string());
+                }
+                let output = output.ok_or("missing output file, set it with -o")?;
+
+                Ok(Command::Link { inputs, output, origin })
+            },
+            Some("test") => {
+                args.next();
+                let specs = args.collect::<Vec<_>>();
+                if let Some(option) = specs.iter().find(|spec| spec.starts_with("--")) {
+                    return Err(format!("unknown option: {}", option));
+                }
+                if specs.is_empty() {
+                    return Err("missing spec file".to_string());
+                }
+
+                Ok(Command::Test { specs })
+            },
+            _ => Args::parse(args).map(|args| Command::Run(Box::new(args))),
+        }
+    }
+}
+
+pub struct Args {
+    pub files: Vec<String>,
+    pub os: Option<String>,
+    pub entry: Option<String>,
+    pub microarch: bool,
+    pub microtrace: Option<String>,
+    pub profile: bool,
+    pub flamegraph: Option<String>,
+    pub coverage: Option<String>,
+    pub traps: Option<String>,
+    pub sandbox: Option<String>,
+    pub on_interrupt: OnInterrupt,
+    pub break_key: u8,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub on_eof: OnEof,
+    pub trap_mode: TrapMode,
+    pub isa: IsaProfile,
+    pub sanitize: Option<OnViolation>,
+    pub fill: Option<Fill>,
+    pub check_calls: Option<OnViolation>,
+    pub callee_saved: Vec<u16>,
+    pub stack_limit: Option<u16>,
+}
+impl Args {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut files = Vec::new();
+        let mut os = None;
+        let mut entry = None;
+        let mut microarch = false;
+        let mut microtrace = None;
+        let mut profile = false;
+        let mut flamegraph = None;
+        let mut coverage = None;
+        let mut traps = None;
+        let mut sandbox = None;
+        let mut on_interrupt = OnInterrupt::Prompt;
+        let mut break_key = DEFAULT_BREAK_KEY;
+        let mut record = None;
+        let mut replay = None;
+        let mut on_eof = OnEof::Halt;
+        let mut trap_mode = TrapMode::Plain;
+        let mut isa = IsaProfile::default();
+        let mut sanitize = None;
+        let mut fill = None;
+        let mut check_calls = None;
+        let mut callee_saved = calls::CALLEE_SAVED.to_vec();
+        let mut stack_limit = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--microarch" => microarch = true,
+                "--microtrace" => {
+                    microarch = true;
+                    microtrace = Some(value(&arg, &mut args)?);
+                },
+                "--profile" => profile = true,
+                "--flamegraph" => flamegraph = Some(value(&arg, &mut args)?),
+                "--coverage" => coverage = Some(value(&arg, &mut args)?),
+                "--os" => os = Some(value(&arg, &mut args)?),
+                "--entry" => entry = Some(value(&arg, &mut args)?),
+                "--traps" => traps = Some(value(&arg, &mut args)?),
+                "--sandbox" => sandbox = Some(value(&arg, &mut args)?),
+                "--on-interrupt" => {
+                    let action = value(&arg, &mut args)?;
+                    on_interrupt = OnInterrupt::parse(&action).ok_or(format!("invalid interrupt action: {}, expected prompt or dump", action))?;
+                },
+                "--isa" => {
+                    let profile = value(&arg, &mut args)?;
+                    isa = IsaProfile::parse(&profile)
+                        .ok_or(format!("invalid ISA profile: {}, expected legacy, patt-patel-2e, patt-patel-3e or lc3tools", profile))?;
+                },
+                "--trap-mode" => {
+                    let mode = value(&arg, &mut args)?;
+                    trap_mode = TrapMode::parse(&mode).ok_or(format!("invalid trap mode: {}, expected plain or reference", mode))?;
+                },
+                "--record" => record = Some(value(&arg, &mut args)?),
+                "--replay" => replay = Some(value(&arg, &mut args)?),
+                "--on-eof" => {
+                    let policy = value(&arg, &mut args)?;
+                    on_eof = OnEof::parse(&policy).ok_or(format!("invalid end of input policy: {}, expected halt, block or a value", policy))?;
+                },
+                "--sanitize" => {
+                    let action = value(&arg, &mut args)?;
+                    sanitize = Some(OnViolation::parse(&action).ok_or(format!("invalid sanitizer action: {}, expected warn or stop", action))?);
+                },
+                "--fill" => {
+                    let text = value(&arg, &mut args)?;
+                    fill = Some(Fill::parse(&text).ok_or(format!("invalid fill: {}, expected random or a word", text))?);
+                },
+                "--check-calls" => {
+                    let action = value(&arg, &mut args)?;
+                    check_calls = Some(OnViolation::parse(&action).ok_or(format!("invalid call check action: {}, expected warn or stop", action))?);
+                },
+                "--callee-saved" => {
+                    let registers = value(&arg, &mut args)?;
+                    callee_saved = calls::parse_registers(&registers).ok_or(format!("invalid registers: {}, expected a list like R1-R3,R5", registers))?;
+                },
+                "--stack-limit" => {
+                    let limit = value(&arg, &mut args)?;
+                    stack_limit = Some(crate::loader::parse_address(&limit).ok_or(format!("invalid stack limit: {}", limit))?);
+                },
+                "--break-key" => {
+                    let key = value(&arg, &mut args)?;
+                    break_key = parse_key(&key).ok_or(format!("invalid break key: {}", key))?;
+                },
+                option if option.starts_with("--") => return Err(format!("unknown option: {}", option)),
+                _ => files.push(arg),
+            }
+        }
+
+        if files.is_empty() {
+            return Err("missing file path".to_string());
+        }
+        if record.is_some() && replay.is_some() {
+            return Err("--record and --replay cannot be used together".to_string());
+        }
+
+        Ok(Args {
+            files,
+            os,
+            entry,
+            microarch,
+            microtrace,
+            profile,
+            flamegraph,
+            coverage,
+            traps,
+            sandbox,
+            on_interrupt,
+            break_key,
+            record,
+            replay,
+            on_eof,
+            trap_mode,
+            isa,
+            sanitize,
+            fill,
+            check_calls,
+            callee_saved,
+            stack_limit,
+        })
+    }
+}
+
+// A key as `^C`, a single character or its code (`x1D`).
+fn parse_key(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('^'), Some(c), None) if ('@'..='_').contains(&c.to_ascii_uppercase()) => Some(c.to_ascii_uppercase() as u8 - b'@'),
+        (Some(c), None, _) if c.is_ascii() => Some(c as u8),
+        _ => crate::loader::parse_address(text).and_then(|key| u8::try_from(key).ok()),
+    }
+}
+
+fn value<I: Iterator<Item = String>>(option: &str, args: &mut I) -> Result<String, String> {
+    args.next().ok_or(format!("missing value for {}", option))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn file_path() {
+        let args = parse(&["2048.obj"]).unwrap();
+
+        assert_eq!(args.files, vec!["2048.obj"]);
+        assert!(!args.microarch);
+        assert!(args.microtrace.is_none());
+    }
+
+    #[test]
+    fn multiple_files() {
+        let args = parse(&["--os", "os.obj", "main.obj", "lib.obj", "--entry", "START"]).unwrap();
+
+        assert_eq!(args.files, vec!["main.obj", "lib.obj"]);
+        assert_eq!(args.os.as_deref(), Some("os.obj"));
+        assert_eq!(args.entry.as_deref(), Some("START"));
+    }
+
+    #[test]
+    fn microarch() {
+        let args = parse(&["--microarch", "2048.obj"]).unwrap();
+        assert!(args.microarch);
+
+        let args = parse(&["2048.obj", "--microtrace", "trace.txt"]).unwrap();
+        assert!(args.microarch);
+        assert_eq!(args.microtrace.as_deref(), Some("trace.txt"));
+    }
+
+    #[test]
+    fn profile() {
+        let args = parse(&["--profile", "rogue.obj", "--flamegraph", "rogue.folded"]).unwrap();
+
+        assert!(args.profile);
+        assert_eq!(args.flamegraph.as_deref(), Some("rogue.folded"));
+        assert!(!parse(&["rogue.obj"]).unwrap().profile);
+    }
+
+    #[test]
+    fn coverage() {
+        let args = parse(&["test.obj", "--coverage", "test.lst"]).unwrap();
+
+        assert_eq!(args.coverage.as_deref(), Some("test.lst"));
+    }
+
+    #[test]
+    fn traps() {
+        let args = parse(&["test.obj", "--traps", "traps.cfg", "--sandbox", "saves"]).unwrap();
+
+        assert_eq!(args.traps.as_deref(), Some("traps.cfg"));
+        assert_eq!(args.sandbox.as_deref(), Some("saves"));
+    }
+
+    #[test]
+    fn sessions() {
+        let args = parse(&["2048.obj", "--record", "bug.lc3rec"]).unwrap();
+        assert_eq!((args.record.as_deref(), args.replay.as_deref()), (Some("bug.lc3rec"), None));
+
+        let args = parse(&["2048.obj", "--replay", "bug.lc3rec"]).unwrap();
+        assert_eq!((args.record.as_deref(), args.replay.as_deref()), (None, Some("bug.lc3rec")));
+
+        assert_eq!(
+            parse(&["2048.obj", "--record", "a.lc3rec", "--replay", "b.lc3rec"]).err().unwrap(),
+            "--record and --replay cannot be used together",
+        );
+    }
+
+    #[test]
+    fn interrupts() {
+        let args = parse(&["test.obj"]).unwrap();
+        assert_eq!((args.on_interrupt, args.break_key), (OnInterrupt::Prompt, 0x03));
+
+        let args = parse(&["test.obj", "--on-interrupt", "dump", "--break-key", "^]"]).unwrap();
+        assert_eq!((args.on_interrupt, args.break_key), (OnInterrupt::Dump, 0x1D));
+
+        assert_eq!(parse_key("x1B"), Some(0x1B));
+        assert_eq!(parse_key("q"), Some(b'q'));
+        assert_eq!(parse(&["test.obj", "--break-key", "^^^"]).err().unwrap(), "invalid break key: ^^^");
+        assert_eq!(
+            parse(&["test.obj", "--on-interrupt", "exit"]).err().unwrap(),
+            "invalid interrupt action: exit, expected prompt or dump",
+        );
+    }
+
+    #[test]
+    fn end_of_input() {
+        assert_eq!(parse(&["test.obj"]).unwrap().on_eof, OnEof::Halt);
+        assert_eq!(parse(&["test.obj", "--on-eof", "xFFFF"]).unwrap().on_eof, OnEof::Value(0xFFFF));
+        assert_eq!(
+            parse(&["test.obj", "--on-eof", "wait"]).err().unwrap(),
+            "invalid end of input policy: wait, expected halt, block or a value",
+        );
+    }
+
+    #[test]
+    fn trap_mode() {
+        assert_eq!(parse(&["test.obj"]).unwrap().trap_mode, TrapMode::Plain);
+        assert_eq!(parse(&["test.obj", "--trap-mode", "reference"]).unwrap().trap_mode, TrapMode::Reference);
+        assert_eq!(parse(&["test.obj", "--trap-mode", "lc3"]).err().unwrap(), "invalid trap mode: lc3, expected plain or reference");
+    }
+
+    #[test]
+    fn isa_profile() {
+        assert_eq!(parse(&["test.obj"]).unwrap().isa, IsaProfile::Legacy);
+        assert_eq!(parse(&["test.obj", "--isa", "lc3tools"]).unwrap().isa, IsaProfile::Lc3tools);
+        assert!(parse(&["test.obj", "--isa", "4e"]).is_err());
+    }
+
+    #[test]
+    fn sanitizer() {
+        let args = parse(&["test.obj"]).unwrap();
+        assert!(args.sanitize.is_none() && args.fill.is_none());
+
+        let args = parse(&["test.obj", "--sanitize", "stop", "--fill", "random"]).unwrap();
+        assert_eq!(args.sanitize, Some(OnViolation::Stop));
+        assert_eq!(args.fill, Some(Fill::Random));
+        assert_eq!(parse(&["test.obj", "--fill", "xDEAD"]).unwrap().fill, Some(Fill::Value(0xDEAD)));
+        assert_eq!(parse(&["test.obj", "--sanitize", "all"]).err().unwrap(), "invalid sanitizer action: all, expected warn or stop");
+    }
+
+    #[test]
+    fn call_checker() {
+        let args = parse(&["test.obj", "--check-calls", "warn"]).unwrap();
+        assert_eq!(args.check_calls, Some(OnViolation::Warn));
+        assert_eq!(args.callee_saved, vec![1, 2, 3, 4, 5]);
+        assert!(args.stack_limit.is_none());
+
+        let args = parse(&["test.obj", "--check-calls", "stop", "--callee-saved", "R4-R5", "--stack-limit", "x3F00"]).unwrap();
+        assert_eq!(args.callee_saved, vec![4, 5]);
+        assert_eq!(args.stack_limit, Some(0x3F00));
+        assert_eq!(
+            parse(&["test.obj", "--callee-saved", "R9"]).err().unwrap(),
+            "invalid registers: R9, expected a list like R1-R3,R5",
+        );
+    }
+
+    #[test]
+    fn convert() {
+        let command = Command::parse(["convert", "test.obj", "test.hex"].iter().map(|arg| arg.to_string())).unwrap();
+        assert!(matches!(command, Command::Convert { input, output } if input == "test.obj" && output == "test.hex"));
+
+        let command = Command::parse(["convert", "test.obj"].iter().map(|arg| arg.to_string()));
+        assert_eq!(command.err().unwrap(), "missing output file");
+
+        let command = Command::parse(["test.obj"].iter().map(|arg| arg.to_string())).unwrap();
+        assert!(matches!(command, Command::Run(_)));
+    }
+
+    #[test]
+    fn link() {
+        let command = Command::parse(["link", "main.robj", "lib.robj", "-o", "main.obj", "--origin", "x4000"].iter().map(|arg| arg.to_string())).unwrap();
+        assert!(matches!(command, Command::Link { inputs, output, origin: Some(0x4000) } if inputs.len() == 2 && output == "main.obj"));
+
+        let command = Command::parse(["link", "main.robj"].iter().map(|arg| arg.to_string()));
+        assert_eq!(command.err().unwrap(), "missing output file, set it with -o");
+    }
+
+    #[test]
+    fn assemble() {
+        let command = Command::parse(["asm", "game.asm"].iter().map(|arg| arg.to_string())).unwrap();
+        assert!(matches!(command, Command::Assemble { input, output, listing: None } if input == "game.asm" && output == "game.obj"));
+
+        let command = Command::parse(["asm", "game.asm", "-o", "game.hex", "--listing", "game.lst"].iter().map(|arg| arg.to_string())).unwrap();
+        assert!(matches!(command, Command::Assemble { output, listing: Some(listing), .. } if output == "game.hex" && listing == "game.lst"));
+    }
+
+    #[test]
+    fn test_specs() {
+        let command = Command::parse(["test", "a.lc3test", "b.lc3test"].iter().map(|arg| arg.to_string())).unwrap();
+        assert!(matches!(command, Command::Test { specs } if specs == ["a.lc3test", "b.lc3test"]));
+
+        let command = Command::parse(["test"].iter().map(|arg| arg.to_string()));
+        assert_eq!(command.err().unwrap(), "missing spec file");
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse(&[]).err().unwrap(), "missing file path");
+        assert_eq!(parse(&["--os", "os.obj"]).err().unwrap(), "missing file path");
+        assert_eq!(parse(&["--fast", "a.obj"]).err().unwrap(), "unknown option: --fast");
+        assert_eq!(parse(&["a.obj", "--microtrace"]).err().unwrap(), "missing value for --microtrace");
+    }
+}
@@ -0,0 +1,657 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::debuginfo::{DebugInfo, SourceLine};
+use crate::expression;
+use crate::formats::Format;
+use crate::preprocess::{self, Line, Location, Preprocessor};
+use crate::symbols::SymbolTable;
+
+const MNEMONICS: [&str; 24] = [
+    "ADD", "AND", "NOT", "BR", "JMP", "RET", "JSR", "JSRR", "LD", "LDI", "LDR", "LEA", "ST", "STI", "STR", "TRAP", "RTI", "GETC",
+    "OUT", "PUTS", "IN", "PUTSP", "HALT", "NOP",
+];
+
+const PSEUDO_INSTRUCTIONS: [&str; 6] = ["LDI16", "PUSH", "POP", "CLR", "MOV", "CALL"];
+
+// Labels of the words in the literal pool. No label in the source has a space, so they cannot clash, and they stay
+// out of the symbol table.
+const LITERAL_PREFIX: &str = "<literal ";
+
+// Macro expansions nested deeper than this are summarised in error messages.
+const SHOWN_EXPANSIONS: usize = 4;
+
+#[derive(Debug)]
+pub enum AsmError {
+    Io(String, io::Error),
+    At(Location, String),
+    NoOrigin,
+}
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::Io(path, err) => write!(f, "{}: {}", path, err),
+            AsmError::At(location, message) => {
+                write!(f, "{}: {}", location, message)?;
+
+                let mut expansion = &location.expansion;
+                for _ in 0..SHOWN_EXPANSIONS {
+                    match expansion {
+                        Some((name, at)) => write!(f, "\n  in macro {} expanded at {}", name, at)?,
+                        None => return Ok(()),
+                    }
+                    expansion = &expansion.as_ref().unwrap().1.expansion;
+                }
+                if expansion.is_some() {
+                    write!(f, "\n  ...")?;
+                }
+
+                Ok(())
+            },
+            AsmError::NoOrigin => write!(f, "program has no .ORIG, nothing to assemble"),
+        }
+    }
+}
+
+pub struct Program {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    pub symbols: SymbolTable,
+    pub lines: Vec<Listed>,
+    pub debug_info: DebugInfo,
+}
+
+// A line of the program with the address it was placed at and the words it assembled to.
+pub struct Listed {
+    pub line: Line,
+    pub address: Option<u16>,
+    pub words: Vec<u16>,
+}
+impl Program {
+    // The program as `Hardware::load` takes it, starting with the origin.
+    pub fn image(&self) -> Vec<u16> {
+        let mut image = vec![self.origin];
+        image.extend(&self.words);
+        image
+    }
+}
+
+pub fn is_mnemonic(word: &str) -> bool {
+    let word = word.to_ascii_uppercase();
+    MNEMONICS.contains(&word.as_str()) || PSEUDO_INSTRUCTIONS.contains(&word.as_str()) || branch_conditions(&word).is_some()
+}
+
+// The nzp bits of `BRnzp`, plain `BR` is always taken.
+fn branch_conditions(operation: &str) -> Option<u16> {
+    let flags = operation.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some(0b111);
+    }
+
+    let mut conditions = 0;
+    let mut last = 3;
+    for flag in flags.chars() {
+        let bit = match flag {
+            'N' => 2,
+            'Z' => 1,
+            'P' => 0,
+            _ => return None,
+        };
+        if bit >= last {
+            return None;
+        }
+        conditions |= 1 << bit;
+        last = bit;
+    }
+
+    Some(conditions)
+}
+
+fn register(text: &str) -> Option<u16> {
+    match text.as_bytes() {
+        [b'R' | b'r', digit @ b'0'..=b'7'] => Some((digit - b'0') as u16),
+        _ => None,
+    }
+}
+
+pub fn assemble_file(input: &Path) -> Result<Program, AsmError> {
+    let mut preprocessor = Preprocessor::default();
+    preprocessor.process_file(input, None)?;
+    assemble(&preprocessor.lines)
+}
+
+// Writes the program in the format given by the extension of `output`, with its symbol table next to it.
+pub fn write(program: &Program, output: &str) -> Result<(), AsmError> {
+    let format = Format::from_extension(output).unwrap_or(Format::Object);
+    let symbols_path = Path::new(output).with_extension("sym");
+    let debug_info_path = Path::new(output).with_extension("dbg");
+
+    fs::write(output, format.write(&program.image())).map_err(|err| AsmError::Io(output.to_string(), err))?;
+    fs::write(&symbols_path, program.symbols.to_lc3as()).map_err(|err| AsmError::Io(symbols_path.display().to_string(), err))?;
+    fs::write(&debug_info_path, program.debug_info.to_text(&program.symbols))
+        .map_err(|err| AsmError::Io(debug_info_path.display().to_string(), err))?;
+
+    Ok(())
+}
+
+pub fn assemble(lines: &[Line]) -> Result<Program, AsmError> {
+    let lines = expand(lines)?;
+    let mut labels = HashMap::<&str, (u16, &Location)>::new();
+    let mut placed = Vec::new();
+    let mut origin = None;
+    let mut address = 0;
+
+    // The first pass only places the lines, so labels can be used before they are defined.
+    for line in &lines {
+        let error = |message: String| AsmError::At(line.location.clone(), message);
+        let operation = line.operation.as_deref();
+
+        if operation == Some(".END") {
+            placed.push((line, None));
+            break;
+        }
+        if operation == Some(".ORIG") {
+            if origin.is_some() {
+                return Err(error("program already has an .ORIG, only one is allowed".to_string()));
+            }
+            let value = constant(operand(line, 0, 1).map_err(error)?).map_err(error)?;
+            if !(0..=0xFFFF).contains(&value) {
+                return Err(error(format!("origin {} is not an address", value)));
+            }
+            origin = Some(value as u16);
+            address = value as usize;
+        }
+
+        if let Some(label) = &line.label {
+            if origin.is_none() {
+                return Err(error(format!("label {} is before .ORIG", label)));
+            }
+            if let Some((_, first)) = labels.get(label.as_str()) {
+                return Err(error(format!("label {} is already defined at {}", label, first)));
+            }
+            labels.insert(label, (address as u16, &line.location));
+        }
+
+        match operation {
+            Some(".ORIG") => {},
+            Some(operation) if origin.is_none() => return Err(error(format!("{} is before .ORIG", operation))),
+            _ => {},
+        }
+
+        placed.push((line, origin.map(|_| address as u16)));
+        if operation.is_some_and(|operation| operation != ".ORIG") {
+            address += size(line).map_err(error)?;
+        }
+        if address > 0x10000 {
+            return Err(error("program does not fit in memory, it continues past xFFFF".to_string()));
+        }
+    }
+
+    let origin = origin.ok_or(AsmError::NoOrigin)?;
+    let mut words = Vec::new();
+    let mut listed = Vec::new();
+    let mut debug_info = DebugInfo::default();
+    for (line, address) in placed {
+        let encoded = match (line.operation.as_deref(), address) {
+            (None | Some(".ORIG" | ".END"), _) | (_, None) => Vec::new(),
+            (_, Some(address)) => encode(line, address, &labels).map_err(|message| AsmError::At(line.location.clone(), message))?,
+        };
+        // Words written by macros and pseudo-instructions belong to the line that used them
+        let source = line.location.origin();
+        for offset in 0..encoded.len() {
+            let source = SourceLine { file: source.file.clone(), line: source.line, column: source.column };
+            debug_info.insert(address.unwrap_or_default().wrapping_add(offset as u16), source);
+        }
+        words.extend(&encoded);
+        listed.push(Listed { line: line.clone(), address, words: encoded });
+    }
+
+    let mut symbols = SymbolTable::default();
+    for (name, (address, _)) in labels.into_iter().filter(|(name, _)| !name.starts_with(LITERAL_PREFIX)) {
+        symbols.insert(name, address);
+    }
+
+    Ok(Program { origin, words, symbols, lines: listed, debug_info })
+}
+
+// Keeps track of the constants loaded by pseudo-instructions until they are placed.
+#[derive(Default)]
+struct LiteralPool {
+    literals: Vec<(String, String)>,
+    count: usize,
+}
+impl LiteralPool {
+    // The label of the word holding `value`, equal values share it.
+    fn literal(&mut self, value: &str) -> String {
+        if let Some((label, _)) = self.literals.iter().find(|(_, literal)| literal == value) {
+            return label.clone();
+        }
+
+        self.count += 1;
+        let label = format!("{}{}>", LITERAL_PREFIX, self.count);
+        self.literals.push((label.clone(), value.to_string()));
+        label
+    }
+
+    fn place(&mut self, at: &Line, lines: &mut Vec<Line>) {
+        for (label, value) in self.literals.drain(..) {
+            lines.push(generated(at, Some(label), ".FILL", &[&value]));
+        }
+    }
+}
+
+fn generated(line: &Line, label: Option<String>, operation: &str, operands: &[&str]) -> Line {
+    let text = format!("{} {} {}", label.as_deref().unwrap_or_default(), operation, operands.join(", "));
+    Line {
+        location: line.location.clone(),
+        label,
+        operation: Some(operation.to_string()),
+        operands: operands.iter().map(|operand| operand.to_string()).collect(),
+        text: text.trim().to_string(),
+        generated: true,
+    }
+}
+
+// Replaces the pseudo-instructions with the instructions they stand for, keeping the original line for listings.
+// The constants they load go to the literal pool, placed at `.POOL` or at the end of the program.
+fn expand(lines: &[Line]) -> Result<Vec<Line>, AsmError> {
+    let mut expanded = Vec::new();
+    let mut pool = LiteralPool::default();
+
+    for line in lines {
+        let error = |message: String| AsmError::At(line.location.clone(), message);
+        let operation = line.operation.as_deref().unwrap_or_default();
+        if !PSEUDO_INSTRUCTIONS.contains(&operation) && operation != ".POOL" {
+            if operation == ".END" {
+                pool.place(line, &mut expanded);
+            }
+            expanded.push(line.clone());
+            continue;
+        }
+
+        expanded.push(Line { operation: None, operands: Vec::new(), ..line.clone() });
+        let operand = |index, count| operand(line, index, count).map_err(error);
+        match operation {
+            "LDI16" => {
+                let literal = pool.literal(operand(1, 2)?);
+                expanded.push(generated(line, None, "LD", &[operand(0, 2)?, &literal]));
+            },
+            "PUSH" => {
+                expanded.push(generated(line, None, "ADD", &["R6", "R6", "#-1"]));
+                expanded.push(generated(line, None, "STR", &[operand(0, 1)?, "R6", "#0"]));
+            },
+            "POP" => {
+                expanded.push(generated(line, None, "LDR", &[operand(0, 1)?, "R6", "#0"]));
+                expanded.push(generated(line, None, "ADD", &["R6", "R6", "#1"]));
+            },
+            "CLR" => expanded.push(generated(line, None, "AND", &[operand(0, 1)?, operand(0, 1)?, "#0"])),
+            "MOV" => expanded.push(generated(line, None, "ADD", &[operand(0, 2)?, operand(1, 2)?, "#0"])),
+            "CALL" => {
+                let literal = pool.literal(operand(0, 1)?);
+                expanded.push(generated(line, None, "LD", &["R7", &literal]));
+                expanded.push(generated(line, None, "JSRR", &["R7"]));
+            },
+            _ => {
+                expect(line, 0).map_err(error)?;
+                pool.place(line, &mut expanded);
+            },
+        }
+    }
+
+    if let Some(last) = lines.last() {
+        pool.place(last, &mut expanded);
+    }
+
+    Ok(expanded)
+}
+
+fn expect(line: &Line, count: usize) -> Result<(), String> {
+    let operation = line.operation.as_deref().unwrap_or_default();
+    if line.operands.len() != count {
+        return Err(format!("{} takes {} operands, got {}", operation, count, line.operands.len()));
+    }
+
+    Ok(())
+}
+
+fn operand(line: &Line, index: usize, count: usize) -> Result<&str, String> {
+    expect(line, count)?;
+    Ok(&line.operands[index])
+}
+
+fn constant(text: &str) -> Result<i32, String> {
+    expression::evaluate(text, &mut |_| None)
+}
+
+fn size(line: &Line) -> Result<usize, String> {
+    match line.operation.as_deref().unwrap_or_default() {
+        ".BLKW" => match constant(operand(line, 0, 1)?)? {
+            count if count >= 0 => Ok(count as usize),
+            count => Err(format!(".BLKW of {} words", count)),
+        },
+        ".STRINGZ" => Ok(preprocess::parse_string(operand(line, 0, 1)?)?.chars().count() + 1),
+        _ => Ok(1),
+    }
+}
+
+fn encode(line: &Line, address: u16, labels: &HashMap<&str, (u16, &Location)>) -> Result<Vec<u16>, String> {
+    let operation = line.operation.as_deref().unwrap_or_default();
+    let operand = |index, count| operand(line, index, count);
+    let expect = |count| expect(line, count);
+
+    // A value with a label in it is an address, anything else is taken as is.
+    let value = |text: &str| {
+        // Expressions have no spaces in their names, so the literal pool labels are looked up as they are
+        if let Some((address, _)) = labels.get(text).filter(|_| text.starts_with(LITERAL_PREFIX)) {
+            return Ok((*address as i32, true));
+        }
+        let mut is_address = false;
+        let value = expression::evaluate(text, &mut |name| {
+            is_address = true;
+            labels.get(name).map(|(address, _)| *address as i32)
+        })?;
+        Ok::<_, String>((value, is_address))
+    };
+    let register = |text: &str| register(text).ok_or(format!("expected a register R0-R7, got `{}`", text));
+    let immediate = |text: &str, name: &str, bits: u32, signed: bool| {
+        let (value, _) = value(text)?;
+        let (min, max) = if signed { (-(1 << (bits - 1)), (1 << (bits - 1)) - 1) } else { (0, (1 << bits) - 1) };
+        if value < min || value > max {
+            return Err(format!("{} does not fit in {}, which holds {} to {}", text, name, min, max));
+        }
+        Ok(value as u16 & ((1 << bits) - 1))
+    };
+    let offset = |text: &str, bits: u32| {
+        let (value, is_address) = value(text)?;
+        let distance = if is_address { value - (address as i32 + 1) } else { value };
+        let half = 1 << (bits - 1);
+        if distance < -half || distance >= half {
+            let target = if text.starts_with(LITERAL_PREFIX) { "the literal pool" } else { text };
+            let hint = if text.starts_with(LITERAL_PREFIX) { ", place a .POOL closer" } else { "" };
+            return Err(format!("{} is {} words away, PCoffset{} holds {} to {}{}", target, distance, bits, -half, half - 1, hint));
+        }
+        Ok(distance as u16 & ((1 << bits) - 1))
+    };
+
+    let word = match operation {
+        "ADD" | "AND" => {
+            let opcode = if operation == "ADD" { 0b0001 } else { 0b0101 };
+            let destination = register(operand(0, 3)?)?;
+            let source = register(operand(1, 3)?)?;
+            let second = match self::register(operand(2, 3)?) {
+                Some(register) => register,
+                None => 1 << 5 | immediate(operand(2, 3)?, "imm5", 5, true)?,
+            };
+            opcode << 12 | destination << 9 | source << 6 | second
+        },
+        "NOT" => 0b1001 << 12 | register(operand(0, 2)?)? << 9 | register(operand(1, 2)?)? << 6 | 0b11_1111,
+        "JMP" => 0b1100 << 12 | register(operand(0, 1)?)? << 6,
+        "RET" => expect(0).map(|_| 0b1100_0001_1100_0000)?,
+        "JSR" => 0b0100 << 12 | 1 << 11 | offset(operand(0, 1)?, 11)?,
+        "JSRR" => 0b0100 << 12 | register(operand(0, 1)?)? << 6,
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            let opcode = match operation {
+                "LD" => 0b0010,
+                "LDI" => 0b1010,
+                "LEA" => 0b1110,
+                "ST" => 0b0011,
+                _ => 0b1011,
+            };
+            opcode << 12 | register(operand(0, 2)?)? << 9 | offset(operand(1, 2)?, 9)?
+        },
+        "LDR" | "STR" => {
+            let opcode = if operation == "LDR" { 0b0110 } else { 0b0111 };
+            opcode << 12 | register(operand(0, 3)?)? << 9 | register(operand(1, 3)?)? << 6 | immediate(operand(2, 3)?, "offset6", 6, true)?
+        },
+        "TRAP" => 0b1111 << 12 | immediate(operand(0, 1)?, "trapvect8", 8, false)?,
+        "RTI" => expect(0).map(|_| 0b1000 << 12)?,
+        "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" => {
+            expect(0)?;
+            let vector = ["GETC", "OUT", "PUTS", "IN", "PUTSP", "HALT"].iter().position(|trap| *trap == operation).unwrap() as u16;
+            0b1111 << 12 | (0x20 + vector)
+        },
+        "NOP" => expect(0).map(|_| 0)?,
+        ".FILL" => {
+            let text = operand(0, 1)?;
+            match value(text)?.0 {
+                value @ -0x8000..=0xFFFF => value as u16,
+                value => return Err(format!("{} does not fit in a word", value)),
+            }
+        },
+        ".BLKW" => return Ok(vec![0; size(line)?]),
+        ".STRINGZ" => {
+            let string = preprocess::parse_string(operand(0, 1)?)?;
+            return Ok(string.chars().map(|c| c as u16).chain([0]).collect());
+        },
+        _ if operation.starts_with('.') => return Err(format!("unknown directive {}", operation)),
+        _ => match branch_conditions(operation) {
+            Some(conditions) => conditions << 9 | offset(operand(0, 1)?, 9)?,
+            None => return Err(format!("unknown instruction {}", operation)),
+        },
+    };
+
+    Ok(vec![word])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run;
+    use crate::utils::setup_default_test;
+
+    fn assemble_source(text: &str) -> Result<Program, AsmError> {
+        let mut preprocessor = Preprocessor::default();
+        preprocessor.process_source("main.asm", text)?;
+        assemble(&preprocessor.lines)
+    }
+
+    #[test]
+    fn instructions() {
+        let program = assemble_source(
+            ".ORIG x3000
+LOOP    ADD R1, R2, R3
+        AND R1, R1, #-16
+        NOT R4, R5
+        BRnp LOOP
+        BR #-1
+        JMP R2
+        RET
+        JSR LOOP
+        JSRR R3
+        LD R0, DATA
+        LDI R1, DATA
+        LDR R2, R6, #-32
+        LEA R3, DATA
+        ST R4, DATA
+        STI R5, DATA
+        STR R6, R5, x1F
+        TRAP x25
+        RTI
+        GETC
+        HALT
+        NOP
+DATA    .FILL LOOP + 1
+        .END
+        ADD R1, R1, R1",
+        )
+        .unwrap();
+
+        assert_eq!(program.origin, 0x3000);
+        assert_eq!(program.words, vec![
+            0b0001_0010_1000_0011,
+            0b0101_0010_0111_0000,
+            0b1001_1001_0111_1111,
+            0b0000_1011_1111_1100,
+            0b0000_1111_1111_1111,
+            0b1100_0000_1000_0000,
+            0b1100_0001_1100_0000,
+            0b0100_1111_1111_1000,
+            0b0100_0000_1100_0000,
+            0b0010_0000_0000_1011,
+            0b1010_0010_0000_1010,
+            0b0110_0101_1010_0000,
+            0b1110_0110_0000_1000,
+            0b0011_1000_0000_0111,
+            0b1011_1010_0000_0110,
+            0b0111_1101_0101_1111,
+            0b1111_0000_0010_0101,
+            0b1000_0000_0000_0000,
+            0b1111_0000_0010_0000,
+            0b1111_0000_0010_0101,
+            0b0000_0000_0000_0000,
+            0x3001,
+        ]);
+        assert_eq!(program.symbols.get("DATA"), Some(0x3015));
+    }
+
+    #[test]
+    fn data() {
+        let program = assemble_source(".ORIG x4000\nA .STRINGZ \"Hi\\n\"\nB .BLKW 2\nC .FILL #-1\n.END").unwrap();
+
+        assert_eq!(program.image(), vec![0x4000, 0x48, 0x69, 0x0A, 0, 0, 0, 0xFFFF]);
+        assert_eq!(program.symbols.get("C"), Some(0x4006));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| assemble_source(text).err().unwrap().to_string();
+
+        assert_eq!(error("ADD R1, R1, #1"), "main.asm:1: ADD is before .ORIG");
+        assert_eq!(error(".ORIG x3000\nADD R1, R1, #16"), "main.asm:2: #16 does not fit in imm5, which holds -16 to 15");
+        assert_eq!(error(".ORIG x3000\nADD R1, R8, #1"), "main.asm:2: expected a register R0-R7, got `R8`");
+        assert_eq!(error(".ORIG x3000\nADD R1, R1"), "main.asm:2: ADD takes 3 operands, got 2");
+        assert_eq!(error(".ORIG x3000\nBR FAR\n.BLKW 300\nFAR HALT"), "main.asm:2: FAR is 300 words away, PCoffset9 holds -256 to 255");
+        assert_eq!(error(".ORIG x3000\nLD R0, MISSING"), "main.asm:2: unknown symbol MISSING");
+        assert_eq!(error(".ORIG x3000\nA HALT\nA HALT"), "main.asm:3: label A is already defined at main.asm:2");
+        assert_eq!(error(".ORIG x3000\nMUL R1, R1, R1"), "main.asm:2: unknown instruction MUL");
+        assert_eq!(error(".ORIG x3000\n.WORD 1"), "main.asm:2: unknown directive .WORD");
+        assert_eq!(error(".ORIG x3000\nHALT R0"), "main.asm:2: HALT takes 0 operands, got 1");
+        assert_eq!(error(".ORIG xFFFF\nHALT\nHALT"), "main.asm:3: program does not fit in memory, it continues past xFFFF");
+        assert_eq!(error("; nothing"), "program has no .ORIG, nothing to assemble");
+    }
+
+    #[test]
+    fn macro_errors() {
+        let text = ".ORIG x3000
+.MACRO INCREMENT \\reg, \\by
+    ADD \\reg, \\reg, \\by
+.ENDM
+.MACRO TWICE \\reg
+    INCREMENT \\reg, #20
+.ENDM
+    TWICE R1
+.END";
+        let err = assemble_source(text).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "main.asm:3: #20 does not fit in imm5, which holds -16 to 15\n  in macro INCREMENT expanded at main.asm:6\n  in macro TWICE expanded at main.asm:8",
+        );
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        let program = assemble_source(
+            ".ORIG x3000
+        LDI16 R6, xFE00
+        LDI16 R1, #1234
+        MOV R2, R1
+        PUSH R2
+        CLR R2
+        POP R3
+        CALL FAR
+        HALT
+        .POOL
+        .BLKW 600
+FAR     LDI16 R4, #1234
+        RET
+.END",
+        )
+        .unwrap();
+
+        assert_eq!(&program.words[..12], &[
+            0b0010_1100_0000_1010, // LD R6, <literal 1>
+            0b0010_0010_0000_1010, // LD R1, <literal 2>
+            0b0001_0100_0110_0000,
+            0b0001_1101_1011_1111,
+            0b0111_0101_1000_0000,
+            0b0101_0100_1010_0000,
+            0b0110_0111_1000_0000,
+            0b0001_1101_1010_0001,
+            0b0010_1110_0000_0100, // LD R7, <literal 3>
+            0b0100_0001_1100_0000,
+            0b1111_0000_0010_0101,
+            0xFE00,
+        ]);
+        assert_eq!(program.symbols.get("<literal 3>"), None);
+        assert!(!program.symbols.to_lc3as().contains("literal"));
+        assert_eq!(program.words[13], 0x300E + 600);
+
+        let mut hardware = setup_default_test();
+        hardware.load(&program.image()).unwrap();
+        run::main_loop(&mut hardware).unwrap();
+
+        assert_eq!(hardware.registers.get(3), 1234);
+        assert_eq!(hardware.registers.get(4), 1234);
+        assert_eq!(hardware.registers.get(6), 0xFE00);
+    }
+
+    #[test]
+    fn literal_pool_too_far() {
+        let err = assemble_source(".ORIG x3000\nLDI16 R1, #1234\n.BLKW 300\n.END").err().unwrap();
+
+        assert_eq!(err.to_string(), "main.asm:2: the literal pool is 300 words away, PCoffset9 holds -256 to 255, place a .POOL closer");
+    }
+
+    #[test]
+    fn run_assembled() {
+        let program = assemble_source(
+            ".ORIG x3000
+COUNT   .EQU 3
+        AND R1, R1, #0
+        ADD R1, R1, COUNT
+.MACRO PRINT \\string
+        LEA R0, \\string
+        PUTS
+.ENDM
+@loop   PRINT MESSAGE
+        ADD R1, R1, #-1
+        BRp @loop
+        HALT
+MESSAGE .STRINGZ \"ab\"
+.END",
+        )
+        .unwrap();
+
+        let mut hardware = setup_default_test();
+        hardware.load(&program.image()).unwrap();
+        run::main_loop(&mut hardware).unwrap();
+
+        assert_eq!(hardware.console.output, b"ababab");
+    }
+
+    #[test]
+    fn debug_info() {
+        let program = assemble_source(
+            ".ORIG x3000
+.MACRO TWICE \\register
+        ADD \\register, \\register, \\register
+        ADD \\register, \\register, \\register
+.ENDM
+START   TWICE R1
+        PUSH R2
+        HALT
+.END",
+        )
+        .unwrap();
+        let line = |address| program.debug_info.get(address).map(|source| (source.line, source.column));
+
+        assert_eq!(line(0x3000), Some((6, 9)));
+        assert_eq!(line(0x3001), Some((6, 9)));
+        assert_eq!(line(0x3003), Some((7, 9)));
+        assert_eq!(line(0x3004), Some((8, 9)));
+        assert_eq!(line(0x3005), None);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::BTreeSet;
+
+use crate::console::Console;
+use crate::hardware::Hardware;
+use crate::sanitizer::OnViolation;
+use crate::traps::{self, TrapError};
+use crate::utils::{offset6, pcoffset11, register_at};
+
+// The registers a subroutine gives back as it got them when `--callee-saved` does not say otherwise,
+// R0 returns the result, R6 is the stack pointer and R7 the return address.
+pub const CALLEE_SAVED: [u16; 5] = [1, 2, 3, 4, 5];
+
+// A subroutine that has not returned yet, with the registers it has to give back.
+struct Frame {
+    routine: u16,
+    caller: u16,
+    return_address: u16,
+    saved: Vec<(u16, u16)>,
+}
+
+// Checks the calling convention at JSR, JSRR and RET, and pushes through R6 past the stack limit. A TRAP to a routine in
+// the vector table returns with RET too, so it is a call that only has to come back.
+pub struct CallChecker {
+    pub on_violation: OnViolation,
+    pub callee_saved: Vec<u16>,
+    // R6 grows down and may not store below this address
+    pub stack_limit: Option<u16>,
+    pub warnings: Vec<String>,
+    frames: Vec<Frame>,
+    reported: BTreeSet<(u16, String)>,
+}
+impl CallChecker {
+    pub fn new(on_violation: OnViolation, callee_saved: Vec<u16>, stack_limit: Option<u16>) -> Self {
+        CallChecker { on_violation, callee_saved, stack_limit, warnings: Vec::new(), frames: Vec::new(), reported: BTreeSet::new() }
+    }
+}
+
+// Registers as `R1,R2` or `R1-R5`, `none` for no register.
+pub fn parse_registers(text: &str) -> Option<Vec<u16>> {
+    if text == "none" {
+        return Some(Vec::new());
+    }
+
+    let register = |text: &str| text.strip_prefix(['R', 'r'])?.parse::<u16>().ok().filter(|r| *r < 8);
+    let mut registers = Vec::new();
+    for part in text.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => registers.extend(register(first)?..=register(last)?),
+            None => registers.push(register(part)?),
+        }
+    }
+    Some(registers)
+}
+
+// Runs before the instruction at PC - 1, so a RET is checked against the registers it returns with.
+pub fn check<C: Console>(instruction: u16, hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    if hardware.calls.is_none() {
+        return Ok(());
+    }
+
+    let address = hardware.program_counter.get().wrapping_sub(1);
+    let returning = instruction >> 12 == 0xC && register_at(instruction, 6) == 7;
+    let violations = match instruction >> 12 {
+        0x4 => {
+            call(instruction, hardware);
+            Vec::new()
+        },
+        0xF => {
+            trap(instruction, hardware);
+            Vec::new()
+        },
+        0xC if returning => returns(hardware),
+        0x7 if register_at(instruction, 6) == 6 => overflow(instruction, hardware),
+        _ => Vec::new(),
+    };
+
+    let stack = call_stack(hardware);
+    if returning {
+        hardware.calls.as_mut().unwrap().frames.pop();
+    }
+    report(hardware, address, violations, stack)
+}
+
+// JSR and JSRR, the routine has to give back R6 and the callee saved registers as they are now.
+fn call<C: Console>(instruction: u16, hardware: &mut Hardware<C>) {
+    let pc = hardware.program_counter.get();
+    let routine = match instruction & 0b0000_1000_0000_0000 {
+        0 => hardware.registers.get(register_at(instruction, 6)),
+        _ => pc.wrapping_add(pcoffset11(instruction)),
+    };
+    let checker = hardware.calls.as_mut().unwrap();
+    let saved = [6].iter().chain(&checker.callee_saved).map(|r| (*r, hardware.registers.get(*r))).collect();
+    checker.frames.push(Frame { routine, caller: pc.wrapping_sub(1), return_address: pc, saved });
+}
+
+// TRAP without a host handler, the routine of the OS follows its own conventions for the registers. With access control
+// it returns with RTI instead.
+fn trap<C: Console>(instruction: u16, hardware: &mut Hardware<C>) {
+    let Some(routine) = traps::os_routine(instruction, hardware) else {
+        return;
+    };
+
+    let pc = hardware.program_counter.get();
+    let checker = hardware.calls.as_mut().unwrap();
+    checker.frames.push(Frame { routine, caller: pc.wrapping_sub(1), return_address: pc, saved: Vec::new() });
+}
+
+// RET, a return with no call to match is the program leaving to whoever started it.
+fn returns<C: Console>(hardware: &Hardware<C>) -> Vec<String> {
+    let Some(frame) = hardware.calls.as_ref().and_then(|checker| checker.frames.last()) else {
+        return Vec::new();
+    };
+    let routine = hardware.describe(frame.routine);
+
+    let mut violations = Vec::new();
+    let r7 = hardware.registers.get(7);
+    if r7 != frame.return_address {
+        violations.push(format!("{} returns to x{:04X}, it was called to return to x{:04X}", routine, r7, frame.return_address));
+    }
+    for (register, value) in frame.saved.iter().copied() {
+        let now = hardware.registers.get(register);
+        if now != value {
+            violations.push(format!("{} returns with R{} x{:04X}, it was x{:04X} when called", routine, register, now, value));
+        }
+    }
+    violations
+}
+
+// STR through R6 below the limit.
+fn overflow<C: Console>(instruction: u16, hardware: &Hardware<C>) -> Vec<String> {
+    let target = hardware.registers.get(6).wrapping_add(offset6(instruction));
+    match hardware.calls.as_ref().and_then(|checker| checker.stack_limit).filter(|limit| target < *limit) {
+        Some(limit) => vec![format!("the stack overflows past x{:04X}, storing at x{:04X}", limit, target)],
+        None => Vec::new(),
+    }
+}
+
+fn report<C: Console>(hardware: &mut Hardware<C>, address: u16, violations: Vec<String>, stack: String) -> Result<(), TrapError> {
+    let location = hardware.describe(address);
+    let checker = hardware.calls.as_mut().unwrap();
+    for violation in violations {
+        if checker.on_violation == OnViolation::Stop {
+            return Err(TrapError::Convention { violation, location, stack });
+        }
+        if checker.reported.insert((address, violation.clone())) {
+            checker.warnings.push(format!("{}, at {}{}", violation, location, stack));
+        }
+    }
+
+    Ok(())
+}
+
+// The subroutines that have not returned, the innermost first.
+fn call_stack<C: Console>(hardware: &Hardware<C>) -> String {
+    let Some(checker) = &hardware.calls else {
+        return String::new();
+    };
+
+    checker.frames.iter().rev()
+        .map(|frame| format!("\n    in {} called from {}", hardware.describe(frame.routine), hardware.describe(frame.caller)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions;
+    use crate::utils::setup_default_test;
+
+    fn run<C: Console>(hardware: &mut Hardware<C>, count: usize) -> Result<(), TrapError> {
+        for _ in 0..count {
+            let instruction = hardware.next().unwrap();
+            instructions::process(instruction, hardware)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(parse_registers("R1-R3,r7"), Some(vec![1, 2, 3, 7]));
+        assert_eq!(parse_registers("none"), Some(vec![]));
+        assert_eq!(parse_registers("R8"), None);
+        assert_eq!(parse_registers("R1,"), None);
+    }
+
+    #[test]
+    fn balanced_call() {
+        let mut hardware = setup_default_test();
+        hardware.calls = Some(CallChecker::new(OnViolation::Stop, CALLEE_SAVED.to_vec(), Some(0x3F00)));
+        hardware.registers.set(6, 0x4000);
+        hardware.load(&[
+            0x3000,
+            0b0100_1000_0000_0001, // JSR SUB
+            0b1111_0000_0010_0101, // HALT
+            0b0001_1101_1011_1111, // SUB: ADD R6, R6, #-1
+            0b0111_0011_1000_0000, // STR R1, R6, #0
+            0b0001_0010_0110_0101, // ADD R1, R1, #5
+            0b0110_0011_1000_0000, // LDR R1, R6, #0
+            0b0001_1101_1010_0001, // ADD R6, R6, #1
+            0b1100_0001_1100_0000, // RET
+        ]).unwrap();
+
+        run(&mut hardware, 7).unwrap();
+        assert_eq!(hardware.program_counter.get(), 0x3001);
+        assert!(hardware.calls.unwrap().frames.is_empty());
+    }
+
+    #[test]
+    fn violations() {
+        let mut hardware = setup_default_test();
+        hardware.calls = Some(CallChecker::new(OnViolation::Warn, CALLEE_SAVED.to_vec(), Some(0x3FFF)));
+        hardware.registers.set(6, 0x4000);
+        hardware.load(&[
+            0x3000,
+            0b0100_1000_0000_0001, // JSR SUB
+            0b1111_0000_0010_0101, // HALT
+            0b0001_1101_1011_1110, // SUB: ADD R6, R6, #-2
+            0b0111_0011_1000_0000, // STR R1, R6, #0
+            0b0001_0010_0110_0101, // ADD R1, R1, #5
+            0b1100_0001_1100_0000, // RET
+        ]).unwrap();
+        hardware.symbols.insert("SUB", 0x3002);
+
+        run(&mut hardware, 5).unwrap();
+        assert_eq!(hardware.calls.unwrap().warnings, vec![
+            "the stack overflows past x3FFF, storing at x3FFE, at SUB+1 (x3003)\n    in SUB (x3002) called from x3000",
+            "SUB (x3002) returns with R6 x3FFE, it was x4000 when called, at SUB+3 (x3005)\n    in SUB (x3002) called from x3000",
+            "SUB (x3002) returns with R1 x0005, it was x0000 when called, at SUB+3 (x3005)\n    in SUB (x3002) called from x3000",
+        ]);
+    }
+
+    #[test]
+    fn clobbered_return_address() {
+        let mut hardware = setup_default_test();
+        hardware.calls = Some(CallChecker::new(OnViolation::Stop, Vec::new(), None));
+        hardware.load(&[
+            0x3000,
+            0b0100_1000_0000_0001, // JSR A
+            0b1111_0000_0010_0101, // HALT
+            0b0100_1000_0000_0001, // A: JSR B
+            0b1100_0001_1100_0000, // RET
+            0b1100_0001_1100_0000, // B: RET
+        ]).unwrap();
+
+        let err = run(&mut hardware, 5).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "x3002 returns to x3003, it was called to return to x3001, fault at x3003\n    in x3002 called from x3000",
+        );
+    }
+
+    #[test]
+    fn trap_routine() {
+        let mut hardware = setup_default_test();
+        hardware.calls = Some(CallChecker::new(OnViolation::Stop, vec![1, 2, 3, 4], None));
+        hardware.traps.unregister(0x21);
+        hardware.memory.set(0x0021, 0x0400);
+        // OUT from the OS, called with R7 pointing back into SUB
+        hardware.memory.set(0x0400, 0b0001_0000_0010_0001); // ADD R0, R0, #1
+        hardware.memory.set(0x0401, 0b1100_0001_1100_0000); // RET
+        hardware.load(&[
+            0x3000,
+            0b0100_1000_0000_0001, // JSR SUB
+            0b1111_0000_0010_0101, // HALT
+            0b0001_1011_1110_0000, // SUB: ADD R5, R7, #0
+            0b1111_0000_0010_0001, // OUT
+            0b0001_1111_0110_0000, // ADD R7, R5, #0
+            0b1100_0001_1100_0000, // RET
+        ]).unwrap();
+
+        run(&mut hardware, 7).unwrap();
+        assert_eq!(hardware.program_counter.get(), 0x3001);
+        assert_eq!(hardware.registers.get(0), 1);
+        assert!(hardware.calls.unwrap().frames.is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Stdin, Stdout, Write};
+
+use crate::terminal::Input;
+
+// The keyboard and display the program talks to.
+// Reading gives `WouldBlock` when no key can be waited for right now, like an interrupted wait or a script
+// with no keys left, and `UnexpectedEof` once the input has ended.
+pub trait Console {
+    // A key if one is ready, without waiting for it.
+    fn poll_key(&mut self) -> io::Result<Option<u8>>;
+    fn read_key(&mut self) -> io::Result<u8>;
+    fn write_char(&mut self, c: u8) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        bytes.iter().try_for_each(|c| self.write_char(*c))
+    }
+}
+
+// What a read gets once the input has ended.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnEof {
+    // The program halts, and the run ends with its own outcome
+    Halt,
+    // The read gives this value, with polling it looks like a key unless it is zero
+    Value(u16),
+    // The program waits for a key that does not come, until it is interrupted
+    Block,
+}
+impl OnEof {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "halt" => Some(OnEof::Halt),
+            "block" => Some(OnEof::Block),
+            value => crate::loader::parse_address(value).map(OnEof::Value),
+        }
+    }
+}
+
+fn eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "the input has ended")
+}
+
+// The terminal the emulator runs in, or whatever stdin and stdout are redirected to.
+pub struct Stdio {
+    input: Input<Stdin>,
+    output: Stdout,
+    eof: bool,
+}
+impl Default for Stdio {
+    fn default() -> Self {
+        Stdio { input: Input(io::stdin()), output: io::stdout(), eof: false }
+    }
+}
+impl Console for Stdio {
+    // Polling waits for the next key, so a program polling the keyboard does not spin and its recordings stay short.
+    // An interrupt stops the wait with `WouldBlock`
+    fn poll_key(&mut self) -> io::Result<Option<u8>> {
+        self.read_key().map(Some)
+    }
+
+    fn read_key(&mut self) -> io::Result<u8> {
+        if self.eof {
+            return Err(eof());
+        }
+
+        let mut buf = [0; 1];
+        match self.input.read(&mut buf)? {
+            0 => {
+                self.eof = true;
+                Err(eof())
+            },
+            _ => Ok(buf[0]),
+        }
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.output.write_all(&[c])
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+// Input given up front and the output kept in memory, the input ends after the last byte.
+pub struct Buffer {
+    input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+impl Buffer {
+    pub fn new(input: &[u8]) -> Self {
+        Buffer { input: input.iter().copied().collect(), output: Vec::new() }
+    }
+}
+impl Console for Buffer {
+    fn poll_key(&mut self) -> io::Result<Option<u8>> {
+        self.read_key().map(Some)
+    }
+
+    fn read_key(&mut self) -> io::Result<u8> {
+        self.input.pop_front().ok_or_else(eof)
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.output.push(c);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Keys pushed by the host while the program runs, reading past them makes the program wait until more are pushed
+// or the script is closed.
+#[derive(Default)]
+pub struct Script {
+    keys: VecDeque<u8>,
+    closed: bool,
+    pub output: Vec<u8>,
+}
+impl Script {
+    pub fn push(&mut self, keys: &[u8]) {
+        self.keys.extend(keys);
+    }
+
+    #[allow(dead_code)]
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+}
+impl Console for Script {
+    fn poll_key(&mut self) -> io::Result<Option<u8>> {
+        match self.keys.pop_front() {
+            Some(key) => Ok(Some(key)),
+            None if self.closed => Err(eof()),
+            None => Ok(None),
+        }
+    }
+
+    fn read_key(&mut self) -> io::Result<u8> {
+        match self.poll_key()? {
+            Some(key) => Ok(key),
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "no key is queued")),
+        }
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.output.push(c);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_on_eof() {
+        assert_eq!(OnEof::parse("halt"), Some(OnEof::Halt));
+        assert_eq!(OnEof::parse("block"), Some(OnEof::Block));
+        assert_eq!(OnEof::parse("xFFFF"), Some(OnEof::Value(0xFFFF)));
+        assert_eq!(OnEof::parse("#0"), Some(OnEof::Value(0)));
+        assert_eq!(OnEof::parse("never"), None);
+    }
+
+    #[test]
+    fn buffer() {
+        let mut console = Buffer::new(b"ab");
+
+        assert_eq!(console.poll_key().unwrap(), Some(b'a'));
+        assert_eq!(console.read_key().unwrap(), b'b');
+        assert_eq!(console.read_key().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        console.write_bytes(b"hi").unwrap();
+        assert_eq!(console.output, b"hi");
+    }
+
+    #[test]
+    fn script() {
+        let mut console = Script::default();
+
+        assert_eq!(console.poll_key().unwrap(), None);
+        assert_eq!(console.read_key().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        console.push(b"x");
+        console.close();
+        assert_eq!(console.read_key().unwrap(), b'x');
+        assert_eq!(console.poll_key().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::disassembler::disassemble;
+use crate::memory::Memory;
+use crate::symbols::SymbolTable;
+
+const LISTING_HEADER: usize = 2;
+
+#[derive(Default)]
+pub struct Coverage {
+    regions: Vec<(u16, usize)>,
+    hits: BTreeMap<u16, u64>,
+    branches: BTreeMap<u16, (u64, u64)>,
+}
+impl Coverage {
+    // Only addresses inside the covered regions end up in the reports.
+    pub fn cover(&mut self, start: u16, len: usize) {
+        self.regions.push((start, len));
+    }
+
+    pub fn instruction(&mut self, address: u16) {
+        *self.hits.entry(address).or_default() += 1;
+    }
+
+    pub fn retract(&mut self, address: u16) {
+        if let Some(hits) = self.hits.get_mut(&address) {
+            *hits = hits.saturating_sub(1);
+        }
+    }
+
+    pub fn branch(&mut self, address: u16, taken: bool) {
+        let (taken_count, not_taken_count) = self.branches.entry(address).or_default();
+        if taken {
+            *taken_count += 1;
+        } else {
+            *not_taken_count += 1;
+        }
+    }
+
+    fn addresses(&self) -> impl Iterator<Item = u16> + '_ {
+        self.regions.iter().flat_map(|&(start, len)| (0..len).map(move |offset| start.wrapping_add(offset as u16)))
+    }
+
+    pub fn hits(&self, address: u16) -> u64 {
+        self.hits.get(&address).copied().unwrap_or_default()
+    }
+
+    // BRnzp always jumps and BR without flags never does, neither has two directions to cover.
+    fn is_conditional_branch(instruction: u16) -> bool {
+        let flags = (instruction >> 9) & 0b111;
+        instruction >> 12 == 0x0 && flags != 0b000 && flags != 0b111
+    }
+
+    pub fn listing(&self, name: &str, memory: &Memory, symbols: &SymbolTable) -> String {
+        let addresses = self.addresses().collect::<Vec<_>>();
+        let executed = addresses.iter().filter(|&&address| self.hits(address) != 0).count();
+        let (directions, covered) = addresses
+            .iter()
+            .filter(|&&address| Self::is_conditional_branch(memory.get(address)))
+            .map(|address| self.branches.get(address).copied().unwrap_or_default())
+            .fold((0, 0), |(directions, covered), (taken, not_taken)| {
+                (directions + 2, covered + (taken != 0) as usize + (not_taken != 0) as usize)
+            });
+
+        let mut listing = String::new();
+        writeln!(listing, "; coverage of {}: {}/{} addresses executed, {}/{} branch directions taken", name, executed, addresses.len(), covered, directions).unwrap();
+        writeln!(listing, "; {:>8}  {:<8} {:<16} instruction", "hits", "address", "label").unwrap();
+
+        for address in addresses {
+            let instruction = memory.get(address);
+            let hits = match self.hits(address) {
+                0 => "#####".to_string(),
+                hits => hits.to_string(),
+            };
+            let label = symbols.name_at(address).unwrap_or_default();
+
+            write!(listing, "{:>10}  x{:04X}    {:<16} {}", hits, address, label, disassemble(address, instruction, symbols)).unwrap();
+            if Self::is_conditional_branch(instruction) {
+                let (taken, not_taken) = self.branches.get(&address).copied().unwrap_or_default();
+                write!(listing, "  ; taken {}, not taken {}", taken, not_taken).unwrap();
+            }
+            writeln!(listing).unwrap();
+        }
+
+        listing
+    }
+
+    // Tracefile in the lcov format, line numbers refer to the listing written by `listing`.
+    pub fn lcov(&self, listing_path: &str, memory: &Memory) -> String {
+        let mut lcov = String::new();
+        writeln!(lcov, "TN:").unwrap();
+        writeln!(lcov, "SF:{}", listing_path).unwrap();
+
+        let (mut lines, mut lines_hit, mut branches, mut branches_hit) = (0, 0, 0, 0);
+        for (index, address) in self.addresses().enumerate() {
+            let line = index + LISTING_HEADER + 1;
+            let hits = self.hits(address);
+
+            if Self::is_conditional_branch(memory.get(address)) {
+                let outcomes = self.branches.get(&address).copied().unwrap_or_default();
+                for (branch, count) in [outcomes.0, outcomes.1].into_iter().enumerate() {
+                    if hits == 0 {
+                        writeln!(lcov, "BRDA:{},0,{},-", line, branch).unwrap();
+                    } else {
+                        writeln!(lcov, "BRDA:{},0,{},{}", line, branch, count).unwrap();
+                    }
+                    branches += 1;
+                    branches_hit += (count != 0) as usize;
+                }
+            }
+
+            writeln!(lcov, "DA:{},{}", line, hits).unwrap();
+            lines += 1;
+            lines_hit += (hits != 0) as usize;
+        }
+
+        writeln!(lcov, "BRF:{}", branches).unwrap();
+        writeln!(lcov, "BRH:{}", branches_hit).unwrap();
+        writeln!(lcov, "LF:{}", lines).unwrap();
+        writeln!(lcov, "LH:{}", lines_hit).unwrap();
+        writeln!(lcov, "end_of_record").unwrap();
+
+        lcov
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (Coverage, Memory) {
+        let mut memory = Memory::default();
+        memory.load(0x3000, &[
+            0b0101_0010_0110_0000, // AND R1, R1, #0
+            0b0000_0100_0000_0001, // BRz x3003
+            0b0001_0010_0110_0001, // ADD R1, R1, #1
+            0b0000_1111_1111_1100, // BRnzp x3000
+        ]).unwrap();
+
+        let mut coverage = Coverage::default();
+        coverage.cover(0x3000, 4);
+        coverage.instruction(0x3000);
+        coverage.instruction(0x3001);
+        coverage.branch(0x3001, true);
+        coverage.instruction(0x3003);
+        coverage.branch(0x3003, true);
+
+        (coverage, memory)
+    }
+
+    #[test]
+    fn listing() {
+        let (coverage, memory) = setup();
+        let mut symbols = SymbolTable::default();
+        symbols.insert("LOOP", 0x3000);
+
+        let listing = coverage.listing("loop.obj", &memory, &symbols);
+        let lines = listing.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], "; coverage of loop.obj: 3/4 addresses executed, 1/2 branch directions taken");
+        assert_eq!(lines[2], "         1  x3000    LOOP             AND R1, R1, #0");
+        assert_eq!(lines[3], "         1  x3001                     BRz x3003  ; taken 1, not taken 0");
+        assert_eq!(lines[4], "     #####  x3002                     ADD R1, R1, #1");
+        assert_eq!(lines[5], "         1  x3003                     BRnzp LOOP");
+    }
+
+    #[test]
+    fn lcov() {
+        let (coverage, memory) = setup();
+
+        assert_eq!(coverage.lcov("loop.lst", &memory), "TN:
+SF:loop.lst
+DA:3,1
+BRDA:4,0,0,1
+BRDA:4,0,1,0
+DA:4,1
+DA:5,0
+DA:6,1
+BRF:2
+BRH:1
+LF:4
+LH:3
+end_of_record
+");
+    }
+}
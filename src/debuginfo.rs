@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::symbols::SymbolTable;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+// The source line every word of a program was assembled from, written by `asm` next to the program.
+#[derive(Default)]
+pub struct DebugInfo {
+    lines: BTreeMap<u16, SourceLine>,
+}
+impl DebugInfo {
+    // Lines like `x3003	main.asm	42	9	LOOP+3`, the label is only there for people reading the file.
+    pub fn parse(text: &str) -> Self {
+        let mut debug_info = DebugInfo::default();
+
+        for line in text.lines().filter(|line| !line.starts_with(';')) {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if let [address, file, line, column, ..] = fields[..] {
+                let address = address.strip_prefix('x').and_then(|address| u16::from_str_radix(address, 16).ok());
+                if let (Some(address), Ok(line), Ok(column)) = (address, line.parse(), column.parse()) {
+                    debug_info.insert(address, SourceLine { file: file.to_string(), line, column });
+                }
+            }
+        }
+
+        debug_info
+    }
+
+    pub fn to_text(&self, symbols: &SymbolTable) -> String {
+        let mut text = String::new();
+        writeln!(text, "; address\tfile\tline\tcolumn\tlabel").unwrap();
+        for (address, source) in &self.lines {
+            let label = symbols.locate(*address).unwrap_or_default();
+            writeln!(text, "x{:04X}\t{}\t{}\t{}\t{}", address, source.file, source.line, source.column, label).unwrap();
+        }
+
+        text
+    }
+
+    // Reads `program.dbg` placed next to `program.obj`, if there is one.
+    pub fn read_for(file_path: &str) -> io::Result<Self> {
+        let path = Path::new(file_path).with_extension("dbg");
+        if path.exists() {
+            Ok(Self::parse(&fs::read_to_string(path)?))
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    pub fn extend(&mut self, other: DebugInfo) {
+        self.lines.extend(other.lines);
+    }
+
+    pub fn insert(&mut self, address: u16, source: SourceLine) {
+        self.lines.insert(address, source);
+    }
+
+    pub fn get(&self, address: u16) -> Option<&SourceLine> {
+        self.lines.get(&address)
+    }
+}
+
+// Describes an address by its label and source line, e.g. `LOOP+3 (main.asm:42)`, falling back to the address.
+pub fn describe(address: u16, symbols: &SymbolTable, debug_info: &DebugInfo) -> String {
+    match (symbols.locate(address), debug_info.get(address)) {
+        (Some(location), Some(source)) => format!("{} ({})", location, source),
+        (None, Some(source)) => format!("x{:04X} ({})", address, source),
+        _ => symbols.describe(address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(line: usize) -> SourceLine {
+        SourceLine { file: "main.asm".to_string(), line, column: 9 }
+    }
+
+    #[test]
+    fn write_and_parse() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("LOOP", 0x3002);
+        let mut debug_info = DebugInfo::default();
+        debug_info.insert(0x3004, source(42));
+        debug_info.insert(0x3005, source(43));
+
+        let text = debug_info.to_text(&symbols);
+        assert!(text.contains("x3004\tmain.asm\t42\t9\tLOOP+2\n"));
+
+        let parsed = DebugInfo::parse(&text);
+        assert_eq!(parsed.get(0x3005), Some(&source(43)));
+        assert_eq!(parsed.get(0x3006), None);
+    }
+
+    #[test]
+    fn describe_addresses() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("loop", 0x3002);
+        let mut debug_info = DebugInfo::default();
+        debug_info.insert(0x3005, source(42));
+        debug_info.insert(0x2000, source(7));
+
+        assert_eq!(describe(0x3005, &symbols, &debug_info), "loop+3 (main.asm:42)");
+        assert_eq!(describe(0x3006, &symbols, &debug_info), "loop+4 (x3006)");
+        assert_eq!(describe(0x2000, &symbols, &debug_info), "x2000 (main.asm:7)");
+        assert_eq!(describe(0x1000, &SymbolTable::default(), &DebugInfo::default()), "x1000");
+    }
+}
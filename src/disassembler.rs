@@ -0,0 +1,112 @@
+use crate::symbols::SymbolTable;
+use crate::utils::{imm5, offset6, pcoffset9, pcoffset11, register_at};
+
+pub fn disassemble(address: u16, instruction: u16, symbols: &SymbolTable) -> String {
+    let target = |offset: u16| {
+        let target = address.wrapping_add(1).wrapping_add(offset);
+        match symbols.name_at(target) {
+            Some(name) => name.to_string(),
+            None => format!("x{:04X}", target),
+        }
+    };
+    let r = |at: u16| format!("R{}", register_at(instruction, at));
+    let operand = || {
+        if instruction & 0b0000_0000_0010_0000 == 0 {
+            r(0)
+        } else {
+            format!("#{}", imm5(instruction) as i16)
+        }
+    };
+
+    match instruction >> 12 {
+        0x0 => {
+            let flags = [(11, 'n'), (10, 'z'), (9, 'p')]
+                .iter()
+                .filter(|(bit, _)| instruction >> bit & 1 == 1)
+                .map(|(_, flag)| *flag)
+                .collect::<String>();
+
+            if flags.is_empty() {
+                "NOP".to_string()
+            } else {
+                format!("BR{} {}", flags, target(pcoffset9(instruction)))
+            }
+        },
+        0x1 => format!("ADD {}, {}, {}", r(9), r(6), operand()),
+        0x2 => format!("LD {}, {}", r(9), target(pcoffset9(instruction))),
+        0x3 => format!("ST {}, {}", r(9), target(pcoffset9(instruction))),
+        0x4 => {
+            if instruction & 0b0000_1000_0000_0000 == 0 {
+                format!("JSRR {}", r(6))
+            } else {
+                format!("JSR {}", target(pcoffset11(instruction)))
+            }
+        },
+        0x5 => format!("AND {}, {}, {}", r(9), r(6), operand()),
+        0x6 => format!("LDR {}, {}, #{}", r(9), r(6), offset6(instruction) as i16),
+        0x7 => format!("STR {}, {}, #{}", r(9), r(6), offset6(instruction) as i16),
+        0x8 => "RTI".to_string(),
+        0x9 => format!("NOT {}, {}", r(9), r(6)),
+        0xA => format!("LDI {}, {}", r(9), target(pcoffset9(instruction))),
+        0xB => format!("STI {}, {}", r(9), target(pcoffset9(instruction))),
+        0xC => {
+            if register_at(instruction, 6) == 7 {
+                "RET".to_string()
+            } else {
+                format!("JMP {}", r(6))
+            }
+        },
+        0xD => format!(".FILL x{:04X}", instruction),
+        0xE => format!("LEA {}, {}", r(9), target(pcoffset9(instruction))),
+        _ => match instruction & 0xFF {
+            0x20 => "GETC".to_string(),
+            0x21 => "OUT".to_string(),
+            0x22 => "PUTS".to_string(),
+            0x23 => "IN".to_string(),
+            0x24 => "PUTSP".to_string(),
+            0x25 => "HALT".to_string(),
+            vector => format!("TRAP x{:02X}", vector),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operate() {
+        let symbols = SymbolTable::default();
+
+        assert_eq!(disassemble(0x3000, 0b0001_0010_1000_0011, &symbols), "ADD R1, R2, R3");
+        assert_eq!(disassemble(0x3000, 0b0001_0010_1011_0001, &symbols), "ADD R1, R2, #-15");
+        assert_eq!(disassemble(0x3000, 0b0101_0110_1110_0000, &symbols), "AND R3, R3, #0");
+        assert_eq!(disassemble(0x3000, 0b1001_0010_1011_1111, &symbols), "NOT R1, R2");
+    }
+
+    #[test]
+    fn memory() {
+        let symbols = SymbolTable::default();
+
+        assert_eq!(disassemble(0x3000, 0b0010_0010_0000_0010, &symbols), "LD R1, x3003");
+        assert_eq!(disassemble(0x3000, 0b1110_0010_1111_1111, &symbols), "LEA R1, x3100");
+        assert_eq!(disassemble(0x3000, 0b0110_1000_0100_0000, &symbols), "LDR R4, R1, #0");
+        assert_eq!(disassemble(0x3000, 0b0111_0100_1111_1111, &symbols), "STR R2, R3, #-1");
+    }
+
+    #[test]
+    fn control() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("LOOP", 0x3000);
+
+        assert_eq!(disassemble(0x3009, 0b0000_1111_1111_0110, &symbols), "BRnzp LOOP");
+        assert_eq!(disassemble(0x3000, 0b0000_0100_0000_0101, &symbols), "BRz x3006");
+        assert_eq!(disassemble(0x3000, 0b0000_0000_0000_0000, &symbols), "NOP");
+        assert_eq!(disassemble(0x3000, 0b0100_1000_0000_0010, &symbols), "JSR x3003");
+        assert_eq!(disassemble(0x3000, 0b0100_0000_1000_0000, &symbols), "JSRR R2");
+        assert_eq!(disassemble(0x3000, 0b1100_0001_1100_0000, &symbols), "RET");
+        assert_eq!(disassemble(0x3000, 0b1100_0000_1000_0000, &symbols), "JMP R2");
+        assert_eq!(disassemble(0x3000, 0b1111_0000_0010_0101, &symbols), "HALT");
+        assert_eq!(disassemble(0x3000, 0b1111_0000_0010_0110, &symbols), "TRAP x26");
+    }
+}
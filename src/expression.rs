@@ -0,0 +1,259 @@
+// Constant expressions used by the assembler: numbers in the LC-3 notations (`#10`, `x0A`, `10`, `'A'`),
+// symbols and the C operators, with the C precedence.
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Number(i32),
+    Symbol(String),
+    Operator(&'static str),
+}
+
+const OPERATORS: [&str; 20] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "<", ">",
+];
+
+// Operators by precedence, loosest first.
+const BINARY: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+pub fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '@'
+}
+
+// Numbers are written like identifiers, `x1F` is a number but `x1G` is a label.
+pub fn parse_number(word: &str) -> Option<i32> {
+    let hex = word.strip_prefix("0x").or_else(|| word.strip_prefix(['x', 'X']));
+    match hex {
+        Some(hex) if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) => i32::from_str_radix(hex, 16).ok(),
+        _ if word.chars().all(|c| c.is_ascii_digit()) => word.parse().ok(),
+        _ => None,
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            let start = i + 1;
+            let sign = (chars.get(start) == Some(&'-')) as usize;
+            i = start + sign + chars[start + sign..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let digits = chars[start..i].iter().collect::<String>();
+            let value = digits.parse().map_err(|_| format!("invalid decimal number #{}", digits))?;
+            tokens.push(Token::Number(value));
+        } else if c == '\'' {
+            match chars.get(i + 1..i + 3) {
+                Some([c, '\'']) => tokens.push(Token::Number(*c as i32)),
+                _ => return Err("invalid character literal".to_string()),
+            }
+            i += 3;
+        } else if is_identifier_char(c) {
+            let start = i;
+            i += chars[i..].iter().take_while(|c| is_identifier_char(**c)).count();
+            let word = chars[start..i].iter().collect::<String>();
+            tokens.push(match parse_number(&word) {
+                Some(value) => Token::Number(value),
+                None if c.is_ascii_digit() => return Err(format!("invalid number {}", word)),
+                None => Token::Symbol(word),
+            });
+        } else if c == '(' || c == ')' {
+            tokens.push(Token::Operator(if c == '(' { "(" } else { ")" }));
+            i += 1;
+        } else {
+            let rest = chars[i..].iter().take(2).collect::<String>();
+            let operator = OPERATORS.iter().find(|operator| rest.starts_with(**operator)).ok_or(format!("unexpected `{}`", c))?;
+            tokens.push(Token::Operator(operator));
+            i += operator.len();
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    lookup: &'a mut dyn FnMut(&str) -> Option<i32>,
+}
+impl Parser<'_> {
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(operator)) => Some(operator),
+            _ => None,
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i32, String> {
+        if level == BINARY.len() {
+            return self.unary();
+        }
+
+        let mut value = self.binary(level + 1)?;
+        while let Some(operator) = self.peek_operator().filter(|operator| BINARY[level].contains(operator)) {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            value = match operator {
+                "||" => (value != 0 || right != 0) as i32,
+                "&&" => (value != 0 && right != 0) as i32,
+                "|" => value | right,
+                "^" => value ^ right,
+                "&" => value & right,
+                "==" => (value == right) as i32,
+                "!=" => (value != right) as i32,
+                "<" => (value < right) as i32,
+                ">" => (value > right) as i32,
+                "<=" => (value <= right) as i32,
+                ">=" => (value >= right) as i32,
+                "<<" => value.wrapping_shl(right as u32),
+                ">>" => value.wrapping_shr(right as u32),
+                "+" => value.wrapping_add(right),
+                "-" => value.wrapping_sub(right),
+                "*" => value.wrapping_mul(right),
+                "/" | "%" if right == 0 => return Err("division by zero".to_string()),
+                "/" => value.wrapping_div(right),
+                _ => value.wrapping_rem(right),
+            };
+        }
+
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i32, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or("expression ends unexpectedly")?;
+        self.position += 1;
+
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Symbol(name) => (self.lookup)(&name).ok_or(format!("unknown symbol {}", name)),
+            Token::Operator("-") => Ok(self.unary()?.wrapping_neg()),
+            Token::Operator("+") => self.unary(),
+            Token::Operator("~") => Ok(!self.unary()?),
+            Token::Operator("!") => Ok((self.unary()? == 0) as i32),
+            Token::Operator("(") => {
+                let value = self.binary(0)?;
+                if self.peek_operator() != Some(")") {
+                    return Err("missing `)`".to_string());
+                }
+                self.position += 1;
+                Ok(value)
+            },
+            Token::Operator(operator) => Err(format!("unexpected `{}`", operator)),
+        }
+    }
+}
+
+pub fn evaluate(text: &str, lookup: &mut dyn FnMut(&str) -> Option<i32>) -> Result<i32, String> {
+    let tokens = tokenize(text)?;
+    if tokens.is_empty() {
+        return Err("missing value".to_string());
+    }
+
+    let mut parser = Parser { tokens, position: 0, lookup };
+    let value = parser.binary(0)?;
+    if parser.position < parser.tokens.len() {
+        return Err(format!("unexpected text after the value in `{}`", text.trim()));
+    }
+
+    Ok(value)
+}
+
+// Replaces the identifiers `replace` knows, leaving string and character literals untouched.
+pub fn substitute(text: &str, replace: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c == '"' || c == '\'' {
+            result.push(c);
+            let mut escaped = false;
+            for (_, next) in chars.by_ref() {
+                result.push(next);
+                if next == c && !escaped {
+                    break;
+                }
+                escaped = next == '\\' && !escaped;
+            }
+        } else if is_identifier_char(c) {
+            let mut end = start + c.len_utf8();
+            while let Some((index, next)) = chars.peek().copied().filter(|(_, next)| is_identifier_char(*next)) {
+                end = index + next.len_utf8();
+                chars.next();
+            }
+            let word = &text[start..end];
+            // `#12` is a number, not an identifier named `12`
+            match replace(word).filter(|_| !text[..start].ends_with('#')) {
+                Some(replacement) => result.push_str(&replacement),
+                None => result.push_str(word),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str) -> Result<i32, String> {
+        evaluate(text, &mut |name| if name == "SIZE" { Some(8) } else { None })
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(value("#10"), Ok(10));
+        assert_eq!(value("#-10"), Ok(-10));
+        assert_eq!(value("#5-#3"), Ok(2));
+        assert_eq!(value("x1F"), Ok(31));
+        assert_eq!(value("0x1f"), Ok(31));
+        assert_eq!(value("42"), Ok(42));
+        assert_eq!(value("'A'"), Ok(65));
+        assert_eq!(value("12A"), Err("invalid number 12A".to_string()));
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(value("SIZE * 2 + 1"), Ok(17));
+        assert_eq!(value("(SIZE + 2) * 2"), Ok(20));
+        assert_eq!(value("1 << 4 | 3"), Ok(19));
+        assert_eq!(value("1 << 2 + 1"), Ok(8));
+        assert_eq!(value("8 >> 1 - 1"), Ok(8));
+        assert_eq!(value("-SIZE"), Ok(-8));
+        assert_eq!(value("SIZE > 4 && !(SIZE == 16)"), Ok(1));
+        assert_eq!(value("SIZE % 3"), Ok(2));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(value("COUNT + 1"), Err("unknown symbol COUNT".to_string()));
+        assert_eq!(value("(1 + 2"), Err("missing `)`".to_string()));
+        assert_eq!(value("1 +"), Err("expression ends unexpectedly".to_string()));
+        assert_eq!(value("1 / 0"), Err("division by zero".to_string()));
+        assert_eq!(value("1 2"), Err("unexpected text after the value in `1 2`".to_string()));
+    }
+
+    #[test]
+    fn substitution() {
+        let replace = |name: &str| if name == "SIZE" { Some("8".to_string()) } else { None };
+
+        assert_eq!(substitute("R1, SIZE+1", &replace), "R1, 8+1");
+        assert_eq!(substitute("\"SIZE\", SIZE_2", &replace), "\"SIZE\", SIZE_2");
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs;
+use std::path::Path;
+
+use crate::loader::{self, LoadError};
+use crate::symbols::SymbolTable;
+
+// Program formats written by lc3as and lc3tools, all of them start with the origin.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Object,
+    Hex,
+    Binary,
+}
+impl Format {
+    pub fn from_extension(file_path: &str) -> Option<Self> {
+        match Path::new(file_path).extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "obj" => Some(Format::Object),
+            "hex" => Some(Format::Hex),
+            "bin" => Some(Format::Binary),
+            _ => None,
+        }
+    }
+
+    // Text formats are only trusted if the file actually is text, `.bin` is also used for raw images.
+    pub fn detect(file_path: &str, bytes: &[u8]) -> Self {
+        let is_text = bytes.iter().all(|byte| byte.is_ascii_hexdigit() || byte.is_ascii_whitespace());
+
+        match Self::from_extension(file_path) {
+            Some(Format::Object) => Format::Object,
+            Some(format) if is_text => format,
+            Some(_) => Format::Object,
+            None if is_text && !bytes.is_empty() => {
+                let lines = String::from_utf8_lossy(bytes);
+                let mut lines = lines.lines().map(str::trim).filter(|line| !line.is_empty());
+
+                if lines.all(|line| line.len() == 16 && line.chars().all(|c| c == '0' || c == '1')) {
+                    Format::Binary
+                } else {
+                    Format::Hex
+                }
+            },
+            None => Format::Object,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Object => "object",
+            Format::Hex => "hex",
+            Format::Binary => "binary",
+        }
+    }
+
+    pub fn expected(&self) -> &'static str {
+        match self {
+            Format::Object => "big-endian 16-bit words",
+            Format::Hex => "4 hexadecimal digits",
+            Format::Binary => "16 binary digits",
+        }
+    }
+
+    pub fn parse(&self, bytes: &[u8]) -> Result<Vec<u16>, LoadError> {
+        match self {
+            Format::Object => parse_object(bytes),
+            Format::Hex => parse_text(bytes, *self, 4, 16),
+            Format::Binary => parse_text(bytes, *self, 16, 2),
+        }
+    }
+
+    pub fn write(&self, program: &[u16]) -> Vec<u8> {
+        match self {
+            Format::Object => program.iter().flat_map(|word| word.to_be_bytes()).collect(),
+            Format::Hex => program.iter().map(|word| format!("{:04X}\n", word)).collect::<String>().into_bytes(),
+            Format::Binary => program.iter().map(|word| format!("{:016b}\n", word)).collect::<String>().into_bytes(),
+        }
+    }
+}
+
+// Rewrites a program in the format given by the extension of `output`, together with its symbol table.
+pub fn convert(input: &str, output: &str) -> Result<(), LoadError> {
+    let program = loader::read_file(input)?;
+    let format = Format::from_extension(output).unwrap_or(Format::Object);
+    fs::write(output, format.write(&program))?;
+
+    let symbols = SymbolTable::read_for(input)?;
+    if !symbols.is_empty() && Path::new(input).with_extension("sym") != Path::new(output).with_extension("sym") {
+        fs::write(Path::new(output).with_extension("sym"), symbols.to_lc3as())?;
+    }
+
+    Ok(())
+}
+
+// Splits the big-endian object file into words, the first one being the origin.
+fn parse_object(bytes: &[u8]) -> Result<Vec<u16>, LoadError> {
+    if bytes.is_empty() {
+        return Err(LoadError::Empty);
+    }
+    if !bytes.len().is_multiple_of(2) {
+        return Err(LoadError::OddLength { len: bytes.len() });
+    }
+
+    Ok(bytes.chunks_exact(2).map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]])).collect())
+}
+
+// One word per line, written with exactly `digits` digits.
+fn parse_text(bytes: &[u8], format: Format, digits: usize, radix: u32) -> Result<Vec<u16>, LoadError> {
+    let text = String::from_utf8_lossy(bytes);
+
+    let mut program = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match u16::from_str_radix(line, radix) {
+            Ok(word) if line.len() == digits && line.chars().all(|c| c.is_digit(radix)) => program.push(word),
+            _ => return Err(LoadError::InvalidLine { line: index + 1, text: line.to_string(), format }),
+        }
+    }
+
+    if program.is_empty() {
+        return Err(LoadError::Empty);
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: [u16; 3] = [0x3000, 0b1110_0010_1111_1111, 0b0101_0110_1110_0000];
+
+    #[test]
+    fn parse_object_words() {
+        assert_eq!(Format::Object.parse(&[0x30, 0x00, 0xE2, 0xFF, 0x56, 0xE0]).unwrap(), PROGRAM);
+        assert!(matches!(Format::Object.parse(&[]), Err(LoadError::Empty)));
+    }
+
+    #[test]
+    fn odd_length() {
+        let err = Format::Object.parse(&[0x30, 0x00, 0xE2]).unwrap_err();
+
+        assert!(matches!(err, LoadError::OddLength { len: 3 }));
+        assert_eq!(err.to_string(), "file has an odd number of bytes (3), the byte at offset 2 is not part of a complete word");
+    }
+
+    #[test]
+    fn parse_hex() {
+        assert_eq!(Format::Hex.parse(b"3000\nE2FF\n56e0\n").unwrap(), PROGRAM);
+
+        let err = Format::Hex.parse(b"3000\n\nE2F\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 3 `E2F` is not a word in the hex format, expected 4 hexadecimal digits");
+    }
+
+    #[test]
+    fn parse_binary() {
+        let text = b"0011000000000000\r\n1110001011111111\r\n0101011011100000\r\n";
+        assert_eq!(Format::Binary.parse(text).unwrap(), PROGRAM);
+
+        assert!(matches!(Format::Binary.parse(b"0011000000000002\n"), Err(LoadError::InvalidLine { line: 1, .. })));
+        assert!(matches!(Format::Binary.parse(b"\n\n"), Err(LoadError::Empty)));
+    }
+
+    #[test]
+    fn write() {
+        assert_eq!(Format::Object.write(&PROGRAM), vec![0x30, 0x00, 0xE2, 0xFF, 0x56, 0xE0]);
+        assert_eq!(Format::Hex.write(&PROGRAM), b"3000\nE2FF\n56E0\n");
+        assert_eq!(Format::Binary.write(&PROGRAM), b"0011000000000000\n1110001011111111\n0101011011100000\n");
+
+        for format in [Format::Object, Format::Hex, Format::Binary] {
+            assert_eq!(format.parse(&format.write(&PROGRAM)).unwrap(), PROGRAM);
+        }
+    }
+
+    #[test]
+    fn convert_files() {
+        let directory = crate::utils::test_directory("formats");
+        let hex = directory.join("test.hex");
+        let hex = hex.to_str().unwrap();
+        convert("test.obj", hex).unwrap();
+
+        assert!(fs::read_to_string(hex).unwrap().starts_with("3000\nE2FF\n"));
+        assert_eq!(loader::read_file(hex).unwrap(), loader::read_file("test.obj").unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn detect() {
+        let binary = Format::Binary.write(&PROGRAM);
+        let hex = Format::Hex.write(&PROGRAM);
+        let object = Format::Object.write(&PROGRAM);
+
+        assert_eq!(Format::detect("test.obj", &hex), Format::Object);
+        assert_eq!(Format::detect("test.hex", &hex), Format::Hex);
+        assert_eq!(Format::detect("test.bin", &binary), Format::Binary);
+        assert_eq!(Format::detect("test.bin", &object), Format::Object);
+        assert_eq!(Format::detect("test", &binary), Format::Binary);
+        assert_eq!(Format::detect("test.txt", &hex), Format::Hex);
+        assert_eq!(Format::detect("test", &object), Format::Object);
+    }
+}
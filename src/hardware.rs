@@ -1,81 +1,259 @@
+use std::fs::File;
+use std::io::LineWriter;
+
 use crate::{
+    calls::CallChecker,
+    console::{Console, OnEof, Stdio},
+    coverage::Coverage,
+    debuginfo::{self, DebugInfo},
+    isa::{self, IsaProfile},
     memory::{Memory, self},
-    registers::{Registers, Flags, ProgramCounter}
+    profile::Profiler,
+    registers::{Registers, Flags, ProgramCounter},
+    sandbox::Sandbox,
+    sanitizer::Sanitizer,
+    session::Session,
+    symbols::SymbolTable,
+    traps::{TrapError, TrapTable},
 };
 
+#[allow(clippy::upper_case_acronyms)]
 enum MemoryMappedRegisters {
     KBSR = 0xFE00,
     KBDR = 0xFE02,
 }
 
-pub struct Hardware<R, W> {
+pub struct Hardware<C> {
     pub program_counter: ProgramCounter,
     pub registers: Registers,
     pub memory: Memory,
     pub flags: Flags,
+    pub halted: bool,
+    // Instructions run so far, the clock record and replay go by.
+    pub instructions: u64,
+    // The keyboard was polled with no key queued since this was last cleared.
+    pub waiting: bool,
+    // How far a trap got before it had to wait for input, like the characters `read-line` stored, it goes on from there.
+    pub trap_progress: Option<u16>,
+    pub on_eof: OnEof,
+    pub isa: IsaProfile,
+    // The program halted because the input ended.
+    pub input_ended: bool,
+    // The privilege bit of the PSR, programs run in user mode. The R6 of the mode not running is kept aside.
+    pub supervisor: bool,
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
+
+    pub console: C,
+    pub traps: TrapTable<C>,
 
-    pub io: (R, W),
+    pub symbols: SymbolTable,
+    pub debug_info: DebugInfo,
+    pub profiler: Option<Profiler>,
+    pub coverage: Option<Coverage>,
+    pub sandbox: Option<Sandbox>,
+    pub session: Option<Session>,
+    pub sanitizer: Option<Sanitizer>,
+    pub calls: Option<CallChecker>,
+    // Where `--microarch` writes the states it goes through
+    pub microtrace: Option<LineWriter<File>>,
 }
-impl Default for Hardware<std::io::Stdin, std::io::Stdout> {
+impl Default for Hardware<Stdio> {
     fn default() -> Self {
-        Hardware {
-            program_counter: ProgramCounter::default(),
-            registers: Registers::default(),
-            memory: Memory::default(),
-            flags: Flags::default(),
-            io: (std::io::stdin(), std::io::stdout()),
-        }
+        Hardware::with_console(Stdio::default())
     }
 }
-impl<R: std::io::Read, W> Hardware<R, W> {
-    #[allow(dead_code)]
-    pub fn default_with_io(io: (R, W)) -> Self {
+impl<C: Console> Hardware<C> {
+    pub fn with_console(console: C) -> Self {
         Hardware {
             program_counter: ProgramCounter::default(),
             registers: Registers::default(),
             memory: Memory::default(),
             flags: Flags::default(),
-            io,
+            halted: false,
+            instructions: 0,
+            waiting: false,
+            trap_progress: None,
+            on_eof: OnEof::Halt,
+            isa: IsaProfile::default(),
+            input_ended: false,
+            supervisor: false,
+            saved_ssp: isa::SUPERVISOR_STACK,
+            saved_usp: 0,
+            console,
+            traps: TrapTable::default(),
+            symbols: SymbolTable::default(),
+            debug_info: DebugInfo::default(),
+            profiler: None,
+            coverage: None,
+            sandbox: None,
+            session: None,
+            sanitizer: None,
+            calls: None,
+            microtrace: None,
         }
     }
 
-    pub fn load(&mut self, program: &[u16]) {
-        self.program_counter.set(program[0].try_into().unwrap());
-        self.memory.load(self.program_counter.get(), &program[1..]);
+    #[cfg(test)]
+    pub fn load(&mut self, program: &[u16]) -> Result<(), crate::loader::LoadError> {
+        crate::loader::check(program, crate::formats::Format::Object)?;
+
+        self.memory.load(program[0], &program[1..])?;
+        self.program_counter.set(program[0]);
+        Ok(())
+    }
+
+    // The label and source line of an address, for messages pointing at the program.
+    pub fn describe(&self, address: u16) -> String {
+        debuginfo::describe(address, &self.symbols, &self.debug_info)
+    }
+
+    // Takes back the instruction that just ran, it runs again once the input it waits for arrives.
+    pub fn retry(&mut self) {
+        let address = self.program_counter.get().wrapping_sub(1);
+        self.program_counter.set(address);
+        self.instructions = self.instructions.saturating_sub(1);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.retract(address);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.retract(address);
+        }
+    }
+
+    // Bit 15 is set in user mode, bits 2, 1 and 0 are N, Z and P.
+    pub fn psr(&self) -> u16 {
+        let flags = [(self.flags.is_negative(), 0b100), (self.flags.is_zero(), 0b010), (self.flags.is_positive(), 0b001)];
+        let mode = if self.supervisor { 0 } else { 1 << 15 };
+        flags.iter().filter(|(set, _)| *set).fold(mode, |psr, (_, bit)| psr | bit)
+    }
+
+    pub fn set_psr(&mut self, psr: u16) {
+        self.set_supervisor(psr & (1 << 15) == 0);
+        match psr & 0b111 {
+            0b100 => self.flags.set_negative(),
+            0b010 => self.flags.set_zero(),
+            0b001 => self.flags.set_positive(),
+            _ => {},
+        }
+    }
+
+    // Switching the mode switches R6 to the stack of the other mode.
+    pub fn set_supervisor(&mut self, supervisor: bool) {
+        if supervisor == self.supervisor {
+            return;
+        }
+        if supervisor {
+            self.saved_usp = self.registers.get(6);
+            self.registers.set(6, self.saved_ssp);
+        } else {
+            self.saved_ssp = self.registers.get(6);
+            self.registers.set(6, self.saved_usp);
+        }
+        self.supervisor = supervisor;
+    }
+
+    // An instruction starts at the address, both engines count it here.
+    pub fn count(&mut self, address: u16) {
+        self.instructions += 1;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.instruction(address);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.instruction(address);
+        }
     }
 
     pub fn next(&mut self) -> Option<u16> {
-        if self.program_counter.get() >= memory::MEMORY_SIZE as u16 {
+        // The PC cannot move past the last word, a program running into it has run off the end of memory
+        if self.halted || self.program_counter.get() as usize == memory::MEMORY_SIZE - 1 {
             return None;
         }
 
         let address = self.program_counter.next();
+        self.count(address);
+
         Some(self.memory.get(address))
     }
 
-    fn handle_keyboard(&mut self) {
-        let mut buf = [0; 1];
-        self.io.0.read_exact(&mut buf).unwrap();
-        let c = buf[0] as u16;
+    // Every byte the program reads comes from here, so it can be recorded or replayed.
+    // Polling the keyboard gets 0 when no key is ready or an interrupt stops the wait, the program pauses at the next instruction.
+    pub fn read_byte(&mut self, polling: bool) -> std::io::Result<u8> {
+        if let Some(byte) = self.session.as_mut().and_then(|session| session.next(self.instructions)) {
+            return byte;
+        }
+
+        let byte = match polling {
+            true => match self.console.poll_key() {
+                Ok(Some(key)) => key,
+                Ok(None) => 0,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => 0,
+                Err(err) => return Err(err),
+            },
+            false => self.console.read_key()?,
+        };
+        if polling && byte == 0 {
+            self.waiting = true;
+        }
+        if let Some(session) = &mut self.session {
+            session.save(self.instructions, byte)?;
+        }
+
+        Ok(byte)
+    }
+
+    // A key for the program, with what `on_eof` says once the input has ended.
+    pub fn read_key(&mut self, polling: bool) -> std::io::Result<u16> {
+        match self.read_byte(polling) {
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => self.end_of_input(err),
+            result => result.map(u16::from),
+        }
+    }
+
+    pub fn end_of_input(&mut self, err: std::io::Error) -> std::io::Result<u16> {
+        match self.on_eof {
+            OnEof::Halt => {
+                self.halted = true;
+                self.input_ended = true;
+                Err(err)
+            },
+            OnEof::Value(value) => Ok(value),
+            OnEof::Block => Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "the input has ended")),
+        }
+    }
+
+    // A replay that diverges or a console that fails stops the program at the read, like in a trap.
+    fn handle_keyboard(&mut self) -> Result<(), TrapError> {
+        let c = match self.read_key(true) {
+            Ok(c) => c,
+            Err(_) if self.input_ended => 0,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                self.waiting = true;
+                0
+            },
+            Err(err) => return Err(TrapError::Io(err)),
+        };
         if c == 0 {
             self.memory.set(MemoryMappedRegisters::KBSR as u16, 0);
         } else {
             self.memory.set(MemoryMappedRegisters::KBDR as u16, c);
             self.memory.set(MemoryMappedRegisters::KBSR as u16, 1 << 15);
         }
+        Ok(())
     }
 
-    pub fn get_memory(&mut self, address: u16) -> u16 {
+    pub fn get_memory(&mut self, address: u16) -> Result<u16, TrapError> {
         if address == MemoryMappedRegisters::KBSR as u16 {
-            self.handle_keyboard();
+            self.handle_keyboard()?;
         }
 
-        self.memory.get(address)
+        Ok(self.memory.get(address))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::loader::LoadError;
     use crate::utils;
 
     use super::*;
@@ -96,7 +274,7 @@ mod tests {
             0b0001_0010_0110_0001,
             0b0001_0100_1011_1111,
             0b0000_1111_1111_1010,
-        ]);
+        ]).unwrap();
 
         assert_eq!(hardware.memory.get(0x3000), 0b1110_0010_1111_1111);
         assert_eq!(hardware.memory.get(0x3005), 0b0110_1000_0100_0000);
@@ -104,12 +282,24 @@ mod tests {
         assert_eq!(hardware.memory.get(0x300A), 0b0000_0000_0000_0000);
     }
 
+    #[test]
+    fn load_empty_program() {
+        let mut hardware = Hardware::default();
+
+        assert!(matches!(hardware.load(&[]), Err(LoadError::Empty)));
+        assert_eq!(hardware.program_counter.get(), 0x3000);
+    }
+
     #[test]
     fn handle_keyboard() {
         let mut hardware = utils::setup_test_with_input("H");
-        hardware.handle_keyboard();
+        hardware.handle_keyboard().unwrap();
 
         assert_eq!(hardware.memory.get(MemoryMappedRegisters::KBDR as u16), 'H' as u16);
         assert_eq!(hardware.memory.get(MemoryMappedRegisters::KBSR as u16), 1 << 15);
+
+        hardware.handle_keyboard().unwrap();
+        assert_eq!(hardware.memory.get(MemoryMappedRegisters::KBSR as u16), 0);
+        assert!(hardware.halted && hardware.input_ended);
     }
 }
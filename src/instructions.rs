@@ -1,17 +1,46 @@
-use std::io::{Write, Read};
-
+use crate::calls;
+use crate::console::Console;
 use crate::hardware::Hardware;
-use crate::traps;
+use crate::isa::{self, Exception};
+use crate::sanitizer;
+use crate::traps::{self, TrapError};
 use crate::utils::{imm5, offset6, pcoffset9, register_at, pcoffset11};
 
-pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W>) {
+// Exceptions go to the handler in the interrupt vector table, without one they stop the program.
+pub fn process<C: Console>(instruction: u16, hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    sanitizer::check(instruction, hardware)?;
+    calls::check(instruction, hardware)?;
+    match execute(instruction, hardware) {
+        Err(err @ TrapError::Exception { .. }) => isa::raise(err, hardware),
+        result => result,
+    }
+}
+
+// The address when the program may access it, an access control violation otherwise.
+fn checked<C: Console>(instruction: u16, address: u16, hardware: &Hardware<C>) -> Result<u16, TrapError> {
+    match isa::allowed(hardware.isa, hardware.supervisor, address) {
+        true => Ok(address),
+        false => Err(TrapError::exception(Exception::AccessControl, instruction, hardware)),
+    }
+}
+
+fn execute<C: Console>(instruction: u16, hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    if let Some(exception) = isa::exception(hardware.isa, hardware.supervisor, instruction) {
+        return Err(TrapError::exception(exception, instruction, hardware));
+    }
+
     match instruction >> 12 {
         0x0 => {
             let n = (instruction & 0b0000_1000_0000_0000) == 0b0000_1000_0000_0000;
             let z = (instruction & 0b0000_0100_0000_0000) == 0b0000_0100_0000_0000;
             let p = (instruction & 0b0000_0010_0000_0000) == 0b0000_0010_0000_0000;
 
-            if n && hardware.flags.is_negative() || z && hardware.flags.is_zero() || p && hardware.flags.is_positive() {
+            let taken = n && hardware.flags.is_negative() || z && hardware.flags.is_zero() || p && hardware.flags.is_positive();
+            if let Some(coverage) = &mut hardware.coverage {
+                coverage.branch(hardware.program_counter.get().wrapping_sub(1), taken);
+            }
+
+            if taken {
                 let pcoffset9 = pcoffset9(instruction);
                 let loc = hardware.program_counter.get() as u32 + pcoffset9 as u32;
 
@@ -39,7 +68,7 @@ pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W
             let pcoffset9 = pcoffset9(instruction);
 
             let loc = hardware.program_counter.get() as u32 + pcoffset9 as u32;
-            let value = hardware.get_memory(loc as u16);
+            let value = hardware.get_memory(checked(instruction, loc as u16, hardware)?)?;
 
             hardware.registers.set(dr, value);
             hardware.flags.set(value);
@@ -49,12 +78,12 @@ pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W
             let pcoffset9 = pcoffset9(instruction);
 
             let loc = hardware.program_counter.get() as u32 + pcoffset9 as u32;
+            let loc = checked(instruction, loc as u16, hardware)?;
 
-            hardware.memory.set(loc as u16, hardware.registers.get(sr));
+            hardware.memory.set(loc, hardware.registers.get(sr));
         }, // ST
         0x4 => {
-            hardware.registers.set(7, hardware.program_counter.get());
-
+            // The target is read before R7 is written, so JSRR R7 works
             let loc = if instruction & 0b0000_1000_0000_0000 == 0b0000_0000_0000_0000 {
                 // JSSR
                 let baser = register_at(instruction, 6);
@@ -66,7 +95,11 @@ pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W
                 loc as u16
             };
 
+            hardware.registers.set(7, hardware.program_counter.get());
             hardware.program_counter.set(loc);
+            if let Some(profiler) = &mut hardware.profiler {
+                profiler.call(loc);
+            }
         }, // JSR / JSRR
         0x5 => {
             let dr = register_at(instruction, 9);
@@ -90,7 +123,7 @@ pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W
             let offset6 = offset6(instruction);
 
             let loc = hardware.registers.get(baser) as u32 + offset6 as u32;
-            let value = hardware.get_memory(loc as u16).clone();
+            let value = hardware.get_memory(checked(instruction, loc as u16, hardware)?)?;
 
             hardware.registers.set(dr, value);
             hardware.flags.set(value);
@@ -101,12 +134,16 @@ pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W
             let offset6 = offset6(instruction);
 
             let loc = hardware.registers.get(baser) as u32 + offset6 as u32;
+            let loc = checked(instruction, loc as u16, hardware)?;
             let value = hardware.registers.get(sr);
 
-            hardware.memory.set(loc as u16, value);
+            hardware.memory.set(loc, value);
         }, // STR
         0x8 => {
-            // Unused in a vm.
+            // Only an exception or a trap entered through the supervisor stack has somewhere to return to
+            if hardware.supervisor && hardware.isa.raises_exceptions() {
+                isa::rti(hardware);
+            }
         }, // RTI
         0x9 => {
             let dr = register_at(instruction, 9);
@@ -122,8 +159,8 @@ pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W
             let pcoffset9 = pcoffset9(instruction);
 
             let loc = hardware.program_counter.get() as u32 + pcoffset9 as u32;
-            let loc = hardware.get_memory(loc as u16);
-            let value = hardware.get_memory(loc);
+            let loc = hardware.get_memory(checked(instruction, loc as u16, hardware)?)?;
+            let value = hardware.get_memory(checked(instruction, loc, hardware)?)?;
 
             hardware.registers.set(dr, value);
             hardware.flags.set(value);
@@ -133,7 +170,8 @@ pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W
             let pcoffset9 = pcoffset9(instruction);
 
             let loc = hardware.program_counter.get() as u32 + pcoffset9 as u32;
-            let loc = hardware.get_memory(loc as u16);
+            let loc = hardware.get_memory(checked(instruction, loc as u16, hardware)?)?;
+            let loc = checked(instruction, loc, hardware)?;
 
             let value = hardware.registers.get(sr);
 
@@ -143,6 +181,11 @@ pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W
             let baser = register_at(instruction, 6);
 
             hardware.program_counter.set(hardware.registers.get(baser));
+            if baser == 7 {
+                if let Some(profiler) = &mut hardware.profiler {
+                    profiler.ret();
+                }
+            }
         }, // JMP / RET
         0xD => {}, // reserved
         0xE => {
@@ -152,16 +195,21 @@ pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W
             let value = hardware.program_counter.get() as u32 + pcoffset9 as u32;
 
             hardware.registers.set(dr, value as u16);
-            hardware.flags.set(value as u16);
+            if hardware.isa.lea_sets_cc() {
+                hardware.flags.set(value as u16);
+            }
         }, // LEA
-        0xF => traps::process(instruction, hardware), // TRAP
-        i @ _  => panic!("unknown instruction: {:#06b}", i),
+        0xF => traps::process(instruction, hardware)?, // TRAP
+        i => panic!("unknown instruction: {:#06b}, fault at {}", i, hardware.describe(hardware.program_counter.get().wrapping_sub(1))),
     };
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::isa::{Exception, IsaProfile};
     use crate::utils::setup_default_test;
 
     #[test]
@@ -169,14 +217,14 @@ mod tests {
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 15);
         hardware.registers.set(3, 15);
-        process(0b0001_0010_1000_0011u16, &mut hardware);
+        process(0b0001_0010_1000_0011u16, &mut hardware).unwrap();
 
         assert!(hardware.registers.get(1) == 30);
         assert!(hardware.flags.is_positive());
 
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 10);
-        process(0b0001_0010_1011_0001u16, &mut hardware);
+        process(0b0001_0010_1011_0001u16, &mut hardware).unwrap();
 
         assert!(hardware.registers.get(1) as i16 == -5);
         assert!(hardware.flags.is_negative());
@@ -187,14 +235,14 @@ mod tests {
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 0b0000_1100_1111_0000);
         hardware.registers.set(3, 0b0000_1111_0011_0000);
-        process(0b0101_0010_1000_0011u16, &mut hardware);
+        process(0b0101_0010_1000_0011u16, &mut hardware).unwrap();
 
         assert!(hardware.registers.get(1) == 0b0000_1100_0011_0000);
         assert!(hardware.flags.is_positive());
 
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 0b1111_1111_0000_0000);
-        process(0b0101_0010_1011_0001u16, &mut hardware);
+        process(0b0101_0010_1011_0001u16, &mut hardware).unwrap();
 
         assert_eq!(hardware.registers.get(1), 0b1111_1111_0000_0000);
         assert!(hardware.flags.is_negative());
@@ -204,12 +252,12 @@ mod tests {
     fn br() {
         let mut hardware = setup_default_test();
         hardware.flags.set_zero();
-        process(0b0000_1100_0000_0010, &mut hardware);
+        process(0b0000_1100_0000_0010, &mut hardware).unwrap();
 
         assert_eq!(hardware.program_counter.get(), 0x3002);
 
         let mut hardware = setup_default_test();
-        process(0b0000_0010_0000_0010, &mut hardware);
+        process(0b0000_0010_0000_0010, &mut hardware).unwrap();
 
         assert_eq!(hardware.program_counter.get(), 0x3000);
     }
@@ -218,7 +266,7 @@ mod tests {
     fn jmp() {
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 0x3002);
-        process(0b1100_0000_1000_0000, &mut hardware);
+        process(0b1100_0000_1000_0000, &mut hardware).unwrap();
 
         assert_eq!(hardware.program_counter.get(), 0x3002);
     }
@@ -226,7 +274,7 @@ mod tests {
     fn ret() {
         let mut hardware = setup_default_test();
         hardware.registers.set(7, 0x3002);
-        process(0b1100_0001_1100_0000, &mut hardware);
+        process(0b1100_0001_1100_0000, &mut hardware).unwrap();
 
         assert_eq!(hardware.program_counter.get(), 0x3002);
     }
@@ -234,7 +282,7 @@ mod tests {
     #[test]
     fn jsr() {
         let mut hardware = setup_default_test();
-        process(0b0100_1000_0000_0010, &mut hardware);
+        process(0b0100_1000_0000_0010, &mut hardware).unwrap();
 
         assert_eq!(hardware.program_counter.get(), 0x3002);
         assert_eq!(hardware.registers.get(7), 0x3000);
@@ -243,17 +291,24 @@ mod tests {
     fn jsrr() {
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 0x3002);
-        process(0b0100_0000_1000_0000, &mut hardware);
+        process(0b0100_0000_1000_0000, &mut hardware).unwrap();
 
         assert_eq!(hardware.program_counter.get(), 0x3002);
         assert_eq!(hardware.registers.get(7), 0x3000);
+
+        let mut hardware = setup_default_test();
+        hardware.registers.set(7, 0x4000);
+        process(0b0100_0001_1100_0000, &mut hardware).unwrap();
+
+        assert_eq!(hardware.program_counter.get(), 0x4000);
+        assert_eq!(hardware.registers.get(7), 0x3000);
     }
 
     #[test]
     fn not() {
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 0b1111_0000_0000_1111);
-        process(0b1001_0010_1011_1111, &mut hardware);
+        process(0b1001_0010_1011_1111, &mut hardware).unwrap();
 
         assert_eq!(hardware.registers.get(1), 0b0000_1111_1111_0000);
         assert!(hardware.flags.is_positive());
@@ -263,7 +318,7 @@ mod tests {
     fn st() {
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 0b0000_1111_1111_0000);
-        process(0b0011_0100_0000_0010, &mut hardware);
+        process(0b0011_0100_0000_0010, &mut hardware).unwrap();
 
         assert_eq!(hardware.memory.get(0x3002), 0b0000_1111_1111_0000);
     }
@@ -273,7 +328,7 @@ mod tests {
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 0b0000_1111_1111_0000);
         hardware.memory.set(0x3002, 0b0011_0000_1000_0000);
-        process(0b1011_0100_0000_0010, &mut hardware);
+        process(0b1011_0100_0000_0010, &mut hardware).unwrap();
 
         assert_eq!(hardware.memory.get(0x3080), 0b0000_1111_1111_0000);
     }
@@ -283,7 +338,7 @@ mod tests {
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 0b0000_1111_1111_0000);
         hardware.registers.set(3, 0x307F);
-        process(0b0111_0100_1100_0001, &mut hardware);
+        process(0b0111_0100_1100_0001, &mut hardware).unwrap();
 
         assert_eq!(hardware.memory.get(0x3080), 0b0000_1111_1111_0000);
     }
@@ -292,7 +347,7 @@ mod tests {
     fn ld() {
         let mut hardware = setup_default_test();
         hardware.memory.set(0x3002, 0b0000_1111_1111_0000);
-        process(0b0010_0010_0000_0010, &mut hardware);
+        process(0b0010_0010_0000_0010, &mut hardware).unwrap();
 
         assert_eq!(hardware.registers.get(1), 0b0000_1111_1111_0000);
         assert!(hardware.flags.is_positive());
@@ -303,7 +358,7 @@ mod tests {
         let mut hardware = setup_default_test();
         hardware.memory.set(0x3000, 0b0011_0000_0000_0010);
         hardware.memory.set(0x3002, 0b0000_1111_1111_0000);
-        process(0b1010_0010_0000_0000, &mut hardware);
+        process(0b1010_0010_0000_0000, &mut hardware).unwrap();
 
         assert_eq!(hardware.registers.get(1), 0b0000_1111_1111_0000);
         assert!(hardware.flags.is_positive());
@@ -314,18 +369,98 @@ mod tests {
         let mut hardware = setup_default_test();
         hardware.registers.set(2, 0x3001);
         hardware.memory.set(0x3002, 0b0000_1111_1111_0000);
-        process(0b0110_0010_1000_0001, &mut hardware);
+        process(0b0110_0010_1000_0001, &mut hardware).unwrap();
 
         assert_eq!(hardware.registers.get(1), 0b0000_1111_1111_0000);
         assert!(hardware.flags.is_positive());
     }
 
+    #[test]
+    fn ldr_last_word() {
+        let mut hardware = setup_default_test();
+        hardware.registers.set(2, 0xFFFF);
+        hardware.memory.set(0xFFFF, 0x1234);
+        process(0b0110_0010_1000_0000, &mut hardware).unwrap();
+
+        assert_eq!(hardware.registers.get(1), 0x1234);
+    }
+
     #[test]
     fn lea() {
         let mut hardware = setup_default_test();
-        process(0b1110_0010_0000_1111, &mut hardware);
+        process(0b1110_0010_0000_1111, &mut hardware).unwrap();
 
         assert_eq!(hardware.registers.get(1), 0b0011_0000_0000_1111);
         assert!(hardware.flags.is_positive());
     }
+
+    #[test]
+    fn isa_profiles() {
+        for profile in [IsaProfile::Legacy, IsaProfile::PattPatel2e, IsaProfile::PattPatel3e, IsaProfile::Lc3tools] {
+            let mut hardware = setup_default_test();
+            hardware.isa = profile;
+            hardware.flags.set(0);
+            process(0b1110_0010_0000_1111, &mut hardware).unwrap();
+            assert_eq!(hardware.flags.is_positive(), profile.lea_sets_cc(), "{:?}", profile);
+
+            let reserved = process(0b1101_0000_0000_0000, &mut hardware);
+            let rti = process(0b1000_0000_0000_0000, &mut hardware);
+            if profile == IsaProfile::Legacy {
+                assert!(reserved.is_ok() && rti.is_ok());
+            } else {
+                assert!(matches!(reserved, Err(TrapError::Exception { exception: Exception::IllegalOpcode, .. })), "{:?}", profile);
+                assert!(matches!(rti, Err(TrapError::Exception { exception: Exception::PrivilegeMode, .. })), "{:?}", profile);
+            }
+        }
+
+        let mut hardware = setup_default_test();
+        hardware.isa = IsaProfile::PattPatel3e;
+        hardware.program_counter.set(0x3001);
+        let err = process(0b1101_0000_0000_0000, &mut hardware).unwrap_err();
+        assert_eq!(err.to_string(), "illegal opcode by xD000, fault at x3000");
+    }
+
+    #[test]
+    fn access_control() {
+        let mut hardware = setup_default_test();
+        hardware.isa = IsaProfile::PattPatel3e;
+        hardware.program_counter.set(0x3001);
+        // STR R0, R1, #0 with R1 at the vector table, then at the keyboard status
+        for address in [0x0100, 0xFE00] {
+            hardware.registers.set(1, address);
+            let err = process(0b0111_0000_0100_0000, &mut hardware).unwrap_err();
+            assert!(matches!(err, TrapError::Exception { exception: Exception::AccessControl, .. }));
+        }
+
+        hardware.isa = IsaProfile::PattPatel2e;
+        hardware.registers.set(0, 0x1234);
+        process(0b0111_0000_0100_0000, &mut hardware).unwrap();
+        assert_eq!(hardware.memory.get(0xFE00), 0x1234);
+    }
+
+    #[test]
+    fn exception_handler() {
+        let mut hardware = setup_default_test();
+        hardware.isa = IsaProfile::Lc3tools;
+        hardware.memory.set(isa::IVT + Exception::IllegalOpcode as u16, 0x1000);
+        hardware.registers.set(6, 0xF000);
+        hardware.flags.set_zero();
+        // The reserved opcode was fetched from x3000
+        hardware.program_counter.set(0x3001);
+
+        process(0b1101_0000_0000_0000, &mut hardware).unwrap();
+        assert!(hardware.supervisor);
+        assert_eq!(hardware.program_counter.get(), 0x1000);
+        assert_eq!(hardware.registers.get(6), 0x2FFE);
+        // The PSR of the user program, then the PC it was at. The fetch has already moved the PC past the faulting
+        // instruction, so x3001 is saved and RTI goes on with the instruction after it.
+        assert_eq!(hardware.memory.get(0x2FFF), 0x8002);
+        assert_eq!(hardware.memory.get(0x2FFE), 0x3001);
+
+        process(0b1000_0000_0000_0000, &mut hardware).unwrap();
+        assert!(!hardware.supervisor);
+        assert!(hardware.flags.is_zero());
+        assert_eq!(hardware.program_counter.get(), 0x3001);
+        assert_eq!(hardware.registers.get(6), 0xF000);
+    }
 }
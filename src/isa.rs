@@ -0,0 +1,190 @@
+use std::fmt;
+
+use crate::console::Console;
+use crate::hardware::Hardware;
+use crate::traps::TrapError;
+
+// The interrupt vector table, the handler of exception `v` is at x0100 + v.
+pub const IVT: u16 = 0x0100;
+// The supervisor stack grows down from the start of the user space, like in the reference OS.
+pub const SUPERVISOR_STACK: u16 = 0x3000;
+
+// The details of the ISA that differ between the textbook editions and simulators, picked with `--isa`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum IsaProfile {
+    // What the emulator always did: LEA sets the condition codes, RTI and opcode 1101 do nothing
+    #[default]
+    Legacy,
+    PattPatel2e,
+    PattPatel3e,
+    // lc3tools follows the 3rd edition
+    Lc3tools,
+}
+impl IsaProfile {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "legacy" => Some(IsaProfile::Legacy),
+            "patt-patel-2e" => Some(IsaProfile::PattPatel2e),
+            "patt-patel-3e" => Some(IsaProfile::PattPatel3e),
+            "lc3tools" => Some(IsaProfile::Lc3tools),
+            _ => None,
+        }
+    }
+
+    // The 3rd edition dropped the condition codes from LEA, it only computes an address.
+    pub fn lea_sets_cc(self) -> bool {
+        matches!(self, IsaProfile::Legacy | IsaProfile::PattPatel2e)
+    }
+
+    // Opcode 1101 is an illegal opcode exception and RTI in user mode a privilege mode exception, instead of doing nothing.
+    pub fn raises_exceptions(self) -> bool {
+        !matches!(self, IsaProfile::Legacy)
+    }
+
+    // User mode cannot touch the system space and the device registers, and traps run in supervisor mode
+    // ending with RTI instead of returning through R7. The 3rd edition added both.
+    pub fn access_control(self) -> bool {
+        matches!(self, IsaProfile::PattPatel3e | IsaProfile::Lc3tools)
+    }
+}
+
+// The exceptions an instruction can raise, by their vector.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exception {
+    PrivilegeMode = 0x00,
+    IllegalOpcode = 0x01,
+    AccessControl = 0x02,
+}
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exception::PrivilegeMode => write!(f, "privilege mode violation"),
+            Exception::IllegalOpcode => write!(f, "illegal opcode"),
+            Exception::AccessControl => write!(f, "access control violation"),
+        }
+    }
+}
+
+// The exception the instruction raises under the profile, if any.
+pub fn exception(profile: IsaProfile, supervisor: bool, instruction: u16) -> Option<Exception> {
+    if !profile.raises_exceptions() {
+        return None;
+    }
+
+    match instruction >> 12 {
+        0x8 if !supervisor => Some(Exception::PrivilegeMode),
+        0xD => Some(Exception::IllegalOpcode),
+        _ => None,
+    }
+}
+
+// Whether the program may read or write the address in the mode it runs in.
+pub fn allowed(profile: IsaProfile, supervisor: bool, address: u16) -> bool {
+    supervisor || !profile.access_control() || (SUPERVISOR_STACK..0xFE00).contains(&address)
+}
+
+// Switches to supervisor mode, pushes the PSR and the PC on the supervisor stack and jumps to the routine.
+pub fn enter<C: Console>(hardware: &mut Hardware<C>, routine: u16) {
+    let psr = hardware.psr();
+    hardware.set_supervisor(true);
+    for value in [psr, hardware.program_counter.get()] {
+        let sp = hardware.registers.get(6).wrapping_sub(1);
+        hardware.registers.set(6, sp);
+        hardware.memory.set(sp, value);
+    }
+    hardware.program_counter.set(routine);
+}
+
+// Pops the PC and the PSR, going back to user mode when the PSR says so.
+pub fn rti<C: Console>(hardware: &mut Hardware<C>) {
+    let mut pop = || {
+        let sp = hardware.registers.get(6);
+        hardware.registers.set(6, sp.wrapping_add(1));
+        hardware.memory.get(sp)
+    };
+    let pc = pop();
+    let psr = pop();
+    hardware.program_counter.set(pc);
+    hardware.set_psr(psr);
+}
+
+// Runs the handler from the interrupt vector table, or gives the exception back as an error when no OS installed one.
+pub fn raise<C: Console>(err: TrapError, hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let TrapError::Exception { exception, .. } = &err else {
+        return Err(err);
+    };
+    let handler = hardware.memory.get(IVT + *exception as u16);
+    if handler == 0 {
+        return Err(err);
+    }
+
+    enter(hardware, handler);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles() {
+        assert_eq!(IsaProfile::parse("patt-patel-3e"), Some(IsaProfile::PattPatel3e));
+        assert_eq!(IsaProfile::parse("3e"), None);
+
+        let cases = [
+            (IsaProfile::Legacy, true, None),
+            (IsaProfile::PattPatel2e, true, Some(Exception::IllegalOpcode)),
+            (IsaProfile::PattPatel3e, false, Some(Exception::IllegalOpcode)),
+            (IsaProfile::Lc3tools, false, Some(Exception::IllegalOpcode)),
+        ];
+        for (profile, lea_sets_cc, reserved) in cases {
+            assert_eq!(profile.lea_sets_cc(), lea_sets_cc, "{:?}", profile);
+            assert_eq!(exception(profile, false, 0xD000), reserved, "{:?}", profile);
+            assert_eq!(exception(profile, false, 0x8000), reserved.map(|_| Exception::PrivilegeMode), "{:?}", profile);
+            assert_eq!(exception(profile, true, 0x8000), None);
+            assert_eq!(exception(profile, false, 0x1000), None);
+        }
+    }
+
+    #[test]
+    fn access_control() {
+        for (address, user) in [(0x0000, false), (0x2FFF, false), (0x3000, true), (0xFDFF, true), (0xFE00, false), (0xFFFF, false)] {
+            assert_eq!(allowed(IsaProfile::Lc3tools, false, address), user, "x{:04X}", address);
+            assert!(allowed(IsaProfile::Lc3tools, true, address));
+            assert!(allowed(IsaProfile::PattPatel2e, false, address));
+        }
+    }
+
+    #[test]
+    fn enter_and_return() {
+        let mut hardware = crate::utils::setup_default_test();
+        hardware.registers.set(6, 0x4000);
+        hardware.program_counter.set(0x3005);
+        hardware.flags.set_negative();
+
+        enter(&mut hardware, 0x1000);
+        assert!(hardware.supervisor);
+        assert_eq!(hardware.program_counter.get(), 0x1000);
+        assert_eq!(hardware.registers.get(6), 0x2FFE);
+        assert_eq!(hardware.memory.get(0x2FFF), 0x8004);
+        assert_eq!(hardware.memory.get(0x2FFE), 0x3005);
+
+        hardware.flags.set_zero();
+        rti(&mut hardware);
+        assert!(!hardware.supervisor && hardware.flags.is_negative());
+        assert_eq!(hardware.program_counter.get(), 0x3005);
+        assert_eq!(hardware.registers.get(6), 0x4000);
+        assert_eq!(hardware.saved_ssp, 0x3000);
+    }
+
+    #[test]
+    fn raise_without_handler() {
+        let mut hardware = crate::utils::setup_default_test();
+        hardware.program_counter.set(0x3001);
+        let err = TrapError::exception(Exception::AccessControl, 0x3000, &hardware);
+
+        let err = raise(err, &mut hardware).unwrap_err();
+        assert_eq!(err.to_string(), "access control violation by x3000, fault at x3000");
+        assert!(!hardware.supervisor);
+    }
+}
@@ -0,0 +1,526 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+
+use crate::formats::Format;
+use crate::loader::{self, LoadError};
+use crate::symbols::SymbolTable;
+
+const DEFAULT_ORIGIN: u16 = 0x3000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RelocationKind {
+    PcOffset9,
+    PcOffset11,
+    Fill,
+}
+impl RelocationKind {
+    fn parse(text: &str) -> Option<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "pcoffset9" => Some(RelocationKind::PcOffset9),
+            "pcoffset11" => Some(RelocationKind::PcOffset11),
+            "fill" => Some(RelocationKind::Fill),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RelocationKind::PcOffset9 => "PCoffset9",
+            RelocationKind::PcOffset11 => "PCoffset11",
+            RelocationKind::Fill => "FILL",
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self {
+            RelocationKind::PcOffset9 => 9,
+            RelocationKind::PcOffset11 => 11,
+            RelocationKind::Fill => 16,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Section {
+    pub name: String,
+    pub origin: Option<u16>,
+    pub words: Vec<u16>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub section: usize,
+    pub offset: u16,
+    pub global: bool,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Relocation {
+    pub section: usize,
+    pub offset: u16,
+    pub kind: RelocationKind,
+    pub symbol: String,
+    pub addend: i32,
+}
+
+// A separately assembled module, its sections are placed and patched by `link`.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Module {
+    pub name: String,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub externs: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug)]
+pub enum LinkError {
+    Io(String, io::Error),
+    Syntax { module: String, line: usize, message: String },
+    PastSection { module: String, section: String, offset: u16 },
+    SymbolPastSection { module: String, symbol: String, section: String, offset: u16 },
+    PastMemory { module: String, section: String, offset: u16 },
+    Empty,
+    DuplicateSymbol { name: String, first: String, second: String },
+    UndefinedSymbol { name: String, module: String },
+    OutOfRange { module: String, section: String, offset: u16, kind: RelocationKind, symbol: String, distance: i32 },
+    Overlap { first: String, second: String, start: u16, end: u16 },
+    Load(LoadError),
+}
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Io(path, err) => write!(f, "{}: {}", path, err),
+            LinkError::Syntax { module, line, message } => write!(f, "{}:{}: {}", module, line, message),
+            LinkError::PastSection { module, section, offset } => write!(f, "{}: relocation at {}+{} is past the end of the section", module, section, offset),
+            LinkError::SymbolPastSection { module, symbol, section, offset } => {
+                write!(f, "{}: symbol {} at {}+{} is past the end of the section", module, symbol, section, offset)
+            },
+            LinkError::PastMemory { module, section, offset } => write!(f, "{}: {}+{} is placed past xFFFF", module, section, offset),
+            LinkError::Empty => write!(f, "nothing to link, the modules have no words"),
+            LinkError::DuplicateSymbol { name, first, second } => write!(f, "{} is exported by both {} and {}", name, first, second),
+            LinkError::UndefinedSymbol { name, module } => write!(f, "{}: undefined symbol {}", module, name),
+            LinkError::OutOfRange { module, section, offset, kind, symbol, distance } => {
+                let half = 1 << (kind.bits() - 1);
+                write!(
+                    f,
+                    "{}: {}+{}: {} to {} is {} words away, the field holds {} to {}",
+                    module, section, offset, kind.name(), symbol, distance, -half, half - 1,
+                )
+            },
+            LinkError::Overlap { first, second, start, end } => write!(f, "{} and {} both take x{:04X}-x{:04X}", first, second, start, end),
+            LinkError::Load(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Module {
+    pub fn read(path: &str) -> Result<Self, LinkError> {
+        let text = fs::read_to_string(path).map_err(|err| LinkError::Io(path.to_string(), err))?;
+        Self::parse(path, &text)
+    }
+
+    // Text format, one directive per line:
+    //   .section NAME [ORIGIN]          following lines hold the words of the section in hex
+    //   .global NAME SECTION OFFSET     symbol visible to other modules
+    //   .local NAME SECTION OFFSET      symbol only this module can refer to
+    //   .extern NAME                    symbol defined by another module
+    //   .reloc SECTION OFFSET KIND SYMBOL[+ADDEND]
+    pub fn parse(name: &str, text: &str) -> Result<Self, LinkError> {
+        let mut module = Module { name: name.to_string(), ..Module::default() };
+
+        for (index, line) in text.lines().enumerate() {
+            let syntax = |message: String| LinkError::Syntax { module: name.to_string(), line: index + 1, message };
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let section = |name: &str| {
+                module.sections.iter().position(|section| section.name == name).ok_or_else(|| syntax(format!("unknown section {}", name)))
+            };
+            let offset = |text: &str| text.parse::<u16>().map_err(|_| syntax(format!("invalid offset {}", text)));
+
+            match fields[..] {
+                [".section", section_name] | [".section", section_name, _] => {
+                    let origin = match fields.get(2) {
+                        Some(origin) => Some(loader::parse_address(origin).ok_or_else(|| syntax(format!("invalid origin {}", origin)))?),
+                        None => None,
+                    };
+                    module.sections.push(Section { name: section_name.to_string(), origin, words: Vec::new() });
+                },
+                [directive @ (".global" | ".local"), symbol, section_name, symbol_offset] => {
+                    let symbol = Symbol {
+                        name: symbol.to_string(),
+                        section: section(section_name)?,
+                        offset: offset(symbol_offset)?,
+                        global: directive == ".global",
+                    };
+                    module.symbols.push(symbol);
+                },
+                [".extern", symbol] => module.externs.push(symbol.to_string()),
+                [".reloc", section_name, reloc_offset, kind, target] => {
+                    let kind = RelocationKind::parse(kind).ok_or_else(|| syntax(format!("unknown relocation kind {}", kind)))?;
+                    let (symbol, addend) = match target.find(['+', '-']) {
+                        Some(at) => (&target[..at], target[at..].trim_start_matches('+').parse::<i32>().map_err(|_| syntax(format!("invalid addend in {}", target)))?),
+                        None => (target, 0),
+                    };
+                    let relocation = Relocation {
+                        section: section(section_name)?,
+                        offset: offset(reloc_offset)?,
+                        kind,
+                        symbol: symbol.to_string(),
+                        addend,
+                    };
+                    module.relocations.push(relocation);
+                },
+                _ if line.starts_with('.') => return Err(syntax(format!("invalid directive `{}`", line))),
+                _ => {
+                    let words = fields
+                        .iter()
+                        .map(|word| match u16::from_str_radix(word, 16) {
+                            Ok(value) if word_is_hex(word) => Ok(value),
+                            _ => Err(syntax(format!("invalid word {}", word))),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let section = module.sections.last_mut().ok_or_else(|| syntax("words outside of a section".to_string()))?;
+                    section.words.extend(words);
+                },
+            }
+        }
+
+        for relocation in &module.relocations {
+            if relocation.offset as usize >= module.sections[relocation.section].words.len() {
+                return Err(LinkError::PastSection {
+                    module: name.to_string(),
+                    section: module.sections[relocation.section].name.clone(),
+                    offset: relocation.offset,
+                });
+            }
+        }
+        for symbol in &module.symbols {
+            if symbol.offset as usize >= module.sections[symbol.section].words.len() {
+                return Err(LinkError::SymbolPastSection {
+                    module: name.to_string(),
+                    symbol: symbol.name.clone(),
+                    section: module.sections[symbol.section].name.clone(),
+                    offset: symbol.offset,
+                });
+            }
+        }
+
+        Ok(module)
+    }
+
+    #[allow(dead_code)]
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        for section in &self.sections {
+            match section.origin {
+                Some(origin) => writeln!(text, ".section {} x{:04X}", section.name, origin).unwrap(),
+                None => writeln!(text, ".section {}", section.name).unwrap(),
+            }
+            for line in section.words.chunks(8) {
+                writeln!(text, "{}", line.iter().map(|word| format!("{:04X}", word)).collect::<Vec<_>>().join(" ")).unwrap();
+            }
+        }
+        for symbol in &self.symbols {
+            let directive = if symbol.global { ".global" } else { ".local" };
+            writeln!(text, "{} {} {} {}", directive, symbol.name, self.sections[symbol.section].name, symbol.offset).unwrap();
+        }
+        for name in &self.externs {
+            writeln!(text, ".extern {}", name).unwrap();
+        }
+        for relocation in &self.relocations {
+            let kind = relocation.kind.name().to_ascii_lowercase();
+            let section = &self.sections[relocation.section].name;
+            match relocation.addend {
+                0 => writeln!(text, ".reloc {} {} {} {}", section, relocation.offset, kind, relocation.symbol).unwrap(),
+                addend => writeln!(text, ".reloc {} {} {} {}{:+}", section, relocation.offset, kind, relocation.symbol, addend).unwrap(),
+            }
+        }
+
+        text
+    }
+}
+
+fn word_is_hex(word: &str) -> bool {
+    word.len() == 4 && word.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub struct Linked {
+    pub program: Vec<u16>,
+    pub symbols: SymbolTable,
+}
+
+// Places every section, resolves the symbols and patches the relocated fields into one flat image.
+// Sections with an origin stay there, the others follow the previous section, starting at `origin`.
+pub fn link(modules: &[Module], origin: Option<u16>) -> Result<Linked, LinkError> {
+    let base = origin
+        .or_else(|| {
+            let sections = modules.iter().flat_map(|module| &module.sections);
+            sections.filter(|section| !section.words.is_empty()).find_map(|section| section.origin)
+        })
+        .unwrap_or(DEFAULT_ORIGIN);
+
+    let mut addresses = Vec::new();
+    let mut placed = Vec::<(String, u16, u16)>::new();
+    let mut cursor = base as usize;
+    for module in modules {
+        let mut module_addresses = Vec::new();
+        for section in &module.sections {
+            let start = section.origin.map(usize::from).unwrap_or(cursor);
+            let end = start + section.words.len();
+            if end > 0x10000 {
+                return Err(LinkError::Load(LoadError::Overflow {
+                    origin: start as u16,
+                    words: section.words.len(),
+                    position: loader::Position::Offset(0),
+                }));
+            }
+
+            if !section.words.is_empty() {
+                let name = format!("{}:{}", module.name, section.name);
+                for (other, other_start, other_end) in &placed {
+                    if start as u16 <= *other_end && *other_start <= (end - 1) as u16 {
+                        return Err(LinkError::Overlap {
+                            first: other.clone(),
+                            second: name,
+                            start: (start as u16).max(*other_start),
+                            end: ((end - 1) as u16).min(*other_end),
+                        });
+                    }
+                }
+                placed.push((name, start as u16, (end - 1) as u16));
+            }
+
+            module_addresses.push(start as u16);
+            // An empty section takes no room, it neither moves the next sections nor widens the image
+            if !section.words.is_empty() {
+                cursor = cursor.max(end);
+            }
+        }
+        addresses.push(module_addresses);
+    }
+
+    let mut globals = HashMap::<&str, (u16, &str)>::new();
+    for (module, module_addresses) in modules.iter().zip(&addresses) {
+        for symbol in module.symbols.iter().filter(|symbol| symbol.global) {
+            let address = place(module, module_addresses, symbol.section, symbol.offset)?;
+            if let Some((_, first)) = globals.insert(&symbol.name, (address, &module.name)) {
+                return Err(LinkError::DuplicateSymbol { name: symbol.name.clone(), first: first.to_string(), second: module.name.clone() });
+            }
+        }
+    }
+
+    let start = placed.iter().map(|(_, start, _)| *start).min().ok_or(LinkError::Empty)?;
+    let end = placed.iter().map(|(_, _, end)| *end).max().ok_or(LinkError::Empty)?;
+    let mut image = vec![0; (end - start) as usize + 1];
+    let mut symbols = SymbolTable::default();
+
+    for (module, module_addresses) in modules.iter().zip(&addresses) {
+        let resolve = |name: &str| {
+            module
+                .symbols
+                .iter()
+                .find(|symbol| symbol.name == name)
+                .map(|symbol| place(module, module_addresses, symbol.section, symbol.offset))
+                .or_else(|| globals.get(name).filter(|_| module.externs.iter().any(|e| e == name)).map(|(address, _)| Ok(*address)))
+                .unwrap_or_else(|| Err(LinkError::UndefinedSymbol { name: name.to_string(), module: module.name.clone() }))
+        };
+
+        for (section, &address) in module.sections.iter().zip(module_addresses).filter(|(section, _)| !section.words.is_empty()) {
+            let at = (address - start) as usize;
+            image[at..at + section.words.len()].copy_from_slice(&section.words);
+        }
+
+        for relocation in &module.relocations {
+            let address = place(module, module_addresses, relocation.section, relocation.offset)?;
+            let target = resolve(&relocation.symbol)? as i32 + relocation.addend;
+            let word = &mut image[(address - start) as usize];
+
+            *word = match relocation.kind {
+                RelocationKind::Fill => target as u16,
+                kind => {
+                    let distance = target - (address as i32 + 1);
+                    let half = 1 << (kind.bits() - 1);
+                    if distance < -half || distance >= half {
+                        return Err(LinkError::OutOfRange {
+                            module: module.name.clone(),
+                            section: module.sections[relocation.section].name.clone(),
+                            offset: relocation.offset,
+                            kind,
+                            symbol: relocation.symbol.clone(),
+                            distance,
+                        });
+                    }
+
+                    let mask = (1 << kind.bits()) - 1;
+                    (*word & !mask) | (distance as u16 & mask)
+                },
+            };
+        }
+
+        for symbol in &module.symbols {
+            if symbols.get(&symbol.name).is_none() {
+                symbols.insert(&symbol.name, place(module, module_addresses, symbol.section, symbol.offset)?);
+            }
+        }
+    }
+
+    let mut program = vec![start];
+    program.extend(image);
+    loader::check(&program, Format::Object).map_err(LinkError::Load)?;
+
+    Ok(Linked { program, symbols })
+}
+
+// The address of a word in a placed section.
+fn place(module: &Module, module_addresses: &[u16], section: usize, offset: u16) -> Result<u16, LinkError> {
+    module_addresses[section].checked_add(offset).ok_or_else(|| LinkError::PastMemory {
+        module: module.name.clone(),
+        section: module.sections[section].name.clone(),
+        offset,
+    })
+}
+
+// Links the modules into `output`, written in the format given by its extension, with a `.sym` file next to it.
+pub fn link_files(inputs: &[String], output: &str, origin: Option<u16>) -> Result<(), LinkError> {
+    let modules = inputs.iter().map(|path| Module::read(path)).collect::<Result<Vec<_>, _>>()?;
+    let linked = link(&modules, origin)?;
+
+    let format = Format::from_extension(output).unwrap_or(Format::Object);
+    let symbols_path = std::path::Path::new(output).with_extension("sym");
+    fs::write(output, format.write(&linked.program)).map_err(|err| LinkError::Io(output.to_string(), err))?;
+    fs::write(&symbols_path, linked.symbols.to_lc3as()).map_err(|err| LinkError::Io(symbols_path.display().to_string(), err))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &str = "; main program
+.section text x3000
+E000 4800 F025
+.section data
+0000
+.global MAIN text 0
+.local MESSAGE_PTR data 0
+.extern MESSAGE
+.extern PRINT
+.reloc text 0 pcoffset9 MESSAGE_PTR
+.reloc text 1 pcoffset11 PRINT
+.reloc data 0 fill MESSAGE
+";
+
+    const LIBRARY: &str = ".section text
+F022 C1C0
+.section strings
+0048 0069 0000
+.global PRINT text 0
+.global MESSAGE strings 0
+";
+
+    #[test]
+    fn parse_and_write() {
+        let module = Module::parse("main.robj", MAIN).unwrap();
+
+        assert_eq!(module.sections.len(), 2);
+        assert_eq!(module.sections[0].origin, Some(0x3000));
+        assert_eq!(module.sections[0].words, vec![0xE000, 0x4800, 0xF025]);
+        assert_eq!(module.symbols[1], Symbol { name: "MESSAGE_PTR".to_string(), section: 1, offset: 0, global: false });
+        assert_eq!(module.relocations[1].kind, RelocationKind::PcOffset11);
+        assert_eq!(Module::parse("main.robj", &module.to_text()).unwrap(), module);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = Module::parse("bad.robj", ".section text\nE00\n").unwrap_err();
+        assert_eq!(err.to_string(), "bad.robj:2: invalid word E00");
+
+        let err = Module::parse("bad.robj", ".section text\n0000\n.reloc data 0 fill X\n").unwrap_err();
+        assert_eq!(err.to_string(), "bad.robj:3: unknown section data");
+
+        let err = Module::parse("bad.robj", ".section text\n0000\n.reloc text 0 offset6 X\n").unwrap_err();
+        assert_eq!(err.to_string(), "bad.robj:3: unknown relocation kind offset6");
+
+        let err = Module::parse("bad.robj", ".section text\n0000\n.reloc text 1 fill X\n").unwrap_err();
+        assert_eq!(err.to_string(), "bad.robj: relocation at text+1 is past the end of the section");
+
+        let err = Module::parse("bad.robj", ".section text xFFFF\n0000\n.global END text 1\n").unwrap_err();
+        assert_eq!(err.to_string(), "bad.robj: symbol END at text+1 is past the end of the section");
+    }
+
+    #[test]
+    fn link_modules() {
+        let main = Module::parse("main.robj", MAIN).unwrap();
+        let library = Module::parse("library.robj", LIBRARY).unwrap();
+
+        let linked = link(&[main, library], None).unwrap();
+
+        assert_eq!(linked.program, vec![
+            0x3000,
+            0b1110_0000_0000_0010, // LEA R0, MESSAGE_PTR
+            0b0100_1000_0000_0010, // JSR PRINT
+            0xF025,
+            0x3006, // .FILL MESSAGE
+            0xF022,
+            0xC1C0,
+            0x0048,
+            0x0069,
+            0x0000,
+        ]);
+        assert_eq!(linked.symbols.get("PRINT"), Some(0x3004));
+        assert_eq!(linked.symbols.get("MESSAGE_PTR"), Some(0x3003));
+    }
+
+    #[test]
+    fn empty_sections() {
+        let module = Module::parse("main.robj", ".section bss x1000\n.section text x3000\nF025\n.section heap xF000\n.section rest\n1234\n").unwrap();
+
+        let linked = link(&[module], None).unwrap();
+        assert_eq!(linked.program, vec![0x3000, 0xF025, 0x1234]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let main = Module::parse("main.robj", MAIN).unwrap();
+        let library = Module::parse("library.robj", &LIBRARY.replace(".section text", ".section text x3400")).unwrap();
+
+        let linked = link(&[main, library], None).unwrap();
+        assert_eq!(linked.program[2], 0b0100_1011_1111_1110);
+
+        let library = Module::parse("library.robj", &LIBRARY.replace(".section text", ".section text x3900")).unwrap();
+        let err = link(&[Module::parse("main.robj", MAIN).unwrap(), library], None).err().unwrap();
+        assert!(matches!(err, LinkError::OutOfRange { kind: RelocationKind::PcOffset11, distance: 2302, .. }));
+        assert_eq!(err.to_string(), "main.robj: text+1: PCoffset11 to PRINT is 2302 words away, the field holds -1024 to 1023");
+    }
+
+    #[test]
+    fn unresolved() {
+        let main = Module::parse("main.robj", MAIN).unwrap();
+
+        let err = link(std::slice::from_ref(&main), None).err().unwrap();
+        assert_eq!(err.to_string(), "main.robj: undefined symbol PRINT");
+
+        let library = Module::parse("library.robj", LIBRARY).unwrap();
+        let err = link(&[main, library.clone(), library], None).err().unwrap();
+        assert_eq!(err.to_string(), "PRINT is exported by both library.robj and library.robj");
+    }
+
+    #[test]
+    fn overlap() {
+        let first = Module::parse("a.robj", ".section text x3000\n0000 0000\n").unwrap();
+        let second = Module::parse("b.robj", ".section text x3001\n0000\n").unwrap();
+
+        let err = link(&[first, second], None).err().unwrap();
+        assert_eq!(err.to_string(), "a.robj:text and b.robj:text both take x3001-x3001");
+    }
+}
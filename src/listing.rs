@@ -0,0 +1,187 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::assembler::Program;
+use crate::expression;
+use crate::preprocess::Location;
+
+// Every source line with the address and words it assembled to, lines written by macros and
+// pseudo-instructions are marked with `+` under the line they came from.
+// The symbol table and the cross-reference of the labels follow.
+pub fn listing(program: &Program) -> String {
+    let mut text = String::new();
+    let mut file = None;
+
+    writeln!(text, "Address  Hex   Binary               Line  Source").unwrap();
+    for listed in &program.lines {
+        let line = &listed.line;
+        let expanded = line.generated || line.location.expansion.is_some();
+        if !expanded && file != Some(&line.location.file) {
+            writeln!(text, "; {}", line.location.file).unwrap();
+            file = Some(&line.location.file);
+        }
+
+        let address = match listed.address {
+            Some(address) if !listed.words.is_empty() || line.label.is_some() => format!("x{:04X}", address),
+            _ => String::new(),
+        };
+        let (hex, bits) = match listed.words.first() {
+            Some(word) => (format!("{:04X}", word), binary(*word)),
+            None => (String::new(), String::new()),
+        };
+        let (number, source) = if expanded {
+            (String::new(), format!("  + {}", line.text.trim()))
+        } else {
+            (line.location.line.to_string(), line.text.clone())
+        };
+        writeln!(text, "{:<7}  {:<4}  {:<19}  {:>4}  {}", address, hex, bits, number, source).unwrap();
+
+        // Words past the first one, like the characters of a .STRINGZ, get a line each
+        for (index, word) in listed.words.iter().enumerate().skip(1) {
+            let address = listed.address.unwrap_or_default().wrapping_add(index as u16);
+            writeln!(text, "x{:04X}    {:04X}  {}", address, word, binary(*word)).unwrap();
+        }
+    }
+
+    let references = references(program);
+
+    writeln!(text).unwrap();
+    writeln!(text, "Symbol table").unwrap();
+    writeln!(text, "Address  Name").unwrap();
+    let mut symbols = references.iter().map(|(name, (address, _, _))| (*address, name)).collect::<Vec<_>>();
+    symbols.sort();
+    for (address, name) in symbols {
+        writeln!(text, "x{:04X}    {}", address, name).unwrap();
+    }
+
+    writeln!(text).unwrap();
+    writeln!(text, "Cross-reference").unwrap();
+    writeln!(text, "{:<16}  {:<16}  Used at", "Name", "Defined at").unwrap();
+    for (name, (_, defined, used)) in &references {
+        let used = if used.is_empty() { "-".to_string() } else { used.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ") };
+        writeln!(text, "{:<16}  {:<16}  {}", name, defined.to_string(), used).unwrap();
+    }
+
+    text
+}
+
+// Groups the bits by 4, like the instruction literals in the tests: `0001_0010_1000_0011`.
+fn binary(word: u16) -> String {
+    let bits = format!("{:016b}", word);
+    bits.as_bytes().chunks(4).map(|group| std::str::from_utf8(group).unwrap()).collect::<Vec<_>>().join("_")
+}
+
+// Every label with its address, the line defining it and the lines using it, as written in the source.
+fn references(program: &Program) -> BTreeMap<&str, (u16, &Location, Vec<&Location>)> {
+    let mut references = BTreeMap::new();
+    for listed in &program.lines {
+        if let Some(label) = &listed.line.label {
+            if let Some(address) = program.symbols.get(label) {
+                references.insert(label.as_str(), (address, listed.line.location.origin(), Vec::new()));
+            }
+        }
+    }
+
+    for listed in &program.lines {
+        let names = RefCell::new(Vec::new());
+        for operand in &listed.line.operands {
+            expression::substitute(operand, &|word| {
+                names.borrow_mut().push(word.to_string());
+                None
+            });
+        }
+
+        let location = listed.line.location.origin();
+        for name in names.into_inner() {
+            if let Some((_, _, used)) = references.get_mut(name.as_str()) {
+                if used.last() != Some(&location) {
+                    used.push(location);
+                }
+            }
+        }
+    }
+
+    references
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+    use crate::preprocess::Preprocessor;
+
+    fn assemble(text: &str) -> Program {
+        let mut preprocessor = Preprocessor::default();
+        preprocessor.process_source("main.asm", text).unwrap();
+        assembler::assemble(&preprocessor.lines).unwrap()
+    }
+
+    #[test]
+    fn binary_groups() {
+        assert_eq!(binary(0b0001_0010_1000_0011), "0001_0010_1000_0011");
+    }
+
+    #[test]
+    fn expansions() {
+        let program = assemble(".ORIG x3000\n; save R1\nSAVE PUSH R1\n     LDI16 R2, #1234\n     .STRINGZ \"ab\"\n.END");
+        let listing = listing(&program);
+
+        assert!(listing.starts_with(
+            "Address  Hex   Binary               Line  Source
+; main.asm
+                                       1  .ORIG x3000
+                                       2  ; save R1
+x3000                                  3  SAVE PUSH R1
+x3000    1DBF  0001_1101_1011_1111          + ADD R6, R6, #-1
+x3001    7380  0111_0011_1000_0000          + STR R1, R6, #0
+                                       4       LDI16 R2, #1234
+x3002    2403  0010_0100_0000_0011          + LD R2, <literal 1>
+x3003    0061  0000_0000_0110_0001     5       .STRINGZ \"ab\"
+x3004    0062  0000_0000_0110_0010
+x3005    0000  0000_0000_0000_0000
+x3006    04D2  0000_0100_1101_0010          + <literal 1> .FILL #1234
+                                       6  .END
+"
+        ));
+    }
+
+    #[test]
+    fn cross_reference() {
+        let program = assemble(
+            ".ORIG x3000
+.MACRO WAIT
+@spin   BRnzp @spin
+.ENDM
+MAIN    LEA R0, MESSAGE
+        PUTS
+        BRnzp MAIN
+        JSR MAIN
+        WAIT
+MESSAGE .STRINGZ \"hi\"
+UNUSED  HALT
+.END",
+        );
+        let listing = listing(&program);
+        let tables = &listing[listing.find("\nSymbol table").unwrap()..];
+
+        assert_eq!(
+            tables,
+            "
+Symbol table
+Address  Name
+x3000    MAIN
+x3004    spin@1
+x3005    MESSAGE
+x3008    UNUSED
+
+Cross-reference
+Name              Defined at        Used at
+MAIN              main.asm:5        main.asm:7, main.asm:8
+MESSAGE           main.asm:10       main.asm:5
+UNUSED            main.asm:11       -
+spin@1            main.asm:9        main.asm:9
+",
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::formats::Format;
+use crate::memory::MEMORY_SIZE;
+
+pub const DEVICE_REGISTERS: u16 = 0xFE00;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Empty,
+    OddLength { len: usize },
+    InvalidLine { line: usize, text: String, format: Format },
+    Overflow { origin: u16, words: usize, position: Position },
+    DeviceRegion { address: u16, position: Position },
+    Overlap { first: String, second: String, start: u16, end: u16 },
+    UnknownEntry(String),
+    Syntax { line: usize, message: String },
+    InFile(String, Box<LoadError>),
+}
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "could not read file: {}", err),
+            LoadError::Empty => write!(f, "file is empty, expected an origin word followed by the program"),
+            LoadError::OddLength { len } => write!(
+                f,
+                "file has an odd number of bytes ({}), the byte at offset {} is not part of a complete word",
+                len, len - 1,
+            ),
+            LoadError::InvalidLine { line, text, format } => write!(
+                f,
+                "line {} `{}` is not a word in the {} format, expected {}",
+                line, text, format.name(), format.expected(),
+            ),
+            LoadError::Overflow { origin, words, position } => write!(
+                f,
+                "program of {} words loaded at x{:04X} does not fit in memory, the word at {} would be placed past xFFFF",
+                words, origin, position,
+            ),
+            LoadError::DeviceRegion { address, position } => write!(
+                f,
+                "program overlaps the device registers (x{:04X}-xFFFF), the word at {} would be loaded to x{:04X}",
+                DEVICE_REGISTERS, position, address,
+            ),
+            LoadError::Overlap { first, second, start, end } => write!(
+                f,
+                "{} and {} both load to x{:04X}-x{:04X}",
+                first, second, start, end,
+            ),
+            LoadError::UnknownEntry(entry) => write!(f, "entry point {} is neither an address nor a known label", entry),
+            LoadError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            LoadError::InFile(path, err) => write!(f, "{}: {}", path, err),
+        }
+    }
+}
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+pub struct Image {
+    pub path: String,
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+impl Image {
+    pub fn read(path: &str) -> Result<Self, LoadError> {
+        let program = read_file(path).map_err(|err| LoadError::InFile(path.to_string(), Box::new(err)))?;
+
+        Ok(Image {
+            path: path.to_string(),
+            origin: program[0],
+            words: program[1..].to_vec(),
+        })
+    }
+
+    // Addresses taken by the image, `None` when it has no words.
+    pub fn span(&self) -> Option<(u16, u16)> {
+        if self.words.is_empty() {
+            None
+        } else {
+            Some((self.origin, self.origin + (self.words.len() - 1) as u16))
+        }
+    }
+}
+
+pub fn check_overlaps(images: &[Image]) -> Result<(), LoadError> {
+    for (index, first) in images.iter().enumerate() {
+        for second in &images[index + 1..] {
+            if let (Some((first_start, first_end)), Some((second_start, second_end))) = (first.span(), second.span()) {
+                if first_start <= second_end && second_start <= first_end {
+                    return Err(LoadError::Overlap {
+                        first: first.path.clone(),
+                        second: second.path.clone(),
+                        start: first_start.max(second_start),
+                        end: first_end.min(second_end),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Accepts `x3000`, `0x3000` and `#12288`.
+pub fn parse_address(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix(['x', 'X'])) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        text.strip_prefix('#')?.parse().ok()
+    }
+}
+
+// Where a word is stored in the file it was read from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Position {
+    Offset(usize),
+    Line(usize),
+}
+impl Position {
+    // Position of the word loaded `index` words after the origin.
+    pub fn of(format: Format, index: usize) -> Self {
+        match format {
+            Format::Object => Position::Offset(2 + index * 2),
+            Format::Hex | Format::Binary => Position::Line(2 + index),
+        }
+    }
+}
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Position::Offset(offset) => write!(f, "offset {}", offset),
+            Position::Line(line) => write!(f, "line {}", line),
+        }
+    }
+}
+
+pub fn read_file(file_path: &str) -> Result<Vec<u16>, LoadError> {
+    let bytes = fs::read(file_path)?;
+    let format = Format::detect(file_path, &bytes);
+
+    let program = format.parse(&bytes)?;
+    check(&program, format)?;
+
+    Ok(program)
+}
+
+pub fn check(program: &[u16], format: Format) -> Result<(), LoadError> {
+    let (&origin, image) = program.split_first().ok_or(LoadError::Empty)?;
+
+    if origin as usize + image.len() > MEMORY_SIZE {
+        return Err(LoadError::Overflow {
+            origin,
+            words: image.len(),
+            position: Position::of(format, MEMORY_SIZE - origin as usize),
+        });
+    }
+    if !image.is_empty() && origin as usize + image.len() > DEVICE_REGISTERS as usize {
+        let index = (DEVICE_REGISTERS as usize).saturating_sub(origin as usize);
+        return Err(LoadError::DeviceRegion {
+            address: origin + index as u16,
+            position: Position::of(format, index),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow() {
+        let err = check(&[0xFFFF, 1, 2], Format::Object).unwrap_err();
+
+        assert!(matches!(err, LoadError::Overflow { origin: 0xFFFF, words: 2, position: Position::Offset(4) }));
+    }
+
+    #[test]
+    fn device_region() {
+        assert!(check(&[0xFDFE, 1, 2], Format::Object).is_ok());
+
+        let err = check(&[0xFDFE, 1, 2, 3], Format::Object).unwrap_err();
+        assert!(matches!(err, LoadError::DeviceRegion { address: 0xFE00, position: Position::Offset(6) }));
+        assert_eq!(
+            err.to_string(),
+            "program overlaps the device registers (xFE00-xFFFF), the word at offset 6 would be loaded to xFE00",
+        );
+
+        let err = check(&[0xFE02, 1], Format::Object).unwrap_err();
+        assert!(matches!(err, LoadError::DeviceRegion { address: 0xFE02, position: Position::Offset(2) }));
+
+        let err = check(&[0xFDFF, 1, 2], Format::Hex).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "program overlaps the device registers (xFE00-xFFFF), the word at line 3 would be loaded to xFE00",
+        );
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(matches!(read_file("missing.obj"), Err(LoadError::Io(_))));
+
+        let err = Image::read("missing.obj").err().unwrap();
+        assert!(err.to_string().starts_with("missing.obj: could not read file"));
+    }
+
+    fn image(path: &str, origin: u16, len: usize) -> Image {
+        Image { path: path.to_string(), origin, words: vec![0; len] }
+    }
+
+    #[test]
+    fn overlaps() {
+        assert!(check_overlaps(&[image("main.obj", 0x3000, 0x10), image("lib.obj", 0x3010, 0x10), image("empty.obj", 0x3000, 0)]).is_ok());
+
+        let err = check_overlaps(&[image("main.obj", 0x3000, 0x10), image("data.obj", 0x4000, 4), image("lib.obj", 0x300C, 0x10)]).unwrap_err();
+        assert!(matches!(err, LoadError::Overlap { start: 0x300C, end: 0x300F, .. }));
+        assert_eq!(err.to_string(), "main.obj and lib.obj both load to x300C-x300F");
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_address("x3000"), Some(0x3000));
+        assert_eq!(parse_address("0x30ff"), Some(0x30FF));
+        assert_eq!(parse_address("#12288"), Some(0x3000));
+        assert_eq!(parse_address("START"), None);
+        assert_eq!(parse_address("xGG"), None);
+    }
+}
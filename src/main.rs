@@ -5,28 +5,138 @@ mod run;
 mod instructions;
 mod utils;
 mod traps;
+mod microarch;
+mod args;
+mod symbols;
+mod disassembler;
+mod profile;
+mod coverage;
+mod loader;
+mod formats;
+mod link;
+mod expression;
+mod preprocess;
+mod assembler;
+mod listing;
+mod debuginfo;
+mod sandbox;
+mod terminal;
+mod monitor;
+mod session;
+mod screen;
+mod spec;
+mod console;
+mod isa;
+mod calls;
+mod sanitizer;
 
+use std::fs;
 use std::path::Path;
 
-use termios::*;
-
 fn main() {
-    let file_path = std::env::args().nth(1).expect("missing file path");
-    if !Path::new(&file_path).exists() {
-        panic!("file does not exist");
+    let command = args::Command::parse(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        std::process::exit(2);
+    });
+
+    match command {
+        args::Command::Run(args) => run(*args),
+        args::Command::Convert { input, output } => {
+            if let Err(err) = formats::convert(&input, &output) {
+                eprintln!("error: could not convert {}: {}", input, err);
+                std::process::exit(1);
+            }
+        },
+        args::Command::Assemble { input, output, listing } => {
+            let program = assembler::assemble_file(Path::new(&input)).and_then(|program| {
+                assembler::write(&program, &output)?;
+                Ok(program)
+            });
+            let program = program.unwrap_or_else(|err| {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            });
+
+            if let Some(path) = listing {
+                if !write_report(path, listing::listing(&program)) {
+                    std::process::exit(1);
+                }
+            }
+        },
+        args::Command::Link { inputs, output, origin } => {
+            if let Err(err) = link::link_files(&inputs, &output, origin) {
+                eprintln!("error: could not link {}: {}", output, err);
+                std::process::exit(1);
+            }
+        },
+        args::Command::Test { specs } => {
+            let mut failed = false;
+            for path in specs {
+                match spec::run_file(&path) {
+                    Ok(passed) => println!("{}: ok, {} checks", path, passed),
+                    Err(failure) => {
+                        eprintln!("error: {}", failure);
+                        failed = true;
+                    },
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+fn run(args: args::Args) {
+    let mut hardware = hardware::Hardware::<console::Stdio>::default();
+    if let Err(err) = run::load(&args, &mut hardware) {
+        eprintln!("error: could not load program: {}", err);
+        std::process::exit(1);
     }
 
-    let stdin = 0;
-    let termios = termios::Termios::from_fd(stdin).unwrap();
+    let terminal = terminal::Terminal::cbreak(0, args.break_key).unwrap_or_else(|err| {
+        eprintln!("error: could not set up the terminal: {}", err);
+        std::process::exit(1);
+    });
+    terminal::catch_interrupts();
+    let result = run::run(&args, &mut hardware, &terminal);
+    drop(terminal);
 
-    let mut new_termios = termios.clone();
-    new_termios.c_iflag &= IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON;
-    new_termios.c_lflag &= !(ICANON | ECHO);
+    let mut written = true;
+    if let Some(profiler) = &hardware.profiler {
+        if args.profile {
+            eprint!("{}", profiler.report(&hardware.memory, &hardware.symbols));
+        }
+        if let Some(path) = &args.flamegraph {
+            written &= write_report(path, profiler.folded(&hardware.symbols));
+        }
+    }
+
+    let calls = hardware.calls.iter().flat_map(|calls| &calls.warnings);
+    for warning in hardware.sanitizer.iter().flat_map(|sanitizer| &sanitizer.warnings).chain(calls) {
+        eprintln!("warning: {}", warning);
+    }
 
-    tcsetattr(stdin, TCSANOW, &mut new_termios).unwrap();
+    if let (Some(coverage), Some(path)) = (&hardware.coverage, &args.coverage) {
+        written &= write_report(path, coverage.listing(&args.files.join(", "), &hardware.memory, &hardware.symbols));
+        written &= write_report(Path::new(path).with_extension("info"), coverage.lcov(path, &hardware.memory));
+    }
 
-    let mut hardware = hardware::Hardware::default();
-    run::run(&file_path, &mut hardware);
+    match result {
+        Ok(()) if !written => std::process::exit(1),
+        // A run that halts because the input ended can be told apart from one the program halted itself
+        Ok(()) if hardware.input_ended => std::process::exit(3),
+        Ok(()) => {},
+        Err(traps::TrapError::Interrupted) => std::process::exit(130),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        },
+    }
+}
 
-    tcsetattr(stdin, TCSANOW, &termios).unwrap();
+// A report of the run, a path that cannot be written is an error after the run, the other reports are still written.
+fn write_report(path: impl AsRef<Path>, contents: String) -> bool {
+    let path = path.as_ref();
+    fs::write(path, contents).map_err(|err| eprintln!("error: cannot write {}: {}", path.display(), err)).is_ok()
 }
@@ -1,9 +1,15 @@
-pub const MEMORY_SIZE: usize = 0xFFFF;
+use crate::formats::Format;
+use crate::loader::{LoadError, Position};
 
-pub struct Memory([u16; MEMORY_SIZE]);
+// The whole 16-bit address space, x0000-xFFFF.
+pub const MEMORY_SIZE: usize = 0x10000;
+
+// The words and which of them something wrote, for the sanitizer.
+#[derive(PartialEq)]
+pub struct Memory([u16; MEMORY_SIZE], Vec<bool>);
 impl Default for Memory {
     fn default() -> Self {
-        Memory([0; MEMORY_SIZE])
+        Memory([0; MEMORY_SIZE], vec![false; MEMORY_SIZE])
     }
 }
 impl Memory {
@@ -12,15 +18,34 @@ impl Memory {
     }
     pub fn set(&mut self, address: u16, value: u16) {
         self.0[address as usize] = value;
+        self.1[address as usize] = true;
+    }
+
+    pub fn is_written(&self, address: u16) -> bool {
+        self.1[address as usize]
     }
 
-    pub fn load(&mut self, start: u16, program: &[u16]) {
+    // Sets the words to what `value` gives without counting them as written.
+    pub fn fill(&mut self, addresses: std::ops::Range<u16>, mut value: impl FnMut() -> u16) {
+        for address in addresses {
+            self.0[address as usize] = value();
+        }
+    }
+
+    pub fn load(&mut self, start: u16, program: &[u16]) -> Result<(), LoadError> {
+        let origin = start;
         let start = start as usize;
-        if program.len() >= MEMORY_SIZE - start {
-            panic!("could not load, program too big")
+        if start + program.len() > MEMORY_SIZE {
+            return Err(LoadError::Overflow {
+                origin,
+                words: program.len(),
+                position: Position::of(Format::Object, MEMORY_SIZE - start),
+            });
         }
 
         self.0[start..start + program.len()].copy_from_slice(program);
+        self.1[start..start + program.len()].fill(true);
+        Ok(())
     }
 }
 
@@ -34,9 +59,16 @@ mod tests {
 
         memory.set(0x3000, 0b0000_0000_1111_0000);
         memory.set(0xF0F0, 0b1000_1000_1000_1000);
+        memory.set(0xFFFF, 0b0000_0000_0000_0001);
 
         assert_eq!(memory.get(0xF0F0), 0b1000_1000_1000_1000);
         assert_eq!(memory.get(0x3000), 0b0000_0000_1111_0000);
+        assert_eq!(memory.get(0xFFFF), 0b0000_0000_0000_0001);
+        assert!(memory.is_written(0x3000) && !memory.is_written(0x3001));
+
+        memory.fill(0x3000..0x3002, || 0xDEAD);
+        assert_eq!(memory.get(0x3001), 0xDEAD);
+        assert!(!memory.is_written(0x3001));
     }
 
     #[test]
@@ -54,11 +86,22 @@ mod tests {
             0b0001_0010_0110_0001,
             0b0001_0100_1011_1111,
             0b0000_1111_1111_1010,
-        ]);
+        ]).unwrap();
 
         assert_eq!(memory.get(0x3000), 0b1110_0010_1111_1111);
         assert_eq!(memory.get(0x3005), 0b0110_1000_0100_0000);
         assert_eq!(memory.get(0x3009), 0b0000_1111_1111_1010);
         assert_eq!(memory.get(0x300A), 0b0000_0000_0000_0000);
     }
+
+    #[test]
+    fn load_program_too_big() {
+        let mut memory = Memory::default();
+
+        assert!(matches!(memory.load(0xFFFE, &[1, 2, 3]), Err(LoadError::Overflow { origin: 0xFFFE, words: 3, .. })));
+        assert_eq!(memory.get(0xFFFE), 0);
+
+        memory.load(0xFFFE, &[1, 2]).unwrap();
+        assert_eq!(memory.get(0xFFFF), 2);
+    }
 }
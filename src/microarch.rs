@@ -0,0 +1,764 @@
+use std::fmt;
+
+use crate::calls;
+use crate::console::Console;
+use crate::hardware::Hardware;
+use crate::isa;
+use crate::memory;
+use crate::sanitizer;
+use crate::traps::{self, TrapError};
+use crate::utils::{imm5, offset6, pcoffset9, pcoffset11, register_at};
+
+pub const FETCH: u8 = 18;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Cond {
+    #[default]
+    Unconditional,
+    MemoryReady,
+    Branch,
+    AddressingMode,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum PcMux {
+    #[default]
+    Increment,
+    Bus,
+    Adder,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum DrMux {
+    #[default]
+    Ir11,
+    R7,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Sr1Mux {
+    #[default]
+    Ir11,
+    Ir8,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Addr1Mux {
+    #[default]
+    Pc,
+    BaseR,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Addr2Mux {
+    #[default]
+    Zero,
+    Offset6,
+    PcOffset9,
+    PcOffset11,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum MarMux {
+    #[default]
+    Zext,
+    Adder,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Aluk {
+    #[default]
+    Add,
+    And,
+    Not,
+    PassA,
+}
+
+// One microinstruction of the control store (Patt & Patel, appendix C).
+// Interrupt and privilege related fields are left out, the vm has no use for them.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ControlWord {
+    pub ird: bool,
+    pub cond: Cond,
+    pub j: u8,
+
+    pub ld_mar: bool,
+    pub ld_mdr: bool,
+    pub ld_ir: bool,
+    pub ld_ben: bool,
+    pub ld_reg: bool,
+    pub ld_cc: bool,
+    pub ld_pc: bool,
+
+    pub gate_pc: bool,
+    pub gate_mdr: bool,
+    pub gate_alu: bool,
+    pub gate_marmux: bool,
+
+    pub pcmux: PcMux,
+    pub drmux: DrMux,
+    pub sr1mux: Sr1Mux,
+    pub addr1mux: Addr1Mux,
+    pub addr2mux: Addr2Mux,
+    pub marmux: MarMux,
+    pub aluk: Aluk,
+
+    pub mio_en: bool,
+    pub r_w: bool,
+}
+
+pub fn control_store(state: u8) -> ControlWord {
+    let word = ControlWord::default();
+    let memory_read = ControlWord { mio_en: true, ld_mdr: true, cond: Cond::MemoryReady, ..word };
+    let pc_relative = ControlWord { ld_mar: true, gate_marmux: true, marmux: MarMux::Adder, addr1mux: Addr1Mux::Pc, addr2mux: Addr2Mux::PcOffset9, ..word };
+    let base_relative = ControlWord { ld_mar: true, gate_marmux: true, marmux: MarMux::Adder, addr1mux: Addr1Mux::BaseR, sr1mux: Sr1Mux::Ir8, addr2mux: Addr2Mux::Offset6, ..word };
+    let subroutine = ControlWord { ld_reg: true, drmux: DrMux::R7, gate_pc: true, ..word };
+    let operate = ControlWord { ld_reg: true, ld_cc: true, gate_alu: true, drmux: DrMux::Ir11, sr1mux: Sr1Mux::Ir8, j: FETCH, ..word };
+
+    match state {
+        0 => ControlWord { cond: Cond::Branch, j: FETCH, ..word }, // BR
+        1 => ControlWord { aluk: Aluk::Add, ..operate }, // ADD
+        2 => ControlWord { j: 25, ..pc_relative }, // LD
+        3 => ControlWord { j: 23, ..pc_relative }, // ST
+        // R7 is written together with the PC, as in the 3rd edition, so JSRR R7 jumps to the old R7
+        4 => ControlWord { cond: Cond::AddressingMode, j: 20, ..word }, // JSR / JSRR
+        5 => ControlWord { aluk: Aluk::And, ..operate }, // AND
+        6 => ControlWord { j: 25, ..base_relative }, // LDR
+        7 => ControlWord { j: 23, ..base_relative }, // STR
+        8 => ControlWord { j: FETCH, ..word }, // RTI, the supervisor stack is handled by `isa`
+        9 => ControlWord { aluk: Aluk::Not, ..operate }, // NOT
+        10 => ControlWord { j: 24, ..pc_relative }, // LDI
+        11 => ControlWord { j: 29, ..pc_relative }, // STI
+        12 => ControlWord { ld_pc: true, pcmux: PcMux::Adder, addr1mux: Addr1Mux::BaseR, sr1mux: Sr1Mux::Ir8, addr2mux: Addr2Mux::Zero, j: FETCH, ..word }, // JMP / RET
+        13 => ControlWord { j: FETCH, ..word }, // reserved
+        14 => ControlWord { ld_reg: true, ld_cc: true, gate_marmux: true, marmux: MarMux::Adder, addr1mux: Addr1Mux::Pc, addr2mux: Addr2Mux::PcOffset9, drmux: DrMux::Ir11, j: FETCH, ..word }, // LEA
+        15 => ControlWord { ld_mar: true, gate_marmux: true, marmux: MarMux::Zext, j: 28, ..word }, // TRAP
+        16 => ControlWord { mio_en: true, r_w: true, cond: Cond::MemoryReady, j: 16, ..word }, // M[MAR] <- MDR
+        18 => ControlWord { ld_mar: true, ld_pc: true, gate_pc: true, pcmux: PcMux::Increment, j: 33, ..word }, // MAR <- PC, PC <- PC + 1
+        20 => ControlWord { ld_pc: true, pcmux: PcMux::Adder, addr1mux: Addr1Mux::BaseR, sr1mux: Sr1Mux::Ir8, addr2mux: Addr2Mux::Zero, j: FETCH, ..subroutine }, // R7 <- PC, PC <- BaseR
+        21 => ControlWord { ld_pc: true, pcmux: PcMux::Adder, addr1mux: Addr1Mux::Pc, addr2mux: Addr2Mux::PcOffset11, j: FETCH, ..subroutine }, // R7 <- PC, PC <- PC + off11
+        22 => ControlWord { ld_pc: true, pcmux: PcMux::Adder, addr1mux: Addr1Mux::Pc, addr2mux: Addr2Mux::PcOffset9, j: FETCH, ..word }, // PC <- PC + off9
+        23 => ControlWord { ld_mdr: true, gate_alu: true, aluk: Aluk::PassA, sr1mux: Sr1Mux::Ir11, j: 16, ..word }, // MDR <- SR
+        24 => ControlWord { j: 24, ..memory_read }, // MDR <- M[MAR]
+        25 => ControlWord { j: 25, ..memory_read }, // MDR <- M[MAR]
+        26 => ControlWord { ld_mar: true, gate_mdr: true, j: 25, ..word }, // MAR <- MDR
+        27 => ControlWord { ld_reg: true, ld_cc: true, gate_mdr: true, drmux: DrMux::Ir11, j: FETCH, ..word }, // DR <- MDR
+        28 => ControlWord { ld_reg: true, drmux: DrMux::R7, gate_pc: true, j: 28, ..memory_read }, // MDR <- M[MAR], R7 <- PC
+        29 => ControlWord { j: 29, ..memory_read }, // MDR <- M[MAR]
+        30 => ControlWord { ld_pc: true, pcmux: PcMux::Bus, gate_mdr: true, j: FETCH, ..word }, // PC <- MDR
+        31 => ControlWord { ld_mar: true, gate_mdr: true, j: 23, ..word }, // MAR <- MDR
+        32 => ControlWord { ld_ben: true, ird: true, ..word }, // decode
+        33 => ControlWord { j: 33, ..memory_read }, // MDR <- M[MAR]
+        35 => ControlWord { ld_ir: true, gate_mdr: true, j: 32, ..word }, // IR <- MDR
+        s => panic!("unused microstate: {}", s),
+    }
+}
+
+// Values on the datapath during a single clock cycle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Microstate {
+    pub cycle: u64,
+    pub state: u8,
+    pub next: u8,
+    pub control: ControlWord,
+
+    pub bus: u16,
+    pub pc: u16,
+    pub mar: u16,
+    pub mdr: u16,
+    pub ir: u16,
+    pub ben: bool,
+    pub ready: bool,
+    pub host_trap: bool,
+    // An exception, RTI or a trap went through the supervisor stack in this one cycle.
+    pub system: bool,
+}
+impl fmt::Display for Microstate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>8} state {:>2} -> {:>2}  BUS={:#06x} PC={:#06x} MAR={:#06x} MDR={:#06x} IR={:#06x} BEN={} R={}",
+            self.cycle, self.state, self.next, self.bus, self.pc, self.mar, self.mdr, self.ir, self.ben as u8, self.ready as u8,
+        )?;
+
+        let control = &self.control;
+        for (name, set) in [
+            ("IRD", control.ird), ("LD.MAR", control.ld_mar), ("LD.MDR", control.ld_mdr), ("LD.IR", control.ld_ir), ("LD.BEN", control.ld_ben),
+            ("LD.REG", control.ld_reg), ("LD.CC", control.ld_cc), ("LD.PC", control.ld_pc),
+            ("GatePC", control.gate_pc), ("GateMDR", control.gate_mdr), ("GateALU", control.gate_alu), ("GateMARMUX", control.gate_marmux),
+            ("MIO.EN", control.mio_en),
+        ] {
+            if set {
+                write!(f, " {}", name)?;
+            }
+        }
+        if control.mio_en {
+            write!(f, " R.W={}", if control.r_w { "WR" } else { "RD" })?;
+        }
+        if self.host_trap {
+            write!(f, " TRAP x{:02X} handled by host", self.ir & 0xFF)?;
+        }
+        if self.system {
+            write!(f, " supervisor stack switched by host")?;
+        }
+
+        Ok(())
+    }
+}
+
+// Executes the program one clock cycle at a time by following the LC-3 state diagram.
+// It shares the architectural state (PC, registers, flags, memory) with `instructions::process`,
+// so both engines can run on the same `Hardware`.
+pub struct Microsequencer {
+    pub state: u8,
+    pub mar: u16,
+    pub mdr: u16,
+    pub ir: u16,
+    pub ben: bool,
+    pub cycles: u64,
+
+    pub memory_latency: u8,
+    waited: u8,
+}
+impl Default for Microsequencer {
+    fn default() -> Self {
+        Microsequencer {
+            state: FETCH,
+            mar: 0,
+            mdr: 0,
+            ir: 0,
+            ben: false,
+            cycles: 0,
+            memory_latency: 1,
+            waited: 0,
+        }
+    }
+}
+impl Microsequencer {
+    pub fn cycle<C: Console>(&mut self, hardware: &mut Hardware<C>) -> Result<Microstate, TrapError> {
+        let state = self.state;
+        let mut control = control_store(state);
+        if state == 14 && !hardware.isa.lea_sets_cc() {
+            control.ld_cc = false;
+        }
+        let pc = hardware.program_counter.get();
+        let ir = self.ir;
+        if state == FETCH {
+            hardware.count(pc);
+        }
+
+        // Traps with a host handler skip the states reading the vector table.
+        let vector = (ir & 0xFF) as u8;
+        if state == 15 && hardware.traps.get(vector).is_some() {
+            // A trap waiting for input runs again from the fetch, the PC is back at it
+            if let Err(err) = traps::process(ir, hardware) {
+                if let TrapError::Blocked = err {
+                    self.state = FETCH;
+                }
+                return Err(err);
+            }
+            return Ok(self.shortcut(state, pc, true));
+        }
+        if state == 15 && hardware.memory.get(vector as u16) == 0 {
+            return Err(TrapError::unregistered(vector, hardware));
+        }
+        if state == 32 {
+            sanitizer::check(ir, hardware)?;
+            calls::check(ir, hardware)?;
+            decoded(ir, hardware);
+        }
+
+        // The supervisor stack is not part of the data path, exceptions, RTI and traps with access control
+        // push and pop it in one cycle.
+        let exception = match isa::exception(hardware.isa, hardware.supervisor, ir) {
+            Some(exception) if state == 8 || state == 13 => Some((exception, ir)),
+            // The fetch reads the instruction the violation is reported for
+            _ if control.mio_en && !isa::allowed(hardware.isa, hardware.supervisor, self.mar) => {
+                Some((isa::Exception::AccessControl, if state == 33 { hardware.memory.get(self.mar) } else { ir }))
+            },
+            _ => None,
+        };
+        if let Some((exception, instruction)) = exception {
+            self.waited = 0;
+            isa::raise(TrapError::exception(exception, instruction, hardware), hardware)?;
+            return Ok(self.shortcut(state, pc, false));
+        }
+        if state == 8 && hardware.supervisor && hardware.isa.raises_exceptions() {
+            isa::rti(hardware);
+            return Ok(self.shortcut(state, pc, false));
+        }
+        if state == 15 && hardware.isa.access_control() {
+            traps::process(ir, hardware)?;
+            return Ok(self.shortcut(state, pc, false));
+        }
+
+        let sr1 = hardware.registers.get(match control.sr1mux {
+            Sr1Mux::Ir11 => register_at(ir, 9),
+            Sr1Mux::Ir8 => register_at(ir, 6),
+        });
+        let operand = if ir & 0b0000_0000_0010_0000 == 0 {
+            hardware.registers.get(register_at(ir, 0))
+        } else {
+            imm5(ir)
+        };
+        let alu = match control.aluk {
+            Aluk::Add => sr1.wrapping_add(operand),
+            Aluk::And => sr1 & operand,
+            Aluk::Not => !sr1,
+            Aluk::PassA => sr1,
+        };
+
+        let addr1 = match control.addr1mux {
+            Addr1Mux::Pc => pc,
+            Addr1Mux::BaseR => sr1,
+        };
+        let addr2 = match control.addr2mux {
+            Addr2Mux::Zero => 0,
+            Addr2Mux::Offset6 => offset6(ir),
+            Addr2Mux::PcOffset9 => pcoffset9(ir),
+            Addr2Mux::PcOffset11 => pcoffset11(ir),
+        };
+        let adder = addr1.wrapping_add(addr2);
+        let marmux = match control.marmux {
+            MarMux::Zext => ir & 0x00FF,
+            MarMux::Adder => adder,
+        };
+
+        let bus = if control.gate_pc {
+            pc
+        } else if control.gate_mdr {
+            self.mdr
+        } else if control.gate_alu {
+            alu
+        } else if control.gate_marmux {
+            marmux
+        } else {
+            0
+        };
+
+        let ready = if control.mio_en {
+            self.waited += 1;
+            self.waited >= self.memory_latency
+        } else {
+            false
+        };
+
+        if control.mio_en && ready {
+            self.waited = 0;
+            if control.r_w {
+                hardware.memory.set(self.mar, self.mdr);
+            } else if control.ld_mdr {
+                self.mdr = hardware.get_memory(self.mar)?;
+            }
+        } else if control.ld_mdr && !control.mio_en {
+            self.mdr = bus;
+        }
+
+        if control.ld_mar {
+            self.mar = bus;
+        }
+        if control.ld_ir {
+            self.ir = bus;
+        }
+        if control.ld_ben {
+            self.ben = (self.ir & 0b0000_1000_0000_0000 != 0 && hardware.flags.is_negative())
+                || (self.ir & 0b0000_0100_0000_0000 != 0 && hardware.flags.is_zero())
+                || (self.ir & 0b0000_0010_0000_0000 != 0 && hardware.flags.is_positive());
+        }
+        if control.ld_reg {
+            let dr = match control.drmux {
+                DrMux::Ir11 => register_at(ir, 9),
+                DrMux::R7 => 7,
+            };
+            hardware.registers.set(dr, bus);
+        }
+        if control.ld_cc {
+            hardware.flags.set(bus);
+        }
+        if control.ld_pc {
+            hardware.program_counter.set(match control.pcmux {
+                PcMux::Increment => pc.wrapping_add(1),
+                PcMux::Bus => bus,
+                PcMux::Adder => adder,
+            });
+        }
+
+        let next = if control.ird {
+            (self.ir >> 12) as u8
+        } else {
+            let mut next = control.j;
+            if control.cond == Cond::Branch && self.ben {
+                next |= 0b100;
+            }
+            if control.cond == Cond::MemoryReady && ready {
+                next |= 0b010;
+            }
+            if control.cond == Cond::AddressingMode && ir & 0b0000_1000_0000_0000 != 0 {
+                next |= 0b001;
+            }
+            next
+        };
+
+        Ok(self.finish(Microstate {
+            cycle: self.cycles,
+            state,
+            next,
+            control,
+            bus,
+            pc,
+            mar: self.mar,
+            mdr: self.mdr,
+            ir: self.ir,
+            ben: self.ben,
+            ready,
+            host_trap: false,
+            system: false,
+        }))
+    }
+
+    // A cycle the host did the work of, going back to the fetch.
+    fn shortcut(&mut self, state: u8, pc: u16, host_trap: bool) -> Microstate {
+        self.finish(Microstate {
+            cycle: self.cycles,
+            state,
+            next: FETCH,
+            control: ControlWord { j: FETCH, ..ControlWord::default() },
+            bus: 0,
+            pc,
+            mar: self.mar,
+            mdr: self.mdr,
+            ir: self.ir,
+            ben: self.ben,
+            ready: false,
+            host_trap,
+            system: !host_trap,
+        })
+    }
+
+    // Runs cycles until the fetch state is reached again.
+    #[allow(dead_code)]
+    pub fn instruction<C: Console>(&mut self, hardware: &mut Hardware<C>) -> Result<Vec<Microstate>, TrapError> {
+        let mut states = vec![self.cycle(hardware)?];
+        while self.state != FETCH {
+            states.push(self.cycle(hardware)?);
+        }
+
+        Ok(states)
+    }
+
+    pub fn is_finished<C>(&self, hardware: &Hardware<C>) -> bool {
+        self.state == FETCH && (hardware.halted || hardware.program_counter.get() as usize == memory::MEMORY_SIZE - 1)
+    }
+
+    fn finish(&mut self, microstate: Microstate) -> Microstate {
+        self.state = microstate.next;
+        self.cycles += 1;
+        microstate
+    }
+}
+
+// What `instructions::process` records for the profiler and the coverage as it runs the instruction, done once it is decoded.
+fn decoded<C: Console>(ir: u16, hardware: &mut Hardware<C>) {
+    let pc = hardware.program_counter.get();
+    if let (0x0, Some(coverage)) = (ir >> 12, &mut hardware.coverage) {
+        let flags = &hardware.flags;
+        let taken = (ir & 0b0000_1000_0000_0000 != 0 && flags.is_negative())
+            || (ir & 0b0000_0100_0000_0000 != 0 && flags.is_zero())
+            || (ir & 0b0000_0010_0000_0000 != 0 && flags.is_positive());
+        coverage.branch(pc.wrapping_sub(1), taken);
+    }
+    let os_routine = traps::os_routine(ir, hardware);
+    let Some(profiler) = &mut hardware.profiler else {
+        return;
+    };
+
+    match ir >> 12 {
+        0x4 if ir & 0b0000_1000_0000_0000 == 0 => profiler.call(hardware.registers.get(register_at(ir, 6))),
+        0x4 => profiler.call(pc.wrapping_add(pcoffset11(ir))),
+        0xC if register_at(ir, 6) == 7 => profiler.ret(),
+        0xF => {
+            if let Some(routine) = os_routine {
+                profiler.call(routine);
+            }
+        },
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::Buffer;
+    use crate::coverage::Coverage;
+    use crate::instructions;
+    use crate::isa::IsaProfile;
+    use crate::profile::Profiler;
+    use crate::symbols::SymbolTable;
+    use crate::utils::setup_default_test;
+
+    fn cross_check(program: &[u16], steps: usize) {
+        cross_check_with(program, steps, |_| {});
+    }
+
+    // Runs the program on both engines from the same setup, giving back the folded profile they agree on.
+    fn cross_check_with(program: &[u16], steps: usize, setup: fn(&mut Hardware<Buffer>)) -> String {
+        let mut isa = setup_default_test();
+        isa.load(program).unwrap();
+        isa.profiler = Some(Profiler::new(program[0]));
+        let mut micro = setup_default_test();
+        micro.load(program).unwrap();
+        micro.profiler = Some(Profiler::new(program[0]));
+        for hardware in [&mut isa, &mut micro] {
+            setup(hardware);
+            let mut coverage = Coverage::default();
+            coverage.cover(program[0], program.len() - 1);
+            hardware.coverage = Some(coverage);
+        }
+        let mut microsequencer = Microsequencer::default();
+
+        for step in 0..steps {
+            let instruction = isa.next().unwrap();
+            instructions::process(instruction, &mut isa).unwrap();
+            microsequencer.instruction(&mut micro).unwrap();
+
+            assert_eq!(micro.program_counter.get(), isa.program_counter.get(), "pc after step {}", step);
+            for r in 0..8 {
+                assert_eq!(micro.registers.get(r), isa.registers.get(r), "R{} after step {}", r, step);
+            }
+            assert_eq!(micro.flags.is_negative(), isa.flags.is_negative(), "n after step {}", step);
+            assert_eq!(micro.flags.is_zero(), isa.flags.is_zero(), "z after step {}", step);
+            assert_eq!(micro.flags.is_positive(), isa.flags.is_positive(), "p after step {}", step);
+        }
+
+        assert!(micro.memory == isa.memory);
+        assert_eq!(micro.console.output, isa.console.output);
+        assert_eq!(micro.instructions, isa.instructions);
+        let (micro_coverage, isa_coverage) = (micro.coverage.unwrap(), isa.coverage.unwrap());
+        assert_eq!(micro_coverage.lcov("test.lst", &micro.memory), isa_coverage.lcov("test.lst", &isa.memory));
+        let symbols = SymbolTable::default();
+        let folded = isa.profiler.unwrap().folded(&symbols);
+        assert_eq!(micro.profiler.unwrap().folded(&symbols), folded);
+        folded
+    }
+
+    #[test]
+    fn fetch_and_decode() {
+        let mut hardware = setup_default_test();
+        hardware.memory.set(0x3000, 0b0001_0010_0110_0001);
+        let mut microsequencer = Microsequencer::default();
+
+        let states = microsequencer.instruction(&mut hardware).unwrap();
+        let path = states.iter().map(|s| s.state).collect::<Vec<_>>();
+
+        assert_eq!(path, vec![18, 33, 35, 32, 1]);
+        assert_eq!(states[0].bus, 0x3000);
+        assert_eq!(states[1].mdr, 0b0001_0010_0110_0001);
+        assert_eq!(states[2].ir, 0b0001_0010_0110_0001);
+        assert_eq!(hardware.registers.get(1), 1);
+        assert_eq!(hardware.program_counter.get(), 0x3001);
+    }
+
+    #[test]
+    fn memory_latency() {
+        let mut hardware = setup_default_test();
+        let mut microsequencer = Microsequencer { memory_latency: 3, ..Microsequencer::default() };
+
+        let path = microsequencer.instruction(&mut hardware).unwrap().iter().map(|s| s.state).collect::<Vec<_>>();
+
+        assert_eq!(path, vec![18, 33, 33, 33, 35, 32, 0]);
+    }
+
+    #[test]
+    fn state_paths() {
+        let cases: [(u16, &[u8]); 8] = [
+            (0b0010_0010_0000_0010, &[2, 25, 27]), // LD
+            (0b1010_0010_0000_0000, &[10, 24, 26, 25, 27]), // LDI
+            (0b0011_0100_0000_0010, &[3, 23, 16]), // ST
+            (0b1011_0100_0000_0010, &[11, 29, 31, 23, 16]), // STI
+            (0b0100_1000_0000_0010, &[4, 21]), // JSR
+            (0b0100_0000_1000_0000, &[4, 20]), // JSRR
+            (0b0000_0100_0000_0010, &[0, 22]), // BRz taken
+            (0b0000_1000_0000_0010, &[0]), // BRn not taken
+        ];
+
+        for (instruction, expected) in cases {
+            let mut hardware = setup_default_test();
+            hardware.memory.set(0x3000, instruction);
+            let mut microsequencer = Microsequencer::default();
+
+            let path = microsequencer.instruction(&mut hardware).unwrap().iter().map(|s| s.state).collect::<Vec<_>>();
+
+            assert_eq!(&path[4..], expected, "{:#018b}", instruction);
+        }
+    }
+
+    #[test]
+    fn host_trap() {
+        let mut hardware = setup_default_test();
+        hardware.registers.set(0, 'H' as u16);
+        hardware.memory.set(0x3000, 0b1111_0000_0010_0001);
+        let mut microsequencer = Microsequencer::default();
+
+        let states = microsequencer.instruction(&mut hardware).unwrap();
+
+        assert!(states.last().unwrap().host_trap);
+        assert_eq!(hardware.console.output, b"H");
+    }
+
+    #[test]
+    fn supervisor_stack() {
+        let mut hardware = setup_default_test();
+        hardware.isa = IsaProfile::PattPatel3e;
+        hardware.registers.set(1, 0x0200);
+        hardware.memory.set(isa::IVT + isa::Exception::AccessControl as u16, 0x1000);
+        hardware.memory.set(0x3000, 0b0110_0000_0100_0000); // LDR R0, R1, #0
+        hardware.memory.set(0x1000, 0b1000_0000_0000_0000); // RTI
+        let mut microsequencer = Microsequencer::default();
+
+        let states = microsequencer.instruction(&mut hardware).unwrap();
+        assert!(states.last().unwrap().system);
+        assert!(hardware.supervisor);
+        assert_eq!(hardware.program_counter.get(), 0x1000);
+
+        microsequencer.instruction(&mut hardware).unwrap();
+        assert!(!hardware.supervisor);
+        assert_eq!(hardware.program_counter.get(), 0x3001);
+    }
+
+    #[test]
+    fn vector_table_trap() {
+        let mut hardware = setup_default_test();
+        hardware.memory.set(0x0026, 0x4000);
+        hardware.memory.set(0x3000, 0b1111_0000_0010_0110);
+        let mut microsequencer = Microsequencer::default();
+
+        let path = microsequencer.instruction(&mut hardware).unwrap().iter().map(|s| s.state).collect::<Vec<_>>();
+
+        assert_eq!(&path[4..], &[15, 28, 30]);
+        assert_eq!(hardware.program_counter.get(), 0x4000);
+        assert_eq!(hardware.registers.get(7), 0x3001);
+    }
+
+    #[test]
+    fn unregistered_trap() {
+        let mut hardware = setup_default_test();
+        hardware.memory.set(0x3000, 0b1111_0000_0010_0110);
+        let mut microsequencer = Microsequencer::default();
+
+        let err = microsequencer.instruction(&mut hardware).unwrap_err();
+
+        assert!(matches!(err, TrapError::Unregistered { vector: 0x26, .. }));
+    }
+
+    #[test]
+    fn cross_check_os_trap_profile() {
+        let folded = cross_check_with(&[
+            0x3000,
+            0b0100_1000_0000_0001, // JSR SUB
+            0b1111_0000_0010_0101, // HALT
+            0b0001_1011_1110_0000, // SUB: ADD R5, R7, #0
+            0b1111_0000_0010_0001, // OUT
+            0b0001_1111_0110_0000, // ADD R7, R5, #0
+            0b1100_0001_1100_0000, // RET
+            0b0001_0000_0010_0001, // OS OUT: ADD R0, R0, #1
+            0b1100_0001_1100_0000, // RET
+        ], 7, |hardware| {
+            hardware.traps.unregister(0x21);
+            hardware.memory.set(0x0021, 0x3006);
+        });
+
+        // The RET of the OS routine leaves it, not SUB
+        assert_eq!(folded, "x3000 1\nx3000;x3002 4\nx3000;x3002;x3006 2\n");
+    }
+
+    #[test]
+    fn cross_check_loop() {
+        cross_check(&[
+            0x3000,
+            0b1110_0010_1111_1111,
+            0b0101_0110_1110_0000,
+            0b0101_0100_1010_0000,
+            0b0001_0100_1010_1100,
+            0b0000_0100_0000_0101,
+            0b0110_1000_0100_0000,
+            0b0001_0110_1100_0001,
+            0b0001_0010_0110_0001,
+            0b0001_0100_1011_1111,
+            0b0000_1111_1111_1010,
+        ], 60);
+    }
+
+    #[test]
+    fn cross_check_memory_and_subroutines() {
+        cross_check(&[
+            0x3000,
+            0b0010_0000_0000_1110, // LD R0, DATA
+            0b1001_0010_0011_1111, // NOT R1, R0
+            0b0011_0010_0000_1101, // ST R1, SLOT
+            0b1010_0100_0000_1101, // LDI R2, POINTER
+            0b1011_0000_0000_1100, // STI R0, POINTER
+            0b1110_0110_0000_1010, // LEA R3, SLOT
+            0b0111_0000_1100_0001, // STR R0, R3, #1
+            0b0110_1000_1100_0001, // LDR R4, R3, #1
+            0b0100_1000_0000_0011, // JSR SUB
+            0b1110_1010_0000_0010, // LEA R5, SUB
+            0b0100_0001_0100_0000, // JSRR R5
+            0b0000_1111_1111_1111, // BR to self
+            0b0001_1010_1011_1111, // SUB: ADD R5, R2, #-1
+            0b0101_1100_1000_0011, // AND R6, R2, R3
+            0b1100_0001_1100_0000, // RET
+            0x1234, // DATA
+            0x0000, // SLOT
+            0x3012, // POINTER
+            0x00AB,
+        ], 40);
+    }
+
+    #[test]
+    fn cross_check_jsrr_r7() {
+        cross_check(&[
+            0x3000,
+            0b1110_1110_0000_0010, // LEA R7, SUB
+            0b0100_0001_1100_0000, // JSRR R7
+            0b0000_1111_1111_1111, // BR to self
+            0b0001_0010_0110_0001, // SUB: ADD R1, R1, #1
+            0b1100_0001_1100_0000, // RET
+        ], 8);
+    }
+
+    #[test]
+    fn isa_profiles() {
+        let lea = [0x3000, 0b0101_0010_0110_0000, 0b1110_0010_0000_1111];
+        for profile in [IsaProfile::Legacy, IsaProfile::PattPatel3e] {
+            let mut hardware = setup_default_test();
+            hardware.isa = profile;
+            hardware.load(&lea).unwrap();
+            let mut microsequencer = Microsequencer::default();
+            microsequencer.instruction(&mut hardware).unwrap();
+            microsequencer.instruction(&mut hardware).unwrap();
+
+            assert_eq!(hardware.registers.get(1), 0x3011);
+            assert_eq!(hardware.flags.is_positive(), profile.lea_sets_cc(), "{:?}", profile);
+        }
+
+        let mut hardware = setup_default_test();
+        hardware.isa = IsaProfile::PattPatel2e;
+        hardware.memory.set(0x3000, 0b1101_0000_0000_0000);
+        let err = Microsequencer::default().instruction(&mut hardware).unwrap_err();
+        assert!(matches!(err, TrapError::Exception { exception: isa::Exception::IllegalOpcode, .. }));
+    }
+
+    #[test]
+    fn cross_check_output() {
+        cross_check(&[
+            0x3000,
+            0b0101_0000_0010_0000, // AND R0, R0, #0
+            0b0001_0000_0010_1111, // ADD R0, R0, #15
+            0b0001_0000_0010_1111, // ADD R0, R0, #15
+            0b0001_0000_0010_1111, // ADD R0, R0, #15
+            0b0001_0000_0010_1111, // ADD R0, R0, #15
+            0b0001_0000_0010_0101, // ADD R0, R0, #5
+            0b1111_0000_0010_0001, // OUT
+        ], 7);
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt::Write as _;
+
+use crate::console::Console;
+use crate::disassembler::disassemble;
+use crate::hardware::Hardware;
+use crate::loader;
+
+// Instructions shown before and after the PC in the dump.
+const WINDOW: u16 = 4;
+
+// What happens when the program is interrupted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnInterrupt {
+    Prompt,
+    Dump,
+}
+impl OnInterrupt {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "prompt" => Some(OnInterrupt::Prompt),
+            "dump" => Some(OnInterrupt::Dump),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Continue,
+    Step(usize),
+    Quit,
+}
+
+// The PC, registers, flags and the instructions around the PC.
+pub fn dump<C: Console>(hardware: &Hardware<C>) -> String {
+    let pc = hardware.program_counter.get();
+    let mut text = String::new();
+
+    writeln!(text, "PC  x{:04X}  {}", pc, hardware.describe(pc)).unwrap();
+    for row in [0, 4] {
+        let registers = (row..row + 4).map(|r| format!("R{} x{:04X}", r, hardware.registers.get(r))).collect::<Vec<_>>();
+        writeln!(text, "{}", registers.join("  ")).unwrap();
+    }
+    let flags = [(hardware.flags.is_negative(), 'n'), (hardware.flags.is_zero(), 'z'), (hardware.flags.is_positive(), 'p')]
+        .iter()
+        .map(|(set, flag)| if *set { flag.to_ascii_uppercase() } else { *flag })
+        .collect::<String>();
+    writeln!(text, "Flags {}", flags).unwrap();
+
+    for address in pc.saturating_sub(WINDOW)..=pc.saturating_add(WINDOW) {
+        let marker = if address == pc { '>' } else { ' ' };
+        let instruction = hardware.memory.get(address);
+        let label = hardware.symbols.name_at(address).unwrap_or_default();
+        writeln!(
+            text,
+            "{} x{:04X}  {:04X}  {:<12}  {}",
+            marker, address, instruction, label, disassemble(address, instruction, &hardware.symbols),
+        )
+        .unwrap();
+    }
+
+    text
+}
+
+// Reads commands from the console until one of them resumes or stops the program.
+pub fn prompt<C: Console>(hardware: &mut Hardware<C>) -> Action {
+    let _ = hardware.console.write_bytes(format!("\n{}", dump(hardware)).as_bytes());
+
+    loop {
+        let _ = hardware.console.write_bytes(b"paused> ");
+        let _ = hardware.console.flush();
+        let Some(line) = read_line(&mut hardware.console) else {
+            return Action::Quit;
+        };
+
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let reply = match words[..] {
+            ["c" | "continue"] => return Action::Continue,
+            ["s" | "step"] => return Action::Step(1),
+            ["s" | "step", count] => match count.parse() {
+                Ok(count) => return Action::Step(count),
+                Err(_) => format!("invalid count {}\n", count),
+            },
+            ["q" | "quit"] => return Action::Quit,
+            ["d" | "dump"] => dump(hardware),
+            ["m" | "memory", address] => memory(hardware, address, "8"),
+            ["m" | "memory", address, count] => memory(hardware, address, count),
+            [] => String::new(),
+            _ => "commands: c(ontinue), s(tep) [count], d(ump), m(emory) address [count], q(uit)\n".to_string(),
+        };
+        let _ = hardware.console.write_bytes(reply.as_bytes());
+    }
+}
+
+fn memory<C: Console>(hardware: &Hardware<C>, address: &str, count: &str) -> String {
+    let address = loader::parse_address(address).or_else(|| hardware.symbols.get(address));
+    let (Some(address), Ok(count)) = (address, count.parse::<u16>()) else {
+        return "expected an address or a label and a count\n".to_string();
+    };
+
+    let mut text = String::new();
+    for address in (0..count).map(|offset| address.wrapping_add(offset)) {
+        writeln!(text, "x{:04X}  {:04X}", address, hardware.memory.get(address)).unwrap();
+    }
+    text
+}
+
+// The line without its newline, `None` at the end of the input.
+fn read_line<C: Console>(console: &mut C) -> Option<String> {
+    let mut line = Vec::new();
+    loop {
+        match console.read_key() {
+            Ok(b'\n' | b'\r') => return Some(String::from_utf8_lossy(&line).to_string()),
+            Ok(key) => line.push(key),
+            _ if line.is_empty() => return None,
+            _ => return Some(String::from_utf8_lossy(&line).to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::setup_test_with_input;
+
+    #[test]
+    fn dump_state() {
+        let mut hardware = setup_test_with_input("");
+        hardware.load(&[0x3000, 0b0001_0010_0110_0001, 0b0000_1111_1111_1110]).unwrap();
+        hardware.symbols.insert("LOOP", 0x3001);
+        hardware.program_counter.set(0x3001);
+        hardware.registers.set(1, 0x0042);
+
+        let dump = dump(&hardware);
+
+        assert!(dump.starts_with(
+            "PC  x3001  LOOP (x3001)
+R0 x0000  R1 x0042  R2 x0000  R3 x0000
+R4 x0000  R5 x0000  R6 x0000  R7 x0000
+Flags nZp
+"
+        ));
+        assert!(dump.contains("  x3000  1261                ADD R1, R1, #1\n"));
+        assert!(dump.contains("> x3001  0FFE  LOOP          BRnzp x3000\n"));
+    }
+
+    #[test]
+    fn commands() {
+        let mut hardware = setup_test_with_input("m x3000 2\nhelp\ns 3\n");
+        hardware.memory.set(0x3000, 0x1234);
+
+        assert_eq!(prompt(&mut hardware), Action::Step(3));
+        let output = String::from_utf8(hardware.console.output.clone()).unwrap();
+        assert!(output.contains("paused> x3000  1234\nx3001  0000\npaused> commands:"));
+
+        assert_eq!(prompt(&mut hardware), Action::Quit);
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assembler::{self, AsmError};
+use crate::expression;
+
+const MAX_DEPTH: usize = 64;
+
+// Where a line of source was written, lines produced by a macro also remember where it was expanded.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    // Where the operation starts, counted from 1, 0 when the line has none.
+    pub column: usize,
+    pub expansion: Option<(String, Box<Location>)>,
+}
+impl Location {
+    // The line that was written in the source, past all the macros that expanded into this one.
+    pub fn origin(&self) -> &Location {
+        match &self.expansion {
+            Some((_, at)) => at.origin(),
+            None => self,
+        }
+    }
+}
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+// A source line after preprocessing, the operands have the constants replaced by their values.
+// Lines only the preprocessor needs, like comments and macro definitions, are kept without an operation.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Line {
+    pub location: Location,
+    pub label: Option<String>,
+    pub operation: Option<String>,
+    pub operands: Vec<String>,
+    pub text: String,
+    // Written by the assembler, like the expansion of a pseudo-instruction.
+    pub generated: bool,
+}
+
+struct Constant {
+    value: i32,
+    redefinable: bool,
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<(Location, String)>,
+}
+
+struct Condition {
+    active: bool,
+    taken: bool,
+    has_else: bool,
+    location: Location,
+}
+
+#[derive(Default)]
+pub struct Preprocessor {
+    constants: HashMap<String, Constant>,
+    macros: HashMap<String, Macro>,
+    includes: Vec<PathBuf>,
+    expansions: usize,
+    pub lines: Vec<Line>,
+}
+impl Preprocessor {
+    pub fn process_file(&mut self, path: &Path, included_at: Option<&Location>) -> Result<(), AsmError> {
+        let io_error = |err| match included_at {
+            Some(location) => AsmError::At(location.clone(), format!("could not include {}: {}", path.display(), err)),
+            None => AsmError::Io(path.display().to_string(), err),
+        };
+
+        let canonical = path.canonicalize().map_err(io_error)?;
+        if self.includes.contains(&canonical) {
+            let location = included_at.cloned().expect("the first file cannot be included twice");
+            return Err(AsmError::At(location, format!("{} includes itself", path.display())));
+        }
+
+        let text = fs::read_to_string(path).map_err(io_error)?;
+        self.includes.push(canonical);
+        self.process_source(&path.display().to_string(), &text)?;
+        self.includes.pop();
+
+        Ok(())
+    }
+
+    pub fn process_source(&mut self, file: &str, text: &str) -> Result<(), AsmError> {
+        let lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, text)| (Location { file: file.to_string(), line: index + 1, column: 0, expansion: None }, text.to_string()))
+            .collect();
+
+        self.process_lines(lines, 0)
+    }
+
+    fn process_lines(&mut self, lines: Vec<(Location, String)>, depth: usize) -> Result<(), AsmError> {
+        let mut conditions = Vec::<Condition>::new();
+        let mut definition = None::<(String, Macro, Location)>;
+
+        for (location, text) in lines {
+            let (label, operation, operands, column) = self.split(&text);
+            let location = Location { column, ..location };
+            let error = |message: String| AsmError::At(location.clone(), message);
+            let active = conditions.iter().all(|condition| condition.active);
+
+            if let Some((name, body, _)) = definition.as_mut() {
+                self.lines.push(Line { location: location.clone(), label: None, operation: None, operands: Vec::new(), text: text.clone(), generated: false });
+                match operation.as_deref() {
+                    Some(".ENDM") => {},
+                    Some(".MACRO") => return Err(error(format!("macro {} is defined inside macro {}", operands.trim(), name))),
+                    _ => {
+                        body.body.push((location.clone(), text));
+                        continue;
+                    },
+                }
+
+                let (name, body, _) = definition.take().unwrap();
+                self.macros.insert(name, body);
+                continue;
+            }
+
+            let is_macro = operation.as_ref().is_some_and(|operation| self.macros.contains_key(operation));
+            let directive = matches!(operation.as_deref(), Some(".IF" | ".ELSE" | ".ENDIF" | ".MACRO" | ".ENDM" | ".INCLUDE" | ".EQU" | ".SET"));
+            if is_macro || directive || !active {
+                // The label of a macro call stays, it is the address of the expansion.
+                let label = label.clone().filter(|_| is_macro && active);
+                self.lines.push(Line { location: location.clone(), label, operation: None, operands: Vec::new(), text: text.clone(), generated: false });
+            }
+
+            match operation.as_deref() {
+                Some(".IF") => {
+                    let taken = active && self.constant(&operands).map_err(error)? != 0;
+                    conditions.push(Condition { active: taken, taken: taken || !active, has_else: false, location: location.clone() });
+                },
+                Some(".ELSE") => {
+                    let condition = conditions.last_mut().ok_or_else(|| error(".ELSE without .IF".to_string()))?;
+                    if condition.has_else {
+                        return Err(error(format!(".IF at {} already has an .ELSE", condition.location)));
+                    }
+                    condition.active = !condition.taken;
+                    condition.has_else = true;
+                },
+                Some(".ENDIF") => {
+                    conditions.pop().ok_or_else(|| error(".ENDIF without .IF".to_string()))?;
+                },
+                _ if !active => {},
+                Some(".MACRO") => {
+                    let (name, parameters) = operands.trim().split_once(char::is_whitespace).unwrap_or((operands.trim(), ""));
+                    if name.is_empty() {
+                        return Err(error(".MACRO is missing a name".to_string()));
+                    }
+                    if assembler::is_mnemonic(name) {
+                        return Err(error(format!("macro {} has the name of an instruction", name)));
+                    }
+
+                    let parameters = split_operands(parameters).into_iter().map(|parameter| parameter.trim_start_matches('\\').to_string()).collect();
+                    definition = Some((name.to_ascii_uppercase(), Macro { parameters, body: Vec::new() }, location.clone()));
+                },
+                Some(".ENDM") => return Err(error(".ENDM without .MACRO".to_string())),
+                Some(".INCLUDE") => {
+                    let file = parse_string(operands.trim()).map_err(error)?;
+                    let path = Path::new(&location.file).parent().unwrap_or(Path::new("")).join(file);
+                    self.process_file(&path, Some(&location))?;
+                },
+                Some(directive @ (".EQU" | ".SET")) => {
+                    let name = label.ok_or_else(|| error(format!("{} needs a name, write `NAME {} value`", directive, directive)))?;
+                    let value = self.constant(&operands).map_err(error)?;
+                    let redefinable = directive == ".SET";
+
+                    match self.constants.get(&name) {
+                        Some(constant) if !(redefinable && constant.redefinable) => {
+                            return Err(error(format!("{} is already defined, only .SET constants can be changed", name)));
+                        },
+                        _ => self.constants.insert(name, Constant { value, redefinable }),
+                    };
+                },
+                Some(name) if self.macros.contains_key(name) => {
+                    let lines = self.expand(name, &operands, &location).map_err(error)?;
+                    if depth == MAX_DEPTH {
+                        return Err(error(format!("macro {} is expanded more than {} levels deep", name, MAX_DEPTH)));
+                    }
+                    self.process_lines(lines, depth + 1)?;
+                },
+                _ => {
+                    let replace = |name: &str| self.constants.get(name).map(|constant| constant.value.to_string());
+                    let operands = split_operands(&operands).iter().map(|operand| expression::substitute(operand, &replace)).collect();
+                    self.lines.push(Line { location, label, operation, operands, text, generated: false });
+                },
+            }
+        }
+
+        if let Some((name, _, location)) = definition {
+            return Err(AsmError::At(location, format!("macro {} is missing .ENDM", name)));
+        }
+        if let Some(condition) = conditions.pop() {
+            return Err(AsmError::At(condition.location, ".IF is missing .ENDIF".to_string()));
+        }
+
+        Ok(())
+    }
+
+    // Splits a line into its label, operation, the text of the operands and the column of the operation,
+    // dropping the comment.
+    fn split(&self, text: &str) -> (Option<String>, Option<String>, String, usize) {
+        let code = strip_comment(text).trim();
+        let is_operation = |word: &str| {
+            let word = word.to_ascii_uppercase();
+            word.starts_with('.') || assembler::is_mnemonic(&word) || self.macros.contains_key(&word)
+        };
+
+        // Every word is a slice of `text`, so its column is how far it starts into it
+        let column = |word: &str| word.as_ptr() as usize - text.as_ptr() as usize + 1;
+
+        let (first, rest) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+        if first.is_empty() {
+            return (None, None, String::new(), 0);
+        }
+        if is_operation(first) {
+            return (None, Some(first.to_ascii_uppercase()), rest.to_string(), column(first));
+        }
+
+        let label = first.trim_end_matches(':').to_string();
+        let rest = rest.trim();
+        let (operation, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        // Neither word is known, so the line is more likely a misspelled instruction than a label
+        if !operation.is_empty() && !is_operation(operation) && !first.ends_with(':') {
+            return (None, Some(first.to_ascii_uppercase()), rest.to_string(), column(first));
+        }
+        if operation.is_empty() {
+            return (Some(label), None, operands.to_string(), 0);
+        }
+        (Some(label), Some(operation.to_ascii_uppercase()), operands.to_string(), column(operation))
+    }
+
+    fn constant(&self, text: &str) -> Result<i32, String> {
+        expression::evaluate(text, &mut |name| self.constants.get(name).map(|constant| constant.value))
+    }
+
+    // Copies the macro body with the arguments in place of `\parameter` and unique names for `@label`.
+    fn expand(&mut self, name: &str, operands: &str, location: &Location) -> Result<Vec<(Location, String)>, String> {
+        let definition = &self.macros[name];
+        let arguments = split_operands(operands);
+        if arguments.len() != definition.parameters.len() {
+            return Err(format!("macro {} takes {} arguments, got {}", name, definition.parameters.len(), arguments.len()));
+        }
+
+        self.expansions += 1;
+        let expansion = self.expansions;
+        let lines = definition
+            .body
+            .iter()
+            .map(|(body_location, text)| {
+                let text = expression::substitute(text, &|word| match word.strip_prefix('@') {
+                    Some(local) if !local.is_empty() => Some(format!("{}@{}", local, expansion)),
+                    _ => None,
+                });
+                let text = definition.parameters.iter().zip(&arguments).fold(text, |text, (parameter, argument)| {
+                    text.replace(&format!("\\{}", parameter), argument)
+                });
+
+                let location = Location { expansion: Some((name.to_string(), Box::new(location.clone()))), ..body_location.clone() };
+                (location, text)
+            })
+            .collect();
+
+        Ok(lines)
+    }
+}
+
+pub fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q && !escaped => quote = None,
+            Some(_) => {},
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' => return &text[..index],
+            None => {},
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    text
+}
+
+// Operands are separated by commas, which may also appear in string literals.
+pub fn split_operands(text: &str) -> Vec<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Vec::new();
+    }
+
+    let mut operands = vec![String::new()];
+    let mut quote = None;
+    let mut escaped = false;
+    for c in text.chars() {
+        match quote {
+            Some(q) if c == q && !escaped => quote = None,
+            Some(_) => {},
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ',' => {
+                operands.push(String::new());
+                escaped = false;
+                continue;
+            },
+            None => {},
+        }
+        escaped = c == '\\' && !escaped;
+        operands.last_mut().unwrap().push(c);
+    }
+
+    operands.iter().map(|operand| operand.trim().to_string()).collect()
+}
+
+// Parses a double quoted string with the C escapes.
+pub fn parse_string(text: &str) -> Result<String, String> {
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .filter(|_| text.len() >= 2)
+        .ok_or(format!("expected a string in double quotes, got `{}`", text))?;
+
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        string.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('e') => '\x1B',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '"' | '\'')) => c,
+            Some(c) => return Err(format!("unknown escape \\{}", c)),
+            None => return Err("string ends with a backslash".to_string()),
+        });
+    }
+
+    Ok(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(text: &str) -> Result<Vec<Line>, AsmError> {
+        let mut preprocessor = Preprocessor::default();
+        preprocessor.process_source("main.asm", text)?;
+        Ok(preprocessor.lines)
+    }
+
+    fn operations(lines: &[Line]) -> Vec<String> {
+        lines.iter().filter(|line| line.operation.is_some()).map(|line| format!("{} {}", line.operation.as_deref().unwrap_or_default(), line.operands.join(", "))).collect()
+    }
+
+    #[test]
+    fn split_lines() {
+        let lines = process("LOOP: ADD R1, R1, #-1 ; count down\nBRp LOOP\nDONE\n.STRINGZ \"a; b, c\"").unwrap();
+
+        assert_eq!(lines[0].label.as_deref(), Some("LOOP"));
+        assert_eq!(lines[0].operation.as_deref(), Some("ADD"));
+        assert_eq!(lines[0].operands, vec!["R1", "R1", "#-1"]);
+        assert_eq!(lines[1].operation.as_deref(), Some("BRP"));
+        assert_eq!(lines[2].label.as_deref(), Some("DONE"));
+        assert_eq!(lines[3].operands, vec!["\"a; b, c\""]);
+        assert_eq!(lines[3].location.line, 4);
+        assert_eq!((lines[0].location.column, lines[1].location.column, lines[2].location.column), (7, 1, 0));
+    }
+
+    #[test]
+    fn constants() {
+        let lines = process("SIZE .EQU 4\nCOUNT .SET SIZE * 2\nADD R1, R1, COUNT\nCOUNT .SET COUNT + 1\n.FILL COUNT").unwrap();
+        assert_eq!(operations(&lines), vec!["ADD R1, R1, 8", ".FILL 9"]);
+
+        let err = process("SIZE .EQU 4\nSIZE .SET 5").unwrap_err();
+        assert_eq!(err.to_string(), "main.asm:2: SIZE is already defined, only .SET constants can be changed");
+
+        let err = process(".EQU 4").unwrap_err();
+        assert_eq!(err.to_string(), "main.asm:1: .EQU needs a name, write `NAME .EQU value`");
+    }
+
+    #[test]
+    fn conditions() {
+        let text = "DEBUG .EQU 1
+.IF DEBUG
+  .IF DEBUG > 1
+    TRAP x21
+  .ELSE
+    TRAP x22
+  .ENDIF
+.ELSE
+  TRAP x23
+.ENDIF
+.IF !DEBUG
+  .IF 1
+    TRAP x24
+  .ELSE
+    TRAP x25
+  .ENDIF
+.ENDIF";
+        assert_eq!(operations(&process(text).unwrap()), vec!["TRAP x22"]);
+
+        let err = process(".IF 1\nHALT").unwrap_err();
+        assert_eq!(err.to_string(), "main.asm:1: .IF is missing .ENDIF");
+
+        let err = process(".IF LOOP\n.ENDIF").unwrap_err();
+        assert_eq!(err.to_string(), "main.asm:1: unknown symbol LOOP");
+
+        let err = process(".ENDIF").unwrap_err();
+        assert_eq!(err.to_string(), "main.asm:1: .ENDIF without .IF");
+    }
+
+    #[test]
+    fn macros() {
+        let text = ".MACRO COUNTDOWN \\reg, \\from
+    AND \\reg, \\reg, #0
+    ADD \\reg, \\reg, \\from
+@loop ADD \\reg, \\reg, #-1
+    BRp @loop
+.ENDM
+START COUNTDOWN R1, #5
+    COUNTDOWN R2, 3
+";
+        let lines = process(text).unwrap();
+
+        assert_eq!(lines.len(), 16);
+        assert_eq!(lines[6].label.as_deref(), Some("START"));
+        assert_eq!(lines[6].text, "START COUNTDOWN R1, #5");
+        assert_eq!(operations(&lines[7..11]), vec!["AND R1, R1, #0", "ADD R1, R1, #5", "ADD R1, R1, #-1", "BRP loop@1"]);
+        assert_eq!(lines[9].label.as_deref(), Some("loop@1"));
+        assert_eq!(lines[14].label.as_deref(), Some("loop@2"));
+
+        let location = &lines[9].location;
+        assert_eq!(location.line, 4);
+        assert_eq!(location.expansion.as_ref().map(|(name, at)| (name.as_str(), at.line)), Some(("COUNTDOWN", 7)));
+    }
+
+    #[test]
+    fn macro_errors() {
+        let err = process(".MACRO TWICE \\a\n  ADD R1, R1, \\a\n  ADD R1, R1, \\a\n.ENDM\nTWICE #1, #2").unwrap_err();
+        assert_eq!(err.to_string(), "main.asm:5: macro TWICE takes 1 arguments, got 2");
+
+        let err = process(".MACRO LOOP\nLOOP\n.ENDM\nLOOP").unwrap_err();
+        assert!(err.to_string().starts_with("main.asm:2: macro LOOP is expanded more than 64 levels deep\n  in macro LOOP expanded at main.asm:2\n"));
+
+        let err = process(".MACRO OPEN\nHALT").unwrap_err();
+        assert_eq!(err.to_string(), "main.asm:1: macro OPEN is missing .ENDM");
+    }
+
+    #[test]
+    fn includes() {
+        let directory = crate::utils::test_directory("preprocess");
+        fs::write(directory.join("lib.asm"), "NEWLINE .EQU x0A\n.MACRO PRINTLN\n  LD R0, NEWLINE\n  OUT\n.ENDM\n").unwrap();
+        fs::write(directory.join("main.asm"), ".INCLUDE \"lib.asm\"\nPRINTLN\n").unwrap();
+        fs::write(directory.join("loop.asm"), ".INCLUDE \"loop.asm\"\n").unwrap();
+
+        let mut preprocessor = Preprocessor::default();
+        preprocessor.process_file(&directory.join("main.asm"), None).unwrap();
+        assert_eq!(operations(&preprocessor.lines), vec!["LD R0, 10", "OUT "]);
+        assert!(preprocessor.lines.iter().find(|line| line.operation.is_some()).unwrap().location.file.ends_with("lib.asm"));
+
+        let err = Preprocessor::default().process_file(&directory.join("loop.asm"), None).unwrap_err();
+        let loop_path = directory.join("loop.asm").display().to_string();
+        assert_eq!(err.to_string(), format!("{}:1: {} includes itself", loop_path, loop_path));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(parse_string("\"a\\n\\\"b\\\"\""), Ok("a\n\"b\"".to_string()));
+        assert!(parse_string("abc").is_err());
+        assert_eq!(split_operands("R1, \"a,b\", ','"), vec!["R1", "\"a,b\"", "','"]);
+        assert_eq!(strip_comment("LD R0, ';' ; load"), "LD R0, ';' ");
+    }
+}
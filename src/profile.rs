@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::disassembler::disassemble;
+use crate::memory::Memory;
+use crate::symbols::SymbolTable;
+
+const HOT_SPOTS: usize = 20;
+
+// A node of the call tree, one per distinct call stack.
+struct Node {
+    entry: u16,
+    parent: Option<usize>,
+    children: HashMap<u16, usize>,
+    count: u64,
+}
+
+pub struct Profiler {
+    counts: Vec<u64>,
+    nodes: Vec<Node>,
+    current: usize,
+    calls: HashMap<u16, u64>,
+    edges: BTreeMap<(u16, u16), u64>,
+}
+impl Profiler {
+    pub fn new(entry: u16) -> Self {
+        Profiler {
+            counts: vec![0; 1 << 16],
+            nodes: vec![Node { entry, parent: None, children: HashMap::new(), count: 0 }],
+            current: 0,
+            calls: HashMap::new(),
+            edges: BTreeMap::new(),
+        }
+    }
+
+    pub fn instruction(&mut self, address: u16) {
+        self.counts[address as usize] += 1;
+        self.nodes[self.current].count += 1;
+    }
+
+    // Takes back an instruction that runs again, like a trap waiting for input.
+    pub fn retract(&mut self, address: u16) {
+        self.counts[address as usize] = self.counts[address as usize].saturating_sub(1);
+        self.nodes[self.current].count = self.nodes[self.current].count.saturating_sub(1);
+    }
+
+    pub fn call(&mut self, target: u16) {
+        let caller = self.nodes[self.current].entry;
+        *self.calls.entry(target).or_default() += 1;
+        *self.edges.entry((caller, target)).or_default() += 1;
+
+        self.current = match self.nodes[self.current].children.get(&target) {
+            Some(&child) => child,
+            None => {
+                let child = self.nodes.len();
+                self.nodes.push(Node { entry: target, parent: Some(self.current), children: HashMap::new(), count: 0 });
+                self.nodes[self.current].children.insert(target, child);
+                child
+            },
+        };
+    }
+
+    pub fn ret(&mut self) {
+        if let Some(parent) = self.nodes[self.current].parent {
+            self.current = parent;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.nodes.iter().map(|node| node.count).sum()
+    }
+
+    // Instructions executed by each subroutine itself and together with everything it called.
+    fn subroutines(&self) -> BTreeMap<u16, (u64, u64)> {
+        let mut subtree = self.nodes.iter().map(|node| node.count).collect::<Vec<_>>();
+        for (index, node) in self.nodes.iter().enumerate().rev() {
+            if let Some(parent) = node.parent {
+                subtree[parent] += subtree[index];
+            }
+        }
+
+        let mut subroutines = BTreeMap::<u16, (u64, u64)>::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let totals = subroutines.entry(node.entry).or_default();
+            totals.0 += node.count;
+
+            // Recursive calls are already included in the outermost frame.
+            let mut ancestor = node.parent;
+            let mut recursive = false;
+            while let Some(parent) = ancestor {
+                recursive |= self.nodes[parent].entry == node.entry;
+                ancestor = self.nodes[parent].parent;
+            }
+            if !recursive {
+                totals.1 += subtree[index];
+            }
+        }
+
+        subroutines
+    }
+
+    pub fn report(&self, memory: &Memory, symbols: &SymbolTable) -> String {
+        let total = self.total().max(1);
+        let percent = |count: u64| count as f64 * 100.0 / total as f64;
+        let mut report = String::new();
+
+        writeln!(report, "Flat profile: {} instructions", self.total()).unwrap();
+        writeln!(report).unwrap();
+        writeln!(report, "{:>8} {:>12} {:>12} {:>8}  subroutine", "self %", "self", "inclusive", "calls").unwrap();
+
+        let mut subroutines = self.subroutines().into_iter().collect::<Vec<_>>();
+        subroutines.sort_by(|(a, (a_own, _)), (b, (b_own, _))| b_own.cmp(a_own).then(a.cmp(b)));
+        for (entry, (own, inclusive)) in subroutines {
+            let calls = self.calls.get(&entry).copied().unwrap_or_default();
+            writeln!(report, "{:>7.2}% {:>12} {:>12} {:>8}  {}", percent(own), own, inclusive, calls, symbols.describe(entry)).unwrap();
+        }
+
+        writeln!(report).unwrap();
+        writeln!(report, "Call graph:").unwrap();
+        writeln!(report).unwrap();
+        for ((caller, callee), calls) in &self.edges {
+            writeln!(report, "  {} -> {}  {} calls", symbols.describe(*caller), symbols.describe(*callee), calls).unwrap();
+        }
+
+        writeln!(report).unwrap();
+        writeln!(report, "Hot spots:").unwrap();
+        writeln!(report).unwrap();
+        writeln!(report, "{:>12} {:>8}  {:<20}  instruction", "count", "%", "address").unwrap();
+
+        let mut addresses = (0..=u16::MAX).filter(|&address| self.counts[address as usize] != 0).collect::<Vec<_>>();
+        addresses.sort_by(|a, b| self.counts[*b as usize].cmp(&self.counts[*a as usize]).then(a.cmp(b)));
+        for address in addresses.into_iter().take(HOT_SPOTS) {
+            let count = self.counts[address as usize];
+            let instruction = disassemble(address, memory.get(address), symbols);
+            writeln!(report, "{:>12} {:>7.2}%  {:<20}  {}", count, percent(count), symbols.describe(address), instruction).unwrap();
+        }
+
+        report
+    }
+
+    // Collapsed stacks, one `outer;inner count` line per call stack, as read by flamegraph.pl and inferno.
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let name = |entry: u16| match symbols.name_at(entry) {
+            Some(name) => name.to_string(),
+            None => format!("x{:04X}", entry),
+        };
+
+        let mut folded = String::new();
+        for node in &self.nodes {
+            if node.count == 0 {
+                continue;
+            }
+
+            let mut stack = vec![name(node.entry)];
+            let mut ancestor = node.parent;
+            while let Some(parent) = ancestor {
+                stack.push(name(self.nodes[parent].entry));
+                ancestor = self.nodes[parent].parent;
+            }
+            stack.reverse();
+
+            writeln!(folded, "{} {}", stack.join(";"), node.count).unwrap();
+        }
+
+        folded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> Profiler {
+        let mut profiler = Profiler::new(0x3000);
+
+        profiler.instruction(0x3000);
+        profiler.instruction(0x3001);
+        profiler.call(0x3010);
+        for _ in 0..3 {
+            profiler.instruction(0x3010);
+            profiler.instruction(0x3011);
+            profiler.call(0x3020);
+            profiler.instruction(0x3020);
+            profiler.ret();
+        }
+        profiler.instruction(0x3012);
+        profiler.ret();
+        profiler.instruction(0x3002);
+
+        profiler
+    }
+
+    #[test]
+    fn counts() {
+        let profiler = profile();
+        let subroutines = profiler.subroutines();
+
+        assert_eq!(profiler.total(), 13);
+        assert_eq!(profiler.counts[0x3010], 3);
+        assert_eq!(subroutines[&0x3000], (3, 13));
+        assert_eq!(subroutines[&0x3010], (7, 10));
+        assert_eq!(subroutines[&0x3020], (3, 3));
+        assert_eq!(profiler.calls[&0x3020], 3);
+        assert_eq!(profiler.edges[&(0x3010, 0x3020)], 3);
+    }
+
+    #[test]
+    fn recursion() {
+        let mut profiler = Profiler::new(0x3000);
+        profiler.call(0x3010);
+        profiler.instruction(0x3010);
+        profiler.call(0x3010);
+        profiler.instruction(0x3010);
+        profiler.ret();
+        profiler.ret();
+
+        assert_eq!(profiler.subroutines()[&0x3010], (2, 2));
+    }
+
+    #[test]
+    fn return_from_entry() {
+        let mut profiler = Profiler::new(0x3000);
+        profiler.ret();
+        profiler.instruction(0x3000);
+
+        assert_eq!(profiler.subroutines()[&0x3000], (1, 1));
+    }
+
+    #[test]
+    fn folded() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("PRINT", 0x3010);
+
+        assert_eq!(profile().folded(&symbols), "MAIN 3\nMAIN;PRINT 7\nMAIN;PRINT;x3020 3\n");
+    }
+
+    #[test]
+    fn report() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("MAIN", 0x3000);
+        let mut memory = Memory::default();
+        memory.set(0x3010, 0b0001_0010_0110_0001);
+
+        let report = profile().report(&memory, &symbols);
+
+        assert!(report.starts_with("Flat profile: 13 instructions"));
+        assert!(report.contains("MAIN (x3000) -> MAIN+16 (x3010)  1 calls"));
+        assert!(report.contains("MAIN+16 (x3010)       ADD R1, R1, #1"));
+    }
+}
@@ -21,10 +21,11 @@ impl ProgramCounter {
 
 const GENERAL_REGISTERS: usize = 8;
 
-pub struct Registers([u16; GENERAL_REGISTERS]);
+// The registers and which of them something wrote, for the sanitizer.
+pub struct Registers([u16; GENERAL_REGISTERS], [bool; GENERAL_REGISTERS]);
 impl Default for Registers {
     fn default() -> Self {
-        Registers([0; GENERAL_REGISTERS])
+        Registers([0; GENERAL_REGISTERS], [false; GENERAL_REGISTERS])
     }
 }
 impl Registers {
@@ -41,6 +42,16 @@ impl Registers {
         }
 
         self.0[register as usize] = value;
+        self.1[register as usize] = true;
+    }
+
+    pub fn is_written(&self, register: u16) -> bool {
+        self.1[register as usize]
+    }
+
+    // Sets the registers to what `value` gives without counting them as written.
+    pub fn fill(&mut self, mut value: impl FnMut() -> u16) {
+        self.0 = [(); GENERAL_REGISTERS].map(|_| value());
     }
 }
 
@@ -75,7 +86,7 @@ impl Flags {
         match value as i16 {
             ..=-1 => self.set_negative(),
             0 => self.set_zero(),
-            0.. => self.set_positive(),
+            1.. => self.set_positive(),
         };
     }
 }
@@ -93,6 +104,7 @@ mod tests {
 
         assert_eq!(registers.get(0b0000_0000_0000_0101), 0b1000_1000_1000_1000);
         assert_eq!(registers.get(0b0000_0000_0000_0000), 0b0000_0000_1111_0000);
+        assert!(registers.is_written(5) && !registers.is_written(1));
     }
 
     #[test]
@@ -1,53 +1,468 @@
-use std::fs::File;
-use std::io::{self, Read, Write};
+use std::fs::{self, File};
+use std::io::{LineWriter, Write};
+use std::path::Path;
 
+use crate::args::Args;
+use crate::calls::CallChecker;
+use crate::console::Console;
+use crate::coverage::Coverage;
+use crate::debuginfo::DebugInfo;
 use crate::hardware::Hardware;
 use crate::instructions;
+use crate::isa::{self, Exception};
+use crate::loader::{self, Image, LoadError};
+use crate::microarch::{self, Microsequencer};
+use crate::monitor::{self, Action, OnInterrupt};
+use crate::profile::Profiler;
+use crate::symbols::SymbolTable;
+use crate::sandbox::Sandbox;
+use crate::sanitizer::{self, Sanitizer};
+use crate::session::Session;
+use crate::terminal::{self, Terminal};
+use crate::traps::{self, TrapError};
 
-pub fn run<R: Read, W: Write>(file_path: &str, hardware: &mut Hardware<R, W>) {
-    let program = read_binary_file(file_path).unwrap();
-    hardware.load(&program);
+pub fn load<C: Console>(args: &Args, hardware: &mut Hardware<C>) -> Result<(), LoadError> {
+    // Filled before loading, so the images are what is written
+    if let Some(fill) = args.fill {
+        sanitizer::fill(hardware, fill);
+    }
+    if let Some(on_violation) = args.sanitize {
+        hardware.sanitizer = Some(Sanitizer::new(on_violation));
+    }
+    if let Some(on_violation) = args.check_calls {
+        hardware.calls = Some(CallChecker::new(on_violation, args.callee_saved.clone(), args.stack_limit));
+    }
+
+    // The OS image goes first, so it is in place before any program is loaded on top of it.
+    let images = args.os.iter().chain(&args.files).map(|path| Image::read(path)).collect::<Result<Vec<_>, _>>()?;
+    let programs = &images[args.os.iter().count()..];
+
+    loader::check_overlaps(&images)?;
+    for image in &images {
+        hardware.memory.load(image.origin, &image.words).map_err(|err| LoadError::InFile(image.path.clone(), Box::new(err)))?;
+        hardware.symbols.extend(SymbolTable::read_for(&image.path)?);
+        hardware.debug_info.extend(DebugInfo::read_for(&image.path)?);
+    }
+
+    let entry = match &args.entry {
+        Some(entry) => loader::parse_address(entry)
+            .or_else(|| hardware.symbols.get(entry))
+            .ok_or_else(|| LoadError::UnknownEntry(entry.clone()))?,
+        None => programs[0].origin,
+    };
+    hardware.program_counter.set(entry);
+
+    // The standard and file traps are registered first, so a trap config can move them
+    for (vector, name) in args.trap_mode.handlers() {
+        hardware.traps.register(*vector, name, traps::handler(name).unwrap());
+    }
+    // The routines of an OS image run in place of the host handlers, the vectors it leaves empty keep theirs
+    if args.os.is_some() {
+        for vector in traps::STANDARD_TRAPS {
+            if hardware.memory.get(vector as u16) != 0 {
+                hardware.traps.unregister(vector);
+            }
+        }
+    }
+    if let Some(path) = &args.sandbox {
+        let sandbox = Sandbox::new(Path::new(path)).map_err(|err| LoadError::InFile(path.clone(), Box::new(err.into())))?;
+        hardware.sandbox = Some(sandbox);
+        for (vector, name) in traps::FILE_TRAPS {
+            hardware.traps.register(vector, name, traps::handler(name).unwrap());
+        }
+    }
+    if let Some(path) = &args.traps {
+        let config = fs::read_to_string(path).map_err(|err| LoadError::InFile(path.clone(), Box::new(err.into())))?;
+        hardware.traps.configure(&config).map_err(|(line, message)| LoadError::InFile(path.clone(), Box::new(LoadError::Syntax { line, message })))?;
+    }
+
+    hardware.on_eof = args.on_eof;
+    hardware.isa = args.isa;
+    // An entry in the system space is an OS, it starts in supervisor mode
+    hardware.set_supervisor(!isa::allowed(args.isa, false, entry));
+    let in_file = |path: &String, err: LoadError| LoadError::InFile(path.clone(), Box::new(err));
+    if let Some(path) = &args.record {
+        hardware.session = Some(Session::record(path).map_err(|err| in_file(path, err.into()))?);
+    }
+    if let Some(path) = &args.replay {
+        let session = Session::replay(path).map_err(|err| in_file(path, err.into()))?;
+        hardware.session = Some(session.map_err(|(line, message)| in_file(path, LoadError::Syntax { line, message }))?);
+    }
+
+    if let Some(path) = &args.microtrace {
+        hardware.microtrace = Some(LineWriter::new(File::create(path).map_err(|err| in_file(path, err.into()))?));
+    }
+
+    if args.profile || args.flamegraph.is_some() {
+        hardware.profiler = Some(Profiler::new(entry));
+    }
+    if args.coverage.is_some() {
+        let mut coverage = Coverage::default();
+        for program in programs {
+            coverage.cover(program.origin, program.words.len());
+        }
+        hardware.coverage = Some(coverage);
+    }
 
-    main_loop(hardware);
+    Ok(())
 }
 
-pub fn main_loop<R: Read, W: Write>(hardware: &mut Hardware<R, W>) {
-    while let Some(instruction) = hardware.next() {
-        if instruction != 0b0000_0000_0000_0000 {
-            instructions::process(instruction, hardware);
+// Runs the program until it halts, pausing when it is interrupted.
+pub fn run<C: Console>(args: &Args, hardware: &mut Hardware<C>, terminal: &Terminal) -> Result<(), TrapError> {
+    let mut trace = hardware.microtrace.take();
+    let mut microsequencer = Microsequencer::default();
+    let mut count = usize::MAX;
+
+    loop {
+        let result = if args.microarch {
+            micro_loop(hardware, &mut microsequencer, trace.as_mut(), count)
+        } else {
+            interpret(hardware, count)
+        };
+        match result {
+            // Nothing else than the interrupt can end the wait, the input has ended and blocks
+            Err(TrapError::Blocked) if !terminal::is_interrupted() => {
+                while !terminal::take_interrupt() {
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+            },
+            // Blocked otherwise happens when an interrupt stops the wait for a key
+            Err(TrapError::Interrupted | TrapError::Blocked) => {
+                terminal::take_interrupt();
+            },
+            Ok(()) if count == usize::MAX || hardware.halted => return Ok(()),
+            Ok(()) => {},
+            Err(err) => return Err(err),
         }
+
+        let action = match args.on_interrupt {
+            OnInterrupt::Prompt => {
+                terminal.suspend();
+                let action = monitor::prompt(hardware);
+                terminal.resume();
+                action
+            },
+            OnInterrupt::Dump => {
+                let _ = hardware.console.write_bytes(format!("\n{}", monitor::dump(hardware)).as_bytes());
+                Action::Quit
+            },
+        };
+        count = match action {
+            Action::Continue => usize::MAX,
+            Action::Step(count) => count,
+            Action::Quit => return Err(TrapError::Interrupted),
+        };
     }
 }
 
-fn read_binary_file(file_path: &str) -> io::Result<Vec<u16>> {
-    let mut file = File::open(file_path)?;
+// Why `resume` gave back control.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stop {
+    Halted,
+    // The program waits for a key that is not queued, push some and resume
+    NeedsInput,
+    // It ran as many instructions as it was given
+    Limit,
+    // The input ended and the program halted, see `OnEof`
+    EndOfInput,
+}
 
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
+// Runs at most `count` instructions like `interpret`, but stops when the program waits for input instead of blocking,
+// so a host can drive it from an event loop. A trap waiting for a key runs again from the start when resumed,
+// polling the keyboard finishes the instruction with no key ready.
+pub fn resume<C: Console>(hardware: &mut Hardware<C>, count: usize) -> Result<Stop, TrapError> {
+    hardware.waiting = false;
+    let halted = |hardware: &Hardware<C>| if hardware.input_ended { Stop::EndOfInput } else { Stop::Halted };
+    for _ in 0..count {
+        if hardware.halted {
+            return Ok(halted(hardware));
+        }
+        match interpret(hardware, 1) {
+            Err(TrapError::Blocked) => return Ok(Stop::NeedsInput),
+            result => result?,
+        }
+        if std::mem::take(&mut hardware.waiting) {
+            return Ok(Stop::NeedsInput);
+        }
+    }
 
-    let arr = buffer.chunks(2).map(|chunk| {
-        let mut bytes = [0; 2];
-        bytes.copy_from_slice(chunk);
-        u16::from_be_bytes(bytes)
-    }).collect::<Vec<_>>();
+    Ok(if hardware.halted { halted(hardware) } else { Stop::Limit })
+}
 
-    Ok(arr)
+#[cfg(test)]
+pub fn main_loop<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    interpret(hardware, usize::MAX)
+}
+
+// Runs at most `count` instructions, stopping early when the program halts or is interrupted.
+pub fn interpret<C: Console>(hardware: &mut Hardware<C>, count: usize) -> Result<(), TrapError> {
+    for _ in 0..count {
+        if terminal::take_interrupt() {
+            return Err(TrapError::Interrupted);
+        }
+        let Some(instruction) = hardware.next() else {
+            break;
+        };
+        let address = hardware.program_counter.get().wrapping_sub(1);
+        if !isa::allowed(hardware.isa, hardware.supervisor, address) {
+            isa::raise(TrapError::exception(Exception::AccessControl, instruction, hardware), hardware)?;
+        } else if instruction != 0b0000_0000_0000_0000 {
+            instructions::process(instruction, hardware)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn micro_loop<C: Console, T: Write>(
+    hardware: &mut Hardware<C>,
+    microsequencer: &mut Microsequencer,
+    mut trace: Option<&mut T>,
+    count: usize,
+) -> Result<(), TrapError> {
+    let mut instructions = 0;
+    while !microsequencer.is_finished(hardware) {
+        // Interrupts are only taken between instructions
+        if microsequencer.state == microarch::FETCH {
+            if instructions == count {
+                break;
+            }
+            instructions += 1;
+            if terminal::take_interrupt() {
+                return Err(TrapError::Interrupted);
+            }
+        }
+
+        let microstate = microsequencer.cycle(hardware)?;
+        if let Some(trace) = trace.as_mut() {
+            // Fetches start a new instruction, so they show where it is in the source
+            let written = if microstate.state == microarch::FETCH && hardware.debug_info.get(microstate.pc).is_some() {
+                writeln!(trace, "{}  ; {}", microstate, hardware.describe(microstate.pc))
+            } else {
+                writeln!(trace, "{}", microstate)
+            };
+            // A trace that cannot be written stops the run rather than going on without it
+            written.map_err(TrapError::Io)?;
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use super::*;
+    use crate::utils::setup_default_test;
+
+    fn write_object(directory: &Path, name: &str, program: &[u16]) -> String {
+        let path = directory.join(name);
+        fs::write(&path, program.iter().flat_map(|word| word.to_be_bytes()).collect::<Vec<_>>()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    // A trace file on a full disk
+    struct Full;
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "no space left"))
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_write_error() {
+        let mut hardware = setup_default_test();
+        hardware.load(&[0x3000, 0b0001_0010_0110_0001]).unwrap();
+
+        let err = micro_loop(&mut hardware, &mut Microsequencer::default(), Some(&mut Full), 1).unwrap_err();
+        assert!(matches!(err, TrapError::Io(_)));
+    }
 
     #[test]
     fn load_program_from_file() {
         let mut hardware = Hardware::default();
 
-        let program = read_binary_file("test.obj").unwrap();
-        hardware.load(&program);
+        let program = loader::read_file("test.obj").unwrap();
+        hardware.load(&program).unwrap();
 
         assert_eq!(hardware.memory.get(0x3000), 0b1110_0010_1111_1111);
         assert_eq!(hardware.memory.get(0x3005), 0b0110_1000_0100_0000);
         assert_eq!(hardware.memory.get(0x3009), 0b0000_1111_1111_1010);
         assert_eq!(hardware.memory.get(0x300A), 0b0000_0000_0000_0000);
     }
+
+    #[test]
+    fn load_multiple_files() {
+        let directory = crate::utils::test_directory("run-multiple");
+        let library = write_object(&directory, "library.obj", &[0x4000, 0b1100_0001_1100_0000]);
+        let os = write_object(&directory, "os.obj", &[0x0020, 0x0400]);
+        fs::write(std::path::Path::new(&library).with_extension("sym"), "//	PRINT 4000\n").unwrap();
+        fs::write(std::path::Path::new(&library).with_extension("dbg"), "x4000\tlibrary.asm\t12\t9\tPRINT\n").unwrap();
+
+        let mut hardware = setup_default_test();
+        load(&args(&["test.obj", &library, "--os", &os]), &mut hardware).unwrap();
+
+        assert_eq!(hardware.program_counter.get(), 0x3000);
+        assert_eq!(hardware.memory.get(0x3000), 0b1110_0010_1111_1111);
+        assert_eq!(hardware.memory.get(0x4000), 0b1100_0001_1100_0000);
+        assert_eq!(hardware.memory.get(0x0020), 0x0400);
+        assert_eq!(hardware.describe(0x4000), "PRINT (library.asm:12)");
+
+        let mut hardware = setup_default_test();
+        load(&args(&["test.obj", &library, "--entry", "PRINT"]), &mut hardware).unwrap();
+        assert_eq!(hardware.program_counter.get(), 0x4000);
+
+        let mut hardware = setup_default_test();
+        load(&args(&["test.obj", &library, "--entry", "x3005"]), &mut hardware).unwrap();
+        assert_eq!(hardware.program_counter.get(), 0x3005);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn os_trap_routines() {
+        let directory = crate::utils::test_directory("run-os-traps");
+        let os = write_object(&directory, "os.obj", &[0x0021, 0x0400]);
+        // OUT: ADD R0, R0, #1, RET
+        let routine = write_object(&directory, "out.obj", &[0x0400, 0b0001_0000_0010_0001, 0b1100_0001_1100_0000]);
+        // AND R0, R0, #0, OUT, HALT
+        let program = write_object(&directory, "main.obj", &[0x3000, 0b0101_0000_0010_0000, 0b1111_0000_0010_0001, 0b1111_0000_0010_0101]);
+
+        let mut hardware = setup_default_test();
+        load(&args(&[&program, &routine, "--os", &os]), &mut hardware).unwrap();
+        assert!(hardware.traps.get(0x21).is_none() && hardware.traps.get(0x25).is_some());
+        main_loop(&mut hardware).unwrap();
+
+        assert!(hardware.halted);
+        assert_eq!(hardware.registers.get(0), 1);
+        assert!(hardware.console.output.is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn load_invalid_files() {
+        let mut hardware = setup_default_test();
+        let err = load(&args(&["test.obj", "hello-world.obj"]), &mut hardware).unwrap_err();
+        assert_eq!(err.to_string(), "test.obj and hello-world.obj both load to x3000-x3009");
+
+        let mut hardware = setup_default_test();
+        let err = load(&args(&["test.obj", "--entry", "MISSING"]), &mut hardware).unwrap_err();
+        assert!(matches!(err, LoadError::UnknownEntry(_)));
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let mut preprocessor = crate::preprocess::Preprocessor::default();
+        preprocessor.process_source("keys.asm", ".ORIG x3000
+POLL    LDI R1, KBSR
+        BRzp POLL
+        LDI R0, KBDR
+        OUT
+        GETC
+        OUT
+        HALT
+KBSR    .FILL xFE00
+KBDR    .FILL xFE02
+.END").unwrap();
+        let program = crate::assembler::assemble(&preprocessor.lines).unwrap().image();
+        let directory = crate::utils::test_directory("run-record");
+        let session = directory.join("keys.lc3rec").to_str().unwrap().to_string();
+
+        let mut hardware = crate::utils::setup_test_with_input("\0\0ab");
+        hardware.load(&program).unwrap();
+        hardware.session = Some(Session::record(&session).unwrap());
+        main_loop(&mut hardware).unwrap();
+        drop(hardware.session.take());
+        assert_eq!(fs::read_to_string(&session).unwrap(), "; instruction byte\n1 x00\n3 x00\n5 x61\n9 x62\n");
+
+        let mut replayed = setup_default_test();
+        replayed.load(&program).unwrap();
+        replayed.session = Some(Session::replay(&session).unwrap().ok().unwrap());
+        main_loop(&mut replayed).unwrap();
+        assert_eq!(replayed.console.output, hardware.console.output);
+        assert_eq!(replayed.instructions, hardware.instructions);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn diverged_replay() {
+        let mut preprocessor = crate::preprocess::Preprocessor::default();
+        preprocessor.process_source("poll.asm", ".ORIG x3000
+POLL    LDI R1, KBSR
+        BRzp POLL
+        HALT
+KBSR    .FILL xFE00
+.END").unwrap();
+        let program = crate::assembler::assemble(&preprocessor.lines).unwrap().image();
+        let directory = crate::utils::test_directory("run-diverged");
+        let session = directory.join("keys.lc3rec");
+        // Recorded from a program that read its first key later
+        fs::write(&session, "; instruction byte\n7 x61\n").unwrap();
+
+        let mut hardware = setup_default_test();
+        hardware.load(&program).unwrap();
+        hardware.session = Some(Session::replay(session.to_str().unwrap()).unwrap().ok().unwrap());
+        let err = main_loop(&mut hardware).unwrap_err();
+        assert!(matches!(err, TrapError::Io(_)));
+        assert_eq!(
+            err.to_string(),
+            "could not use the console: replay diverged, the program reads input at instruction 1 but the session has it at 7",
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn resume_after_input() {
+        let mut preprocessor = crate::preprocess::Preprocessor::default();
+        preprocessor.process_source("echo.asm", ".ORIG x3000
+POLL    LDI R1, KBSR
+        BRzp POLL
+        LDI R0, KBDR
+        OUT
+        GETC
+        OUT
+        LEA R0, LINE
+        AND R1, R1, #0
+        ADD R1, R1, #8
+        TRAP x27
+        HALT
+KBSR    .FILL xFE00
+KBDR    .FILL xFE02
+LINE    .BLKW 9
+.END").unwrap();
+        let program = crate::assembler::assemble(&preprocessor.lines).unwrap().image();
+        let mut hardware = Hardware::with_console(crate::console::Script::default());
+        hardware.load(&program).unwrap();
+        hardware.traps.configure("x27 read-line").unwrap();
+        hardware.coverage = Some(Coverage::default());
+
+        // Polling stops after the load of the status, so the loop is not spun while waiting
+        assert_eq!(resume(&mut hardware, 100).unwrap(), Stop::NeedsInput);
+        assert_eq!((hardware.program_counter.get(), hardware.instructions), (0x3001, 1));
+
+        hardware.console.push(b"a");
+        assert_eq!(resume(&mut hardware, 100).unwrap(), Stop::NeedsInput);
+        assert_eq!((hardware.program_counter.get(), hardware.instructions), (0x3004, 6));
+        assert_eq!(hardware.console.output, b"a");
+
+        hardware.console.push(b"bhi");
+        assert_eq!(resume(&mut hardware, 100).unwrap(), Stop::NeedsInput);
+        assert_eq!(hardware.program_counter.get(), 0x3009);
+        hardware.console.push(b" there\n");
+        assert_eq!(resume(&mut hardware, 1).unwrap(), Stop::Limit);
+        assert_eq!(resume(&mut hardware, 100).unwrap(), Stop::Halted);
+
+        // The waiting traps ran once each, their output and the line are not repeated
+        assert_eq!(hardware.console.output, b"abhi there\n");
+        assert_eq!((0x300D..0x3016).map(|address| hardware.memory.get(address)).collect::<Vec<_>>(), b"hi there\0".map(u16::from));
+        assert_eq!(hardware.instructions, 13);
+        assert_eq!(hardware.coverage.as_ref().unwrap().hits(0x3009), 1);
+    }
 }
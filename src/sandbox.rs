@@ -0,0 +1,182 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+const MAX_FILES: usize = 16;
+
+// Codes the file traps return in R0, all negative so `BRn` catches them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileError {
+    NotFound = -1,
+    Denied = -2,
+    BadHandle = -3,
+    TooManyFiles = -4,
+    Invalid = -5,
+    Io = -6,
+}
+impl FileError {
+    pub fn code(self) -> u16 {
+        self as i16 as u16
+    }
+}
+impl From<io::Error> for FileError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => FileError::NotFound,
+            io::ErrorKind::PermissionDenied => FileError::Denied,
+            io::ErrorKind::InvalidInput => FileError::Invalid,
+            _ => FileError::Io,
+        }
+    }
+}
+
+// The directory programs can reach with the file traps, and the files they have open in it.
+pub struct Sandbox {
+    root: PathBuf,
+    files: Vec<Option<File>>,
+}
+impl Sandbox {
+    pub fn new(root: &Path) -> io::Result<Self> {
+        Ok(Sandbox { root: fs::canonicalize(root)?, files: Vec::new() })
+    }
+
+    // Only relative paths going down from the root are accepted, a symlink cannot lead out of it either.
+    fn resolve(&self, path: &str) -> Result<PathBuf, FileError> {
+        let relative = Path::new(path);
+        if path.is_empty() || relative.file_name().is_none() {
+            return Err(FileError::Invalid);
+        }
+        if !relative.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return Err(FileError::Denied);
+        }
+
+        let path = self.root.join(relative);
+        let parent = fs::canonicalize(path.parent().unwrap())?;
+        let path = match fs::canonicalize(&path) {
+            Ok(path) => path,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => parent.join(path.file_name().unwrap()),
+            Err(err) => return Err(err.into()),
+        };
+        if !parent.starts_with(&self.root) || !path.starts_with(&self.root) {
+            return Err(FileError::Denied);
+        }
+
+        Ok(path)
+    }
+
+    // Modes: 0 reads, 1 writes a new file, 2 appends and 3 reads and writes, creating the file.
+    pub fn open(&mut self, path: &str, mode: u16) -> Result<u16, FileError> {
+        let mut options = OpenOptions::new();
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true).create(true).truncate(true),
+            2 => options.append(true).create(true),
+            3 => options.read(true).write(true).create(true).truncate(false),
+            _ => return Err(FileError::Invalid),
+        };
+
+        let file = options.open(self.resolve(path)?)?;
+        let handle = match self.files.iter().position(Option::is_none) {
+            Some(handle) => handle,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            },
+            None => return Err(FileError::TooManyFiles),
+        };
+        self.files[handle] = Some(file);
+
+        Ok(handle as u16)
+    }
+
+    fn file(&mut self, handle: u16) -> Result<&mut File, FileError> {
+        self.files.get_mut(handle as usize).and_then(Option::as_mut).ok_or(FileError::BadHandle)
+    }
+
+    pub fn read(&mut self, handle: u16, count: usize) -> Result<Vec<u8>, FileError> {
+        let mut buf = Vec::new();
+        self.file(handle)?.take(count as u64).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn write(&mut self, handle: u16, bytes: &[u8]) -> Result<(), FileError> {
+        Ok(self.file(handle)?.write_all(bytes)?)
+    }
+
+    pub fn close(&mut self, handle: u16) -> Result<(), FileError> {
+        self.file(handle)?;
+        self.files[handle as usize] = None;
+        Ok(())
+    }
+
+    // Whence: 0 from the start, 1 from the current position and 2 from the end.
+    pub fn seek(&mut self, handle: u16, offset: i16, whence: u16) -> Result<u64, FileError> {
+        let from = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(FileError::Invalid),
+        };
+
+        Ok(self.file(handle)?.seek(from)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox(name: &str) -> Sandbox {
+        let root = crate::utils::test_directory(&format!("sandbox-{}", name));
+        fs::create_dir_all(root.join("saves")).unwrap();
+        Sandbox::new(&root).unwrap()
+    }
+
+    #[test]
+    fn files() {
+        let mut sandbox = sandbox("files");
+
+        let handle = sandbox.open("saves/game.dat", 1).unwrap();
+        sandbox.write(handle, b"hello world").unwrap();
+        sandbox.close(handle).unwrap();
+        assert_eq!(sandbox.close(handle), Err(FileError::BadHandle));
+
+        let handle = sandbox.open("./saves/game.dat", 0).unwrap();
+        assert_eq!(sandbox.seek(handle, 6, 0), Ok(6));
+        assert_eq!(sandbox.read(handle, 3).unwrap(), b"wor");
+        assert_eq!(sandbox.read(handle, 10).unwrap(), b"ld");
+        assert_eq!(sandbox.read(handle, 10).unwrap(), b"");
+        assert_eq!(sandbox.seek(handle, -5, 2), Ok(6));
+        assert_eq!(sandbox.write(handle, b"!"), Err(FileError::Io));
+        fs::remove_dir_all(&sandbox.root).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let mut sandbox = sandbox("errors");
+
+        assert_eq!(sandbox.open("missing.txt", 0), Err(FileError::NotFound));
+        assert_eq!(sandbox.open("../outside.txt", 1), Err(FileError::Denied));
+        assert_eq!(sandbox.open("/etc/passwd", 0), Err(FileError::Denied));
+        assert_eq!(sandbox.open("saves", 4), Err(FileError::Invalid));
+        assert_eq!(sandbox.open("", 0), Err(FileError::Invalid));
+        assert_eq!(sandbox.read(3, 1), Err(FileError::BadHandle));
+        assert_eq!(FileError::NotFound.code(), 0xFFFF);
+
+        for _ in 0..MAX_FILES {
+            sandbox.open("log.txt", 2).unwrap();
+        }
+        assert_eq!(sandbox.open("log.txt", 2), Err(FileError::TooManyFiles));
+        fs::remove_dir_all(&sandbox.root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_out() {
+        let sandbox = sandbox("symlink");
+        std::os::unix::fs::symlink(std::env::temp_dir(), sandbox.root.join("out")).unwrap();
+
+        assert_eq!(sandbox.resolve("out/file.txt"), Err(FileError::Denied));
+        fs::remove_dir_all(&sandbox.root).unwrap();
+    }
+}
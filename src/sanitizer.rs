@@ -0,0 +1,219 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeSet;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::console::Console;
+use crate::hardware::Hardware;
+use crate::isa;
+use crate::traps::TrapError;
+use crate::utils::{offset6, pcoffset9, register_at};
+
+// What happens when a check finds something wrong, like `--sanitize` finding a read of a register or a word nothing wrote.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnViolation {
+    // Every violation is reported once after the run
+    Warn,
+    Stop,
+}
+impl OnViolation {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "warn" => Some(OnViolation::Warn),
+            "stop" => Some(OnViolation::Stop),
+            _ => None,
+        }
+    }
+}
+
+// What the registers and the user space hold at startup instead of zeros, picked with `--fill`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fill {
+    Value(u16),
+    Random,
+}
+impl Fill {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "random" => Some(Fill::Random),
+            _ => crate::loader::parse_address(text).map(Fill::Value),
+        }
+    }
+}
+
+// Fills the registers and x3000-xFDFF, the vector tables and the device registers stay empty so traps and exceptions
+// still find no routine. Nothing filled counts as written.
+pub fn fill<C: Console>(hardware: &mut Hardware<C>, fill: Fill) {
+    let mut state = RandomState::new().build_hasher().finish() | 1;
+    let mut value = || match fill {
+        Fill::Value(value) => value,
+        // xorshift, any word is as likely
+        Fill::Random => {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u16
+        },
+    };
+
+    hardware.registers.fill(&mut value);
+    hardware.memory.fill(isa::SUPERVISOR_STACK..0xFE00, &mut value);
+}
+
+// Tracks nothing itself, `Memory` and `Registers` know what was written. It keeps what it has reported.
+pub struct Sanitizer {
+    pub on_violation: OnViolation,
+    pub warnings: Vec<String>,
+    reported: BTreeSet<(u16, String)>,
+}
+impl Sanitizer {
+    pub fn new(on_violation: OnViolation) -> Self {
+        Sanitizer { on_violation, warnings: Vec::new(), reported: BTreeSet::new() }
+    }
+}
+
+// Reports the registers and words the instruction at PC - 1 reads before anything wrote them.
+pub fn check<C: Console>(instruction: u16, hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let Some(on_violation) = hardware.sanitizer.as_ref().map(|sanitizer| sanitizer.on_violation) else {
+        return Ok(());
+    };
+
+    let address = hardware.program_counter.get().wrapping_sub(1);
+    for read in undefined(instruction, hardware) {
+        let location = hardware.describe(address);
+        if on_violation == OnViolation::Stop {
+            return Err(TrapError::Undefined { read, location });
+        }
+
+        let sanitizer = hardware.sanitizer.as_mut().unwrap();
+        if sanitizer.reported.insert((address, read.clone())) {
+            sanitizer.warnings.push(format!("{} is read before anything wrote it, at {}", read, location));
+        }
+    }
+
+    Ok(())
+}
+
+fn undefined<C: Console>(instruction: u16, hardware: &Hardware<C>) -> Vec<String> {
+    let pc = hardware.program_counter.get();
+    let mut registers = Vec::new();
+    let mut words = vec![pc.wrapping_sub(1)];
+
+    match instruction >> 12 {
+        // AND with #0 clears the register whatever it held
+        0x5 if instruction & 0b0000_0000_0011_1111 == 0b0000_0000_0010_0000 => {},
+        // ADD, AND
+        0x1 | 0x5 => {
+            registers.push(register_at(instruction, 6));
+            if instruction & 0b0000_0000_0010_0000 == 0 {
+                registers.push(register_at(instruction, 0));
+            }
+        },
+        // JSRR, JMP, NOT
+        0x4 if instruction & 0b0000_1000_0000_0000 == 0 => registers.push(register_at(instruction, 6)),
+        0x9 | 0xC => registers.push(register_at(instruction, 6)),
+        // LD, LDI
+        0x2 => words.push(pc.wrapping_add(pcoffset9(instruction))),
+        0xA => {
+            let pointer = pc.wrapping_add(pcoffset9(instruction));
+            words.push(pointer);
+            if hardware.memory.is_written(pointer) {
+                words.push(hardware.memory.get(pointer));
+            }
+        },
+        // ST, STI
+        0x3 => registers.push(register_at(instruction, 9)),
+        0xB => {
+            registers.push(register_at(instruction, 9));
+            words.push(pc.wrapping_add(pcoffset9(instruction)));
+        },
+        // LDR, STR, the address is only known when the base register is
+        0x6 | 0x7 => {
+            let base = register_at(instruction, 6);
+            registers.push(base);
+            if instruction >> 12 == 0x7 {
+                registers.push(register_at(instruction, 9));
+            } else if hardware.registers.is_written(base) {
+                words.push(hardware.registers.get(base).wrapping_add(offset6(instruction)));
+            }
+        },
+        // OUT, PUTS and PUTSP print what R0 holds or points to
+        0xF if matches!(instruction & 0xFF, 0x21 | 0x22 | 0x24) => registers.push(0),
+        _ => {},
+    }
+
+    let registers = registers.into_iter().filter(|r| !hardware.registers.is_written(*r)).map(|r| format!("R{}", r));
+    // The device registers are written by the devices
+    let words = words.into_iter().filter(|a| *a < 0xFE00 && !hardware.memory.is_written(*a)).map(|a| hardware.describe(a));
+    registers.chain(words).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions;
+    use crate::utils::setup_default_test;
+
+    #[test]
+    fn options() {
+        assert_eq!(OnViolation::parse("stop"), Some(OnViolation::Stop));
+        assert_eq!(Fill::parse("xDEAD"), Some(Fill::Value(0xDEAD)));
+        assert_eq!(Fill::parse("random"), Some(Fill::Random));
+        assert_eq!(Fill::parse("noise"), None);
+    }
+
+    #[test]
+    fn warnings() {
+        let mut hardware = setup_default_test();
+        hardware.sanitizer = Some(Sanitizer::new(OnViolation::Warn));
+        // ADD R1, R1, #1, then LD R2 from x3010 twice
+        hardware.load(&[0x3000, 0b0001_0010_0110_0001, 0b0010_0100_0000_1110, 0b0010_0100_0000_1101]).unwrap();
+        hardware.symbols.insert("DATA", 0x3010);
+
+        for _ in 0..3 {
+            let instruction = hardware.next().unwrap();
+            instructions::process(instruction, &mut hardware).unwrap();
+        }
+        hardware.program_counter.set(0x3002);
+        let instruction = hardware.next().unwrap();
+        instructions::process(instruction, &mut hardware).unwrap();
+
+        assert_eq!(hardware.sanitizer.unwrap().warnings, vec![
+            "R1 is read before anything wrote it, at x3000",
+            "DATA (x3010) is read before anything wrote it, at x3001",
+            "DATA (x3010) is read before anything wrote it, at x3002",
+        ]);
+    }
+
+    #[test]
+    fn stop() {
+        let mut hardware = setup_default_test();
+        hardware.sanitizer = Some(Sanitizer::new(OnViolation::Stop));
+        // AND R0, R0, #0, then OUT and STR R0, R1, #0
+        hardware.load(&[0x3000, 0b0101_0000_0010_0000, 0b1111_0000_0010_0001, 0b0111_0000_0100_0000]).unwrap();
+
+        for _ in 0..2 {
+            let instruction = hardware.next().unwrap();
+            instructions::process(instruction, &mut hardware).unwrap();
+        }
+        let instruction = hardware.next().unwrap();
+        let err = instructions::process(instruction, &mut hardware).unwrap_err();
+        assert_eq!(err.to_string(), "R1 is read before anything wrote it, fault at x3002");
+    }
+
+    #[test]
+    fn fill_memory() {
+        let mut hardware = setup_default_test();
+        fill(&mut hardware, Fill::Value(0xDEAD));
+        hardware.load(&[0x3000, 0x1234]).unwrap();
+
+        assert_eq!(hardware.registers.get(3), 0xDEAD);
+        assert_eq!(hardware.memory.get(0x3001), 0xDEAD);
+        assert_eq!(hardware.memory.get(0x0100), 0);
+        assert!(hardware.memory.is_written(0x3000) && !hardware.memory.is_written(0x3001));
+        assert!(!hardware.registers.is_written(3));
+
+        fill(&mut hardware, Fill::Random);
+        let words = (0x4000..0x4010).map(|address| hardware.memory.get(address)).collect::<BTreeSet<_>>();
+        assert!(words.len() > 1);
+    }
+}
@@ -0,0 +1,223 @@
+// A headless terminal: the output of a program drawn into a grid of characters, following the ANSI escape
+// sequences games use to move the cursor, clear the screen and pick colors.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cell {
+    pub c: char,
+    // SGR color codes, 30-37 and 90-97 for the foreground, 40-47 and 100-107 for the background
+    pub fg: Option<u8>,
+    pub bg: Option<u8>,
+    pub bold: bool,
+}
+impl Default for Cell {
+    fn default() -> Self {
+        Cell { c: ' ', fg: None, bg: None, bold: false }
+    }
+}
+
+pub struct Screen {
+    rows: usize,
+    cols: usize,
+    cells: Vec<Vec<Cell>>,
+    cursor: (usize, usize),
+    saved: (usize, usize),
+    style: Cell,
+    // An escape sequence split between two writes
+    pending: Vec<u8>,
+}
+impl Screen {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Screen {
+            rows,
+            cols,
+            cells: vec![vec![Cell::default(); cols]; rows],
+            cursor: (0, 0),
+            saved: (0, 0),
+            style: Cell::default(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            if !self.pending.is_empty() {
+                self.pending.push(*byte);
+                self.escape();
+                continue;
+            }
+
+            match byte {
+                0x1B => self.pending.push(*byte),
+                b'\n' => {
+                    // Terminals translate the newline to a carriage return and a line feed
+                    self.cursor.1 = 0;
+                    self.line_feed();
+                },
+                b'\r' => self.cursor.1 = 0,
+                0x08 => self.cursor.1 = self.cursor.1.saturating_sub(1),
+                b'\t' => self.cursor.1 = ((self.cursor.1 / 8 + 1) * 8).min(self.cols - 1),
+                0x00..=0x1F | 0x7F => {},
+                c => self.put(*c as char),
+            }
+        }
+    }
+
+    fn put(&mut self, c: char) {
+        if self.cursor.1 >= self.cols {
+            self.cursor.1 = 0;
+            self.line_feed();
+        }
+
+        let (row, col) = self.cursor;
+        self.cells[row][col] = Cell { c, ..self.style };
+        self.cursor.1 += 1;
+    }
+
+    fn line_feed(&mut self) {
+        if self.cursor.0 + 1 < self.rows {
+            self.cursor.0 += 1;
+        } else {
+            self.cells.remove(0);
+            self.cells.push(vec![Cell::default(); self.cols]);
+        }
+    }
+
+    // Runs the pending escape sequence once it is complete.
+    fn escape(&mut self) {
+        let sequence = &self.pending[1..];
+        match sequence {
+            [] | [b'['] => return,
+            [b'[', .., last] if !(0x40..=0x7E).contains(last) => return,
+            [b'[', parameters @ .., command] => {
+                let private = parameters.first() == Some(&b'?');
+                let parameters = String::from_utf8_lossy(parameters).trim_start_matches('?').to_string();
+                let numbers = parameters.split(';').map(|number| number.parse::<usize>().ok()).collect::<Vec<_>>();
+                let command = *command;
+                if !private {
+                    self.control(command, &numbers);
+                }
+            },
+            [b'7'] => self.saved = self.cursor,
+            [b'8'] => self.cursor = self.saved,
+            [b'c'] => *self = Screen::new(self.rows, self.cols),
+            _ => {},
+        }
+        self.pending.clear();
+    }
+
+    fn control(&mut self, command: u8, numbers: &[Option<usize>]) {
+        let number = |index: usize, default: usize| numbers.get(index).copied().flatten().unwrap_or(default);
+        let (row, col) = self.cursor;
+
+        match command {
+            b'A' => self.cursor.0 = row.saturating_sub(number(0, 1).max(1)),
+            b'B' => self.cursor.0 = (row + number(0, 1).max(1)).min(self.rows - 1),
+            b'C' => self.cursor.1 = (col + number(0, 1).max(1)).min(self.cols - 1),
+            b'D' => self.cursor.1 = col.min(self.cols - 1).saturating_sub(number(0, 1).max(1)),
+            b'G' => self.cursor.1 = number(0, 1).clamp(1, self.cols) - 1,
+            b'H' | b'f' => self.cursor = (number(0, 1).clamp(1, self.rows) - 1, number(1, 1).clamp(1, self.cols) - 1),
+            b'J' => {
+                let cursor = row * self.cols + col.min(self.cols);
+                let range = match number(0, 0) {
+                    0 => cursor..self.rows * self.cols,
+                    1 => 0..cursor + 1,
+                    _ => 0..self.rows * self.cols,
+                };
+                for index in range {
+                    if let Some(cell) = self.cells.get_mut(index / self.cols).and_then(|line| line.get_mut(index % self.cols)) {
+                        *cell = Cell::default();
+                    }
+                }
+            },
+            b'K' => {
+                let range = match number(0, 0) {
+                    0 => col.min(self.cols)..self.cols,
+                    1 => 0..(col + 1).min(self.cols),
+                    _ => 0..self.cols,
+                };
+                for cell in &mut self.cells[row][range] {
+                    *cell = Cell::default();
+                }
+            },
+            b'm' => {
+                for code in numbers.iter().map(|code| code.unwrap_or(0)) {
+                    match code {
+                        0 => self.style = Cell::default(),
+                        1 => self.style.bold = true,
+                        22 => self.style.bold = false,
+                        30..=37 | 90..=97 => self.style.fg = Some(code as u8),
+                        39 => self.style.fg = None,
+                        40..=47 | 100..=107 => self.style.bg = Some(code as u8),
+                        49 => self.style.bg = None,
+                        _ => {},
+                    }
+                }
+            },
+            b's' => self.saved = self.cursor,
+            b'u' => self.cursor = self.saved,
+            _ => {},
+        }
+    }
+
+    // The text of a row, counted from 0, without the spaces at its end.
+    pub fn row(&self, row: usize) -> Option<String> {
+        let line = self.cells.get(row)?;
+        Some(line.iter().map(|cell| cell.c).collect::<String>().trim_end().to_string())
+    }
+
+    pub fn cell(&self, row: usize, col: usize) -> Option<Cell> {
+        self.cells.get(row)?.get(col).copied()
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn text(&self) -> String {
+        (0..self.rows).map(|row| self.row(row).unwrap()).collect::<Vec<_>>().join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_and_wrapping() {
+        let mut screen = Screen::new(3, 5);
+        screen.feed(b"ab\ncdefgh\tx");
+
+        assert_eq!(screen.text(), "ab\ncdefg\nh   x");
+        assert_eq!(screen.cursor(), (2, 5));
+
+        screen.feed(b"\nlast");
+        assert_eq!(screen.text(), "cdefg\nh   x\nlast");
+    }
+
+    #[test]
+    fn cursor_and_clearing() {
+        let mut screen = Screen::new(4, 10);
+        screen.feed(b"xxxxxxxxxx\nxxxxxxxxxx\n\x1B[2J\x1B[H2048\x1B[3;4H8\x1B[2D4");
+
+        assert_eq!(screen.text(), "2048\n\n  48\n");
+
+        screen.feed(b"\x1B[1;3H\x1B[K\x1B[3");
+        screen.feed(b"B!");
+        assert_eq!(screen.text(), "20\n\n  48\n  !");
+    }
+
+    #[test]
+    fn colors() {
+        let mut screen = Screen::new(2, 10);
+        screen.feed(b"\x1B[1;31mA\x1B[0mB\x1B[44mC\x1B[?25l");
+
+        assert_eq!(screen.cell(0, 0), Some(Cell { c: 'A', fg: Some(31), bg: None, bold: true }));
+        assert_eq!(screen.cell(0, 1), Some(Cell { c: 'B', ..Cell::default() }));
+        assert_eq!(screen.cell(0, 2).unwrap().bg, Some(44));
+        assert_eq!(screen.row(0).as_deref(), Some("ABC"));
+    }
+}
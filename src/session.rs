@@ -0,0 +1,85 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, LineWriter, Write};
+
+// Every byte the program read with the number of instructions run when it was read, so a run can be replayed exactly.
+// Files are lines like `1523 x61`, `;` starts a comment.
+pub enum Session {
+    Record(Box<dyn Write>),
+    Replay(VecDeque<(u64, u8)>),
+}
+impl Session {
+    pub fn record(path: &str) -> io::Result<Self> {
+        let mut file = LineWriter::new(File::create(path)?);
+        writeln!(file, "; instruction byte")?;
+        Ok(Session::Record(Box::new(file)))
+    }
+
+    pub fn replay(path: &str) -> io::Result<Result<Self, (usize, String)>> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, (usize, String)> {
+        let mut events = VecDeque::new();
+        for (index, line) in text.lines().enumerate() {
+            let fields = line.split(';').next().unwrap_or_default().split_whitespace().collect::<Vec<_>>();
+            let event = match fields[..] {
+                [] => continue,
+                [count, byte] => count.parse().ok().zip(byte.strip_prefix('x').and_then(|byte| u8::from_str_radix(byte, 16).ok())),
+                _ => None,
+            };
+            let event = event.ok_or_else(|| (index + 1, format!("expected an instruction count and a byte, got `{}`", line.trim())))?;
+            if events.back().is_some_and(|(last, _)| *last > event.0) {
+                return Err((index + 1, "instruction counts go back".to_string()));
+            }
+            events.push_back(event);
+        }
+
+        Ok(Session::Replay(events))
+    }
+
+    // Gives the recorded byte, which has to be read at the same instruction as in the recording.
+    pub fn next(&mut self, instructions: u64) -> Option<io::Result<u8>> {
+        let Session::Replay(events) = self else {
+            return None;
+        };
+
+        Some(match events.pop_front() {
+            Some((count, byte)) if count == instructions => Ok(byte),
+            Some((count, _)) => Err(io::Error::other(format!(
+                "replay diverged, the program reads input at instruction {} but the session has it at {}",
+                instructions, count,
+            ))),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the replayed session has no more input")),
+        })
+    }
+
+    pub fn save(&mut self, instructions: u64, byte: u8) -> io::Result<()> {
+        match self {
+            Session::Record(out) => writeln!(out, "{} x{:02X}", instructions, byte),
+            Session::Replay(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_replay() {
+        let mut session = Session::parse("; instruction byte\n12 x61\n12 x00\n40 x0A ; enter\n").unwrap();
+
+        assert_eq!(session.next(12).unwrap().unwrap(), b'a');
+        assert_eq!(session.next(12).unwrap().unwrap(), 0);
+        let err = session.next(39).unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "replay diverged, the program reads input at instruction 39 but the session has it at 40");
+        assert_eq!(session.next(50).unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Session::parse("12 x61\n12 61").err(), Some((2, "expected an instruction count and a byte, got `12 61`".to_string())));
+        assert_eq!(Session::parse("12 x61\n10 x62").err(), Some((2, "instruction counts go back".to_string())));
+    }
+}
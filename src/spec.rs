@@ -0,0 +1,278 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::args::Args;
+use crate::console::Script;
+use crate::hardware::Hardware;
+use crate::preprocess;
+use crate::run;
+use crate::screen::Screen;
+
+// Instructions a program may run between two checks when it never waits for a key.
+const DEFAULT_LIMIT: usize = 1_000_000;
+
+const COLORS: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct Failure {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+struct Runner<'a> {
+    dir: &'a Path,
+    hardware: Option<Hardware<Script>>,
+    screen: Screen,
+    limit: usize,
+    // The program has not run since it got new keys
+    stale: bool,
+}
+impl Runner<'_> {
+    fn hardware(&mut self) -> Result<&mut Hardware<Script>, String> {
+        self.hardware.as_mut().ok_or("no program is loaded, start with `load file.obj`".to_string())
+    }
+
+    // Runs the program until it waits for a key, halts or reaches the limit, and draws its output.
+    fn settle(&mut self) -> Result<&Screen, String> {
+        let limit = self.limit;
+        let stale = std::mem::take(&mut self.stale);
+        let hardware = self.hardware()?;
+        if stale {
+            run::resume(hardware, limit).map_err(|err| format!("program stopped: {}", err))?;
+        }
+
+        let output = std::mem::take(&mut hardware.console.output);
+        self.screen.feed(&output);
+        Ok(&self.screen)
+    }
+
+    fn command(&mut self, tokens: &[String]) -> Result<(), String> {
+        let words = tokens.iter().map(String::as_str).collect::<Vec<_>>();
+        match words[..] {
+            ["load", ref args @ ..] if !args.is_empty() => {
+                // Files are relative to the spec, option values like `--entry MAIN` are kept as they are
+                let args = args.iter().map(|arg| match self.dir.join(arg) {
+                    path if !arg.starts_with('-') && path.exists() => path.display().to_string(),
+                    _ => arg.to_string(),
+                });
+                let args = Args::parse(args)?;
+                let mut hardware = Hardware::with_console(Script::default());
+                run::load(&args, &mut hardware).map_err(|err| format!("could not load program: {}", err))?;
+
+                let (rows, cols) = self.screen.size();
+                self.hardware = Some(hardware);
+                self.screen = Screen::new(rows, cols);
+                self.stale = true;
+            },
+            ["screen", size] => {
+                let (cols, rows) = size
+                    .split_once('x')
+                    .and_then(|(cols, rows)| cols.parse::<usize>().ok().zip(rows.parse::<usize>().ok()))
+                    .filter(|(cols, rows)| *cols > 0 && *rows > 0)
+                    .ok_or(format!("invalid screen size {}, expected columns x rows like 80x24", size))?;
+                self.screen = Screen::new(rows, cols);
+            },
+            ["limit", count] => self.limit = count.parse().map_err(|_| format!("invalid instruction limit {}", count))?,
+            ["input", keys] => {
+                let keys = preprocess::parse_string(keys)?;
+                self.hardware()?.console.push(&keys.chars().map(|c| c as u8).collect::<Vec<_>>());
+                self.stale = true;
+            },
+            ["expect", ref expectation @ ..] => self.expect(expectation)?,
+            _ => return Err(format!("unknown command `{}`", words.join(" "))),
+        }
+
+        Ok(())
+    }
+
+    fn expect(&mut self, words: &[&str]) -> Result<(), String> {
+        let number = |word: &str| word.parse::<usize>().ok().filter(|number| *number > 0);
+        let text = |word: &str| preprocess::parse_string(word);
+
+        match *words {
+            ["row", row, check @ ("contains" | "is"), expected] => {
+                let row = number(row).ok_or(format!("invalid row {}, rows count from 1", row))?;
+                let expected = text(expected)?;
+                let actual = self.settle()?.row(row - 1).ok_or(format!("row {} is past the bottom of the screen", row))?;
+                let matches = if check == "is" { actual == expected } else { actual.contains(&expected) };
+                if !matches {
+                    let check = if check == "is" { "be" } else { "contain" };
+                    return Err(format!("expected row {} to {} {:?}, it is {:?}", row, check, expected, actual));
+                }
+            },
+            ["screen", "contains", expected] => {
+                let expected = text(expected)?;
+                let screen = self.settle()?.text();
+                if !screen.contains(&expected) {
+                    return Err(format!("expected the screen to contain {:?}, it shows\n{}", expected, screen));
+                }
+            },
+            ["cursor", row, col] => {
+                let (row, col) = number(row).zip(number(col)).ok_or("invalid cursor position, rows and columns count from 1")?;
+                let (actual_row, actual_col) = self.settle()?.cursor();
+                if (actual_row + 1, actual_col + 1) != (row, col) {
+                    return Err(format!("expected the cursor at {} {}, it is at {} {}", row, col, actual_row + 1, actual_col + 1));
+                }
+            },
+            ["color", row, col, color] => {
+                let (row, col) = number(row).zip(number(col)).ok_or("invalid cell, rows and columns count from 1")?;
+                let expected = match COLORS.iter().position(|name| *name == color) {
+                    Some(index) => Some(30 + index as u8),
+                    None if color == "default" => None,
+                    None => Some(color.parse().map_err(|_| format!("unknown color {}", color))?),
+                };
+                let cell = self.settle()?.cell(row - 1, col - 1).ok_or(format!("{} {} is outside the screen", row, col))?;
+                if cell.fg != expected {
+                    let name = |code: Option<u8>| code.map_or("default".to_string(), |code| code.to_string());
+                    return Err(format!("expected {:?} at {} {} in color {}, it is in {}", cell.c, row, col, name(expected), name(cell.fg)));
+                }
+            },
+            ["halted"] => {
+                self.settle()?;
+                if !self.hardware()?.halted {
+                    return Err("expected the program to have halted".to_string());
+                }
+            },
+            _ => return Err(format!("unknown expectation `{}`", words.join(" "))),
+        }
+
+        Ok(())
+    }
+}
+
+// Splits a line into words, a string in double quotes is one word with its quotes.
+fn tokens(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+
+        let mut token = c.to_string();
+        if c == '"' {
+            let mut escaped = false;
+            loop {
+                let next = chars.next().ok_or("string is missing its closing quote")?;
+                token.push(next);
+                if next == '"' && !escaped {
+                    break;
+                }
+                escaped = next == '\\' && !escaped;
+            }
+        } else {
+            while let Some(next) = chars.next_if(|next| !next.is_whitespace()) {
+                token.push(next);
+            }
+        }
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+// Runs the commands of a spec in order and stops at the first failing expectation, returns how many passed.
+pub fn run_spec(file: &str, text: &str, dir: &Path) -> Result<usize, Failure> {
+    let mut runner = Runner { dir, hardware: None, screen: Screen::new(24, 80), limit: DEFAULT_LIMIT, stale: false };
+    let mut passed = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let failure = |message: String| Failure { file: file.to_string(), line: index + 1, message };
+        let tokens = tokens(line).map_err(failure)?;
+        if tokens.is_empty() {
+            continue;
+        }
+
+        runner.command(&tokens).map_err(failure)?;
+        if tokens[0] == "expect" {
+            passed += 1;
+        }
+    }
+
+    Ok(passed)
+}
+
+pub fn run_file(path: &str) -> Result<usize, Failure> {
+    let text = fs::read_to_string(path).map_err(|err| Failure { file: path.to_string(), line: 0, message: err.to_string() })?;
+    run_spec(path, &text, Path::new(path).parent().unwrap_or(Path::new("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(directory: &Path, name: &str, source: &str) -> String {
+        let mut preprocessor = preprocess::Preprocessor::default();
+        preprocessor.process_source(name, source).unwrap();
+        let program = crate::assembler::assemble(&preprocessor.lines).unwrap();
+
+        let path = directory.join(name).with_extension("obj");
+        crate::assembler::write(&program, path.to_str().unwrap()).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn tokens_with_strings() {
+        assert_eq!(tokens("expect row 2 is \"a \\\"b\\\" c\" ; comment").unwrap(), ["expect", "row", "2", "is", "\"a \\\"b\\\" c\""]);
+        assert_eq!(tokens("input \"abc").unwrap_err(), "string is missing its closing quote");
+    }
+
+    #[test]
+    fn echo_program() {
+        // Clears the screen, then prints every key in red on the second row
+        let directory = crate::utils::test_directory("spec-echo");
+        let path = program(
+            &directory,
+            "echo",
+            ".ORIG x3000
+        LEA R0, CLEAR
+        PUTS
+LOOP    GETC
+        OUT
+        BRnzp LOOP
+CLEAR   .STRINGZ \"\\e[2J\\e[Htitle\\n\\e[31m\"
+.END",
+        );
+        let spec = format!(
+            "; the keys show up in red
+load {}
+screen 20x5
+expect row 1 is \"title\"
+input \"ab\"
+expect row 2 contains \"ab\"
+expect cursor 2 3
+expect color 2 1 red
+input \"c\"
+expect screen contains \"abc\"",
+            path,
+        );
+
+        assert_eq!(run_spec("echo.lc3test", &spec, Path::new("")), Ok(5));
+
+        let failing = spec.replace("contains \"ab\"", "is \"ba\"");
+        assert_eq!(
+            run_spec("echo.lc3test", &failing, Path::new("")).unwrap_err().to_string(),
+            "echo.lc3test:6: expected row 2 to be \"ba\", it is \"ab\"",
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let failure = |text: &str| run_spec("bad.lc3test", text, Path::new("")).unwrap_err().message;
+
+        assert_eq!(failure("input \"a\""), "no program is loaded, start with `load file.obj`");
+        assert_eq!(failure("screen 80"), "invalid screen size 80, expected columns x rows like 80x24");
+        assert_eq!(failure("press a"), "unknown command `press a`");
+    }
+}
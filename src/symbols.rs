@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Default)]
+pub struct SymbolTable {
+    addresses: HashMap<String, u16>,
+    names: BTreeMap<u16, String>,
+}
+impl SymbolTable {
+    // Parses the symbol table written by lc3as, lines like `//	LOOP              3003`.
+    pub fn parse(text: &str) -> Self {
+        let mut symbols = SymbolTable::default();
+
+        for line in text.lines() {
+            let line = line.trim_start_matches("//");
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if let [name, address] = fields[..] {
+                if let Ok(address) = u16::from_str_radix(address.trim_start_matches(['x', 'X']), 16) {
+                    symbols.insert(name, address);
+                }
+            }
+        }
+
+        symbols
+    }
+
+    // Writes the table in the same layout as lc3as, so other tools can read it back.
+    pub fn to_lc3as(&self) -> String {
+        let mut symbols = self.addresses.iter().collect::<Vec<_>>();
+        symbols.sort_by(|(a_name, a), (b_name, b)| a.cmp(b).then(a_name.cmp(b_name)));
+
+        let mut text = String::new();
+        writeln!(text, "// Symbol table").unwrap();
+        writeln!(text, "// Scope level 0:").unwrap();
+        writeln!(text, "//\tSymbol Name       Page Address").unwrap();
+        writeln!(text, "//\t----------------  ------------").unwrap();
+        for (name, address) in symbols {
+            writeln!(text, "//\t{:<16}  {:04X}", name, address).unwrap();
+        }
+
+        text
+    }
+
+    pub fn read(file_path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(file_path)?))
+    }
+
+    // Reads `program.sym` placed next to `program.obj`, if there is one.
+    pub fn read_for(file_path: &str) -> io::Result<Self> {
+        let path = Path::new(file_path).with_extension("sym");
+        if path.exists() {
+            Self::read(&path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    pub fn extend(&mut self, other: SymbolTable) {
+        for (name, address) in other.addresses {
+            self.insert(&name, address);
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.addresses.insert(name.to_string(), address);
+        self.names.entry(address).or_insert_with(|| name.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    // Describes the address relative to the closest label before it, e.g. `LOOP+3`.
+    pub fn locate(&self, address: u16) -> Option<String> {
+        let (label_address, name) = self.names.range(..=address).next_back()?;
+
+        Some(match address - label_address {
+            0 => name.clone(),
+            offset => format!("{}+{}", name, offset),
+        })
+    }
+
+    pub fn describe(&self, address: u16) -> String {
+        match self.locate(address) {
+            Some(location) => format!("{} (x{:04X})", location, address),
+            None => format!("x{:04X}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "// Symbol table
+// Scope level 0:
+//	Symbol Name       Page Address
+//	----------------  ------------
+//	MAIN              3000
+//	LOOP              3003
+//	DONE              3010
+";
+
+    #[test]
+    fn parse_lc3as_symbols() {
+        let symbols = SymbolTable::parse(SYM);
+
+        assert_eq!(symbols.get("MAIN"), Some(0x3000));
+        assert_eq!(symbols.get("LOOP"), Some(0x3003));
+        assert_eq!(symbols.get("Symbol"), None);
+        assert_eq!(symbols.name_at(0x3010), Some("DONE"));
+    }
+
+    #[test]
+    fn write_lc3as_symbols() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("LOOP", 0x3003);
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("DONE", 0x3010);
+
+        assert_eq!(symbols.to_lc3as(), SYM);
+        assert_eq!(SymbolTable::parse(&symbols.to_lc3as()).get("DONE"), Some(0x3010));
+    }
+
+    #[test]
+    fn locate() {
+        let symbols = SymbolTable::parse(SYM);
+
+        assert_eq!(symbols.locate(0x2FFF), None);
+        assert_eq!(symbols.locate(0x3003).as_deref(), Some("LOOP"));
+        assert_eq!(symbols.locate(0x3006).as_deref(), Some("LOOP+3"));
+        assert_eq!(symbols.describe(0x3006), "LOOP+3 (x3006)");
+        assert_eq!(symbols.describe(0x2000), "x2000");
+    }
+}
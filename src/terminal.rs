@@ -0,0 +1,203 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set by Ctrl-C (or the break key) once `catch_interrupts` is called, the program pauses at the next instruction.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Ctrl-C, the key sending the interrupt unless `--break-key` changes it.
+pub const DEFAULT_BREAK_KEY: u8 = 0x03;
+
+// Puts the terminal in cbreak mode while the program runs: keys arrive one at a time without being echoed,
+// the break key still sends a signal. The settings are restored when the guard is dropped, on a panic and on a signal.
+// Without a terminal, when the input is a pipe or a file, nothing is changed.
+pub struct Terminal {
+    #[cfg(unix)]
+    settings: Option<(i32, libc::termios, libc::termios)>,
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::io;
+    use std::mem::MaybeUninit;
+    use std::sync::OnceLock;
+
+    // The settings to restore from the panic hook and the signal handler, which cannot reach the guard.
+    static ORIGINAL: OnceLock<(i32, libc::termios)> = OnceLock::new();
+
+    pub fn is_terminal(fd: i32) -> bool {
+        unsafe { libc::isatty(fd) == 1 }
+    }
+
+    pub fn get(fd: i32) -> io::Result<libc::termios> {
+        let mut termios = MaybeUninit::uninit();
+        if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { termios.assume_init() })
+    }
+
+    pub fn set(fd: i32, termios: &libc::termios) -> io::Result<()> {
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Only line buffering and echo are turned off, input translation and signals work as usual.
+    pub fn cbreak(mut termios: libc::termios, break_key: u8) -> libc::termios {
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        termios.c_cc[libc::VINTR] = break_key;
+        termios
+    }
+
+    pub fn restore() {
+        if let Some((fd, termios)) = ORIGINAL.get() {
+            let _ = set(*fd, termios);
+        }
+    }
+
+    extern "C" fn terminated(signal: libc::c_int) {
+        restore();
+        unsafe { libc::_exit(128 + signal) };
+    }
+
+    extern "C" fn interrupt(_signal: libc::c_int) {
+        super::INTERRUPTED.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // Without `SA_RESTART`, so a read waiting for a key returns and the program can pause.
+    pub fn catch_interrupts() {
+        unsafe {
+            let mut action = std::mem::zeroed::<libc::sigaction>();
+            action.sa_sigaction = interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        }
+    }
+
+    pub fn install(fd: i32, original: libc::termios) {
+        if ORIGINAL.set((fd, original)).is_err() {
+            return;
+        }
+
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore();
+            hook(info);
+        }));
+
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT] {
+            unsafe { libc::signal(signal, terminated as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+        }
+    }
+}
+
+impl Terminal {
+    #[cfg(unix)]
+    pub fn cbreak(fd: i32, break_key: u8) -> io::Result<Self> {
+        if !unix::is_terminal(fd) {
+            return Ok(Terminal { settings: None });
+        }
+
+        let original = unix::get(fd)?;
+        let cbreak = unix::cbreak(original, break_key);
+        unix::install(fd, original);
+        unix::set(fd, &cbreak)?;
+
+        Ok(Terminal { settings: Some((fd, original, cbreak)) })
+    }
+
+    #[cfg(not(unix))]
+    pub fn cbreak(_fd: i32, _break_key: u8) -> io::Result<Self> {
+        Ok(Terminal {})
+    }
+
+    #[allow(dead_code)]
+    pub fn is_terminal(&self) -> bool {
+        #[cfg(unix)]
+        return self.settings.is_some();
+        #[cfg(not(unix))]
+        return false;
+    }
+
+    // Gives the terminal its own settings back for a while, to read a line with echo.
+    pub fn suspend(&self) {
+        #[cfg(unix)]
+        if let Some((fd, original, _)) = &self.settings {
+            let _ = unix::set(*fd, original);
+        }
+    }
+
+    pub fn resume(&self) {
+        #[cfg(unix)]
+        if let Some((fd, _, cbreak)) = &self.settings {
+            let _ = unix::set(*fd, cbreak);
+        }
+    }
+}
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.suspend();
+    }
+}
+
+pub fn catch_interrupts() {
+    #[cfg(unix)]
+    unix::catch_interrupts();
+}
+
+// Whether an interrupt came since the last call.
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Reads the input, but gives up waiting for a key with `WouldBlock` when an interrupt comes,
+// instead of reading again like `read_exact` does.
+pub struct Input<R>(pub R);
+impl<R: Read> Read for Input<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted && is_interrupted() => {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "interrupted while waiting for input"));
+                },
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn not_a_terminal() {
+        let file = std::fs::File::open("Cargo.toml").unwrap();
+        let terminal = Terminal::cbreak(file.as_raw_fd(), DEFAULT_BREAK_KEY).unwrap();
+
+        assert!(!terminal.is_terminal());
+    }
+
+    #[test]
+    fn cbreak_flags() {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        termios.c_iflag = libc::ICRNL | libc::IXON;
+        termios.c_lflag = libc::ICANON | libc::ECHO | libc::ISIG;
+
+        let termios = unix::cbreak(termios, 0x1D);
+
+        assert_eq!(termios.c_iflag, libc::ICRNL | libc::IXON);
+        assert_eq!(termios.c_lflag, libc::ISIG);
+        assert_eq!((termios.c_cc[libc::VMIN], termios.c_cc[libc::VTIME], termios.c_cc[libc::VINTR]), (1, 0, 0x1D));
+    }
+}
@@ -1,100 +1,524 @@
-use std::io::{Write, Read};
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
 
+use crate::console::Console;
 use crate::hardware::Hardware;
+use crate::isa::{self, Exception};
+use crate::loader;
+use crate::sandbox::FileError;
 
-pub fn process<R: Read, W: Write>(instruction: u16, hardware: &mut Hardware<R, W>) {
-    match instruction & 0xFF {
-        0x20 => {
-            let mut buf = [0; 1];
-            hardware.io.0.read_exact(&mut buf).unwrap();
-            let c = buf[0] as u16;
-
-            hardware.registers.set(0, c);
-            hardware.flags.set(c);
-        }, // GETC
-        0x21 => {
-            let c = hardware.registers.get(0) as u8;
-
-            hardware.io.1.write_all(&[c]).unwrap();
-        }, // OUT
-        0x22 => {
-            let string_loc = hardware.registers.get(0) as u16;
-            let mut offset = 0;
-
-            loop {
-                let c = hardware.get_memory(string_loc + offset);
-                if c == 0 {
-                    break;
-                }
+// A trap routine implemented on the host, it runs in place of the instructions the vector table points to.
+pub type Handler<C> = fn(&mut Hardware<C>) -> Result<(), TrapError>;
+
+#[derive(Debug)]
+pub enum TrapError {
+    Unregistered { vector: u8, location: String },
+    // The trap waits for input that is not there yet, the PC is back at the TRAP so it runs again.
+    Blocked,
+    // An instruction the ISA profile does not allow.
+    Exception { exception: Exception, instruction: u16, location: String },
+    // `--sanitize stop` found a read of a register or a word nothing wrote.
+    Undefined { read: String, location: String },
+    // `--check-calls stop` found a subroutine breaking the calling convention, `stack` lists the calls it is in.
+    Convention { violation: String, location: String, stack: String },
+    Interrupted,
+    Io(io::Error),
+}
+impl TrapError {
+    // The trap at the instruction before the PC, which has already moved past it.
+    pub fn unregistered<C: Console>(vector: u8, hardware: &Hardware<C>) -> Self {
+        let address = hardware.program_counter.get().wrapping_sub(1);
+        TrapError::Unregistered { vector, location: hardware.describe(address) }
+    }
+
+    pub fn exception<C: Console>(exception: Exception, instruction: u16, hardware: &Hardware<C>) -> Self {
+        let address = hardware.program_counter.get().wrapping_sub(1);
+        TrapError::Exception { exception, instruction, location: hardware.describe(address) }
+    }
+}
+impl fmt::Display for TrapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapError::Unregistered { vector, location } => write!(
+                f,
+                "TRAP x{:02X} has no host handler and no routine in the vector table, fault at {}",
+                vector, location,
+            ),
+            TrapError::Blocked => write!(f, "trap is waiting for input"),
+            TrapError::Exception { exception, instruction, location } => {
+                write!(f, "{} by x{:04X}, fault at {}", exception, instruction, location)
+            },
+            TrapError::Undefined { read, location } => write!(f, "{} is read before anything wrote it, fault at {}", read, location),
+            TrapError::Convention { violation, location, stack } => write!(f, "{}, fault at {}{}", violation, location, stack),
+            TrapError::Interrupted => write!(f, "interrupted"),
+            TrapError::Io(err) => write!(f, "could not use the console: {}", err),
+        }
+    }
+}
+impl From<io::Error> for TrapError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock => TrapError::Blocked,
+            _ => TrapError::Io(err),
+        }
+    }
+}
 
-                hardware.io.1.write_all(&[c as u8]).unwrap();
+// The host handlers by vector, the standard traps x20-x25 are registered unless a config removes them.
+pub struct TrapTable<C> {
+    handlers: BTreeMap<u8, (String, Handler<C>)>,
+}
+impl<C: Console> Default for TrapTable<C> {
+    fn default() -> Self {
+        let mut table = TrapTable { handlers: BTreeMap::new() };
+        for (vector, name) in STANDARD_TRAPS.into_iter().zip(["getc", "out", "puts", "in", "putsp", "halt"]) {
+            table.register(vector, name, handler(name).unwrap());
+        }
+
+        table
+    }
+}
+impl<C: Console> TrapTable<C> {
+    // Reads lines like `x26 print-decimal`, `none` removes the handler so the vector table is used.
+    pub fn configure(&mut self, text: &str) -> Result<(), (usize, String)> {
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| (index + 1, message);
+            let fields = line.split(';').next().unwrap_or_default().split_whitespace().collect::<Vec<_>>();
+            let (vector, name) = match fields[..] {
+                [] => continue,
+                [vector, name] => (vector, name),
+                _ => return Err(error(format!("expected a vector and a handler, got `{}`", line.trim()))),
+            };
 
-                offset += 1;
+            let vector = loader::parse_address(vector)
+                .and_then(|vector| u8::try_from(vector).ok())
+                .ok_or_else(|| error(format!("invalid trap vector {}, expected x00-xFF", vector)))?;
+            if name == "none" {
+                self.unregister(vector);
+                continue;
             }
+            let handler = handler(name).ok_or_else(|| error(format!("unknown trap handler {}", name)))?;
+            self.register(vector, name, handler);
+        }
 
-            hardware.io.1.flush().unwrap();
-        }, // PUTS
-        0x23 => {
-            hardware.io.1.flush().unwrap();
-
-            let mut buf = [0; 1];
-            hardware.io.0.read_exact(&mut buf).unwrap();
-            let c = buf[0];
-
-            hardware.registers.set(0, c as u16);
-            hardware.flags.set(c as u16);
-        }, // IN
-        0x24 => {
-            let string_loc = hardware.registers.get(0) as u16;
-            let mut offset = 0;
-
-            loop {
-                let c = hardware.get_memory(string_loc + offset);
-                if c == 0 {
-                    break;
-                }
+        Ok(())
+    }
+}
+impl<C> TrapTable<C> {
+    pub fn register(&mut self, vector: u8, name: &str, handler: Handler<C>) {
+        self.handlers.insert(vector, (name.to_string(), handler));
+    }
+
+    pub fn unregister(&mut self, vector: u8) {
+        self.handlers.remove(&vector);
+    }
+
+    pub fn get(&self, vector: u8) -> Option<Handler<C>> {
+        self.handlers.get(&vector).map(|(_, handler)| *handler)
+    }
+
+    #[allow(dead_code)]
+    pub fn name(&self, vector: u8) -> Option<&str> {
+        self.handlers.get(&vector).map(|(name, _)| name.as_str())
+    }
+}
+
+// How the standard traps talk to the console.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrapMode {
+    // IN reads a key like GETC and HALT stops silently
+    Plain,
+    // The output of the routines in the reference OS, byte for byte
+    Reference,
+}
+impl TrapMode {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "plain" => Some(TrapMode::Plain),
+            "reference" => Some(TrapMode::Reference),
+            _ => None,
+        }
+    }
+
+    pub fn handlers(self) -> &'static [(u8, &'static str)] {
+        match self {
+            TrapMode::Plain => &[(0x23, "in"), (0x25, "halt")],
+            TrapMode::Reference => &[(0x23, "in-prompt"), (0x25, "halt-message")],
+        }
+    }
+}
+
+const IN_PROMPT: &[u8] = b"\nInput a character> ";
+const HALT_MESSAGE: &[u8] = b"\n\n--- Halting the LC-3 ---\n\n";
+
+// The handlers a trap config can refer to by name.
+pub fn handler<C: Console>(name: &str) -> Option<Handler<C>> {
+    Some(match name {
+        "getc" => getc,
+        "out" => out,
+        "puts" => puts,
+        "in" => _in,
+        "putsp" => putsp,
+        "halt" => halt,
+        "in-prompt" => in_prompt,
+        "halt-message" => halt_message,
+        "print-decimal" => print_decimal,
+        "read-line" => read_line,
+        "random" => random,
+        "file-open" => file_open,
+        "file-read" => file_read,
+        "file-write" => file_write,
+        "file-close" => file_close,
+        "file-seek" => file_seek,
+        _ => return None,
+    })
+}
+
+// Runs the host handler of the vector, or jumps to the routine in the vector table like the hardware does.
+pub fn process<C: Console>(instruction: u16, hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let vector = (instruction & 0xFF) as u8;
+    if let Some(handler) = hardware.traps.get(vector) {
+        let result = handler(hardware);
+        match result {
+            Err(TrapError::Blocked) => hardware.retry(),
+            // The input ended and the machine halted, the trap stops there
+            Err(_) if hardware.input_ended => return Ok(()),
+            _ => {},
+        }
+        return result;
+    }
+
+    let routine = hardware.memory.get(vector as u16);
+    if routine == 0 {
+        return Err(TrapError::unregistered(vector, hardware));
+    }
+    // With access control the routine runs in supervisor mode and returns with RTI
+    if hardware.isa.access_control() {
+        isa::enter(hardware, routine);
+        return Ok(());
+    }
+    if let Some(profiler) = &mut hardware.profiler {
+        profiler.call(routine);
+    }
+    hardware.registers.set(7, hardware.program_counter.get());
+    hardware.program_counter.set(routine);
+
+    Ok(())
+}
 
-                let c1: u8 = (c & 0xFF) as u8;
-                hardware.io.1.write_all(&[c1]).unwrap();
+// The routine of the OS a TRAP jumps to like JSR does, leaving the return address in R7 for its RET. None with a host
+// handler, with no routine in the vector table, or with access control where the routine returns with RTI.
+pub fn os_routine<C: Console>(instruction: u16, hardware: &Hardware<C>) -> Option<u16> {
+    let vector = (instruction & 0xFF) as u8;
+    let routine = hardware.memory.get(vector as u16);
+    if hardware.traps.get(vector).is_some() || routine == 0 || hardware.isa.access_control() {
+        return None;
+    }
+    Some(routine)
+}
+
+fn getc<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let c = hardware.read_key(false)?;
+
+    hardware.registers.set(0, c);
+    hardware.flags.set(c);
+    Ok(())
+}
+
+fn out<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let c = hardware.registers.get(0) as u8;
+
+    hardware.console.write_char(c)?;
+    Ok(())
+}
+
+fn puts<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let string_loc = hardware.registers.get(0);
+    let mut offset = 0;
+
+    loop {
+        let c = hardware.get_memory(string_loc + offset)?;
+        if c == 0 {
+            break;
+        }
+
+        hardware.console.write_char(c as u8)?;
+
+        offset += 1;
+    }
+
+    hardware.console.flush()?;
+    Ok(())
+}
+
+fn _in<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    hardware.console.flush()?;
+
+    let c = hardware.read_key(false)?;
+
+    hardware.registers.set(0, c);
+    hardware.flags.set(c);
+    Ok(())
+}
+
+// Prompts for the key and echoes it on its own line.
+fn in_prompt<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    // Run again after waiting for the key, the prompt is already there
+    if hardware.trap_progress.take().is_none() {
+        hardware.console.write_bytes(IN_PROMPT)?;
+    }
+    hardware.console.flush()?;
+
+    let c = hardware.read_key(false).inspect_err(|err| {
+        if err.kind() == io::ErrorKind::WouldBlock {
+            hardware.trap_progress = Some(0);
+        }
+    })?;
+    hardware.console.write_bytes(&[c as u8, b'\n'])?;
+    hardware.console.flush()?;
 
-                let c2: u8 = (c >> 8) as u8;
-                if c2 != 0 {
-                    hardware.io.1.write_all(&[c2]).unwrap();
+    hardware.registers.set(0, c);
+    hardware.flags.set(c);
+    Ok(())
+}
+
+fn putsp<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let string_loc = hardware.registers.get(0);
+    let mut offset = 0;
+
+    loop {
+        let c = hardware.get_memory(string_loc + offset)?;
+        if c == 0 {
+            break;
+        }
+
+        let c1: u8 = (c & 0xFF) as u8;
+        hardware.console.write_char(c1)?;
+
+        let c2: u8 = (c >> 8) as u8;
+        if c2 != 0 {
+            hardware.console.write_char(c2)?;
+        }
+
+        offset += 1;
+    }
+
+    hardware.console.flush()?;
+    Ok(())
+}
+
+fn halt<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    hardware.console.flush()?;
+    hardware.halted = true;
+    Ok(())
+}
+
+fn halt_message<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    hardware.console.write_bytes(HALT_MESSAGE)?;
+    halt(hardware)
+}
+
+// Prints R0 as a signed decimal number.
+fn print_decimal<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let value = hardware.registers.get(0) as i16;
+
+    hardware.console.write_bytes(value.to_string().as_bytes())?;
+    hardware.console.flush()?;
+    Ok(())
+}
+
+// Reads a line, echoing it, into the string at R0, keeping at most R1 characters.
+// R1 is set to the number of characters kept, the newline is not stored. The line also ends with the input.
+fn read_line<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let string_loc = hardware.registers.get(0);
+    let capacity = hardware.registers.get(1);
+    let mut length = hardware.trap_progress.take().unwrap_or(0);
+    hardware.console.flush()?;
+
+    loop {
+        let result = match hardware.read_byte(false) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => hardware.end_of_input(err).map(|_| None),
+            result => result.map(Some),
+        };
+        let c = match result {
+            Ok(Some(c)) => c,
+            Ok(None) => break,
+            Err(err) => {
+                // What was typed is already stored and echoed, waiting again must not lose or repeat it
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    hardware.trap_progress = Some(length);
                 }
+                return Err(err.into());
+            },
+        };
+        hardware.console.write_char(c)?;
+        hardware.console.flush()?;
+        if c == b'\n' || c == b'\r' {
+            break;
+        }
 
-                offset += 1;
-            }
+        if length < capacity {
+            hardware.memory.set(string_loc.wrapping_add(length), c as u16);
+            length += 1;
+        }
+    }
 
-            hardware.io.1.flush().unwrap();
-        }, // PUTSP
-        0x25 => {
-            std::process::exit(1);
-        }, // HALT
-        i @ _  => panic!("unknown trap code: {:#010b}", i),
+    hardware.memory.set(string_loc.wrapping_add(length), 0);
+    hardware.registers.set(1, length);
+    Ok(())
+}
+
+// Sets R0 to a random number below R0, or to any word when R0 is zero.
+fn random<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let bound = hardware.registers.get(0);
+    // Every `RandomState` is seeded differently, which is all the randomness a program needs
+    let value = RandomState::new().build_hasher().finish();
+    let value = if bound == 0 { value as u16 } else { (value % bound as u64) as u16 };
+
+    hardware.registers.set(0, value);
+    hardware.flags.set(value);
+    Ok(())
+}
+
+// GETC, OUT, PUTS, IN, PUTSP and HALT.
+pub const STANDARD_TRAPS: [u8; 6] = [0x20, 0x21, 0x22, 0x23, 0x24, 0x25];
+
+// The vectors `--sandbox` registers the file traps at.
+pub const FILE_TRAPS: [(u8, &str); 5] =
+    [(0x40, "file-open"), (0x41, "file-read"), (0x42, "file-write"), (0x43, "file-close"), (0x44, "file-seek")];
+
+const MAX_PATH: u16 = 255;
+
+// Sets R0 to the result of a file trap, or to the negative error code, and the flags to match.
+fn file_result<C>(hardware: &mut Hardware<C>, result: Result<u16, FileError>) -> Result<(), TrapError> {
+    let value = result.unwrap_or_else(FileError::code);
+    hardware.registers.set(0, value);
+    hardware.flags.set(value);
+    Ok(())
+}
+
+// Opens the file named by the string at R0 in the mode in R1, R0 is set to the handle.
+fn file_open<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let string_loc = hardware.registers.get(0);
+    let mode = hardware.registers.get(1);
+
+    let mut path = String::new();
+    for offset in 0..=MAX_PATH {
+        match hardware.memory.get(string_loc.wrapping_add(offset)) {
+            0 => break,
+            _ if offset == MAX_PATH => return file_result(hardware, Err(FileError::Invalid)),
+            c => path.push(c as u8 as char),
+        }
+    }
+
+    let result = match &mut hardware.sandbox {
+        Some(sandbox) => sandbox.open(&path, mode),
+        None => Err(FileError::Denied),
+    };
+    file_result(hardware, result)
+}
+
+// Reads at most R2 bytes from the file R0 into the words at R1, R0 is set to the number read, 0 at the end of the file.
+fn file_read<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let (handle, buffer, count) = (hardware.registers.get(0), hardware.registers.get(1), hardware.registers.get(2));
+
+    let result = match &mut hardware.sandbox {
+        Some(sandbox) => sandbox.read(handle, count.min(i16::MAX as u16) as usize),
+        None => Err(FileError::Denied),
     };
+    let result = result.map(|bytes| {
+        for (offset, byte) in bytes.iter().enumerate() {
+            hardware.memory.set(buffer.wrapping_add(offset as u16), *byte as u16);
+        }
+        bytes.len() as u16
+    });
+    file_result(hardware, result)
+}
+
+// Writes the low bytes of the R2 words at R1 to the file R0, R0 is set to the number written.
+fn file_write<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let (handle, buffer, count) = (hardware.registers.get(0), hardware.registers.get(1), hardware.registers.get(2));
+    let count = count.min(i16::MAX as u16);
+    let bytes = (0..count).map(|offset| hardware.memory.get(buffer.wrapping_add(offset)) as u8).collect::<Vec<_>>();
+
+    let result = match &mut hardware.sandbox {
+        Some(sandbox) => sandbox.write(handle, &bytes).map(|_| count),
+        None => Err(FileError::Denied),
+    };
+    file_result(hardware, result)
+}
+
+// Closes the file R0, R0 is set to 0.
+fn file_close<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let handle = hardware.registers.get(0);
+
+    let result = match &mut hardware.sandbox {
+        Some(sandbox) => sandbox.close(handle).map(|_| 0),
+        None => Err(FileError::Denied),
+    };
+    file_result(hardware, result)
+}
+
+// Moves the position in the file R0 by the signed R1 from the place in R2, R0 is set to 0 and R1 to the position.
+fn file_seek<C: Console>(hardware: &mut Hardware<C>) -> Result<(), TrapError> {
+    let (handle, offset, whence) = (hardware.registers.get(0), hardware.registers.get(1), hardware.registers.get(2));
+
+    let result = match &mut hardware.sandbox {
+        Some(sandbox) => sandbox.seek(handle, offset as i16, whence),
+        None => Err(FileError::Denied),
+    };
+    if let Ok(position) = result {
+        hardware.registers.set(1, position as u16);
+    }
+    file_result(hardware, result.map(|_| 0))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::console::OnEof;
     use crate::utils::{setup_default_test, setup_test_with_input};
 
     #[test]
     fn getc() {
         let mut hardware = setup_test_with_input("Hello World!");
-        process(0b0000_0000_0010_0000, &mut hardware);
+        process(0b0000_0000_0010_0000, &mut hardware).unwrap();
 
         assert_eq!(hardware.registers.get(0), 'H' as u16);
-        assert_eq!(hardware.flags.is_positive(), true);
+        assert!(hardware.flags.is_positive());
+    }
+
+    #[test]
+    fn getc_at_end_of_input() {
+        let mut hardware = setup_default_test();
+        hardware.program_counter.set(0x3001);
+        process(0b1111_0000_0010_0000, &mut hardware).unwrap();
+        assert!(hardware.halted && hardware.input_ended);
+        assert_eq!(hardware.program_counter.get(), 0x3001);
+
+        let mut hardware = setup_default_test();
+        hardware.on_eof = OnEof::Value(0xFFFF);
+        process(0b1111_0000_0010_0000, &mut hardware).unwrap();
+        assert_eq!(hardware.registers.get(0), 0xFFFF);
+        assert!(hardware.flags.is_negative() && !hardware.halted);
+
+        let mut hardware = setup_default_test();
+        hardware.on_eof = OnEof::Block;
+        hardware.program_counter.set(0x3001);
+        assert!(matches!(process(0b1111_0000_0010_0000, &mut hardware), Err(TrapError::Blocked)));
+        assert_eq!(hardware.program_counter.get(), 0x3000);
     }
 
     #[test]
     fn out() {
         let mut hardware = setup_default_test();
         hardware.registers.set(0, 'H' as u16);
-        process(0b0000_0000_0010_0001, &mut hardware);
+        process(0b0000_0000_0010_0001, &mut hardware).unwrap();
 
-        assert_eq!(hardware.io.1, b"H");
+        assert_eq!(hardware.console.output, b"H");
     }
 
     #[test]
@@ -105,20 +529,47 @@ mod tests {
             'H' as u16, 'e' as u16, 'l' as u16, 'l' as u16, 'o' as u16, ' ' as u16,
             'W' as u16, 'o' as u16, 'r' as u16, 'l' as u16, 'd' as u16, '!' as u16,
             0x0000,
-        ]);
+        ]).unwrap();
 
-        process(0b0000_0000_0010_0010, &mut hardware);
+        process(0b0000_0000_0010_0010, &mut hardware).unwrap();
 
-        assert_eq!(hardware.io.1, b"Hello World!");
+        assert_eq!(hardware.console.output, b"Hello World!");
     }
 
     #[test]
     fn _in() {
         let mut hardware = setup_test_with_input("Hello World!");
-        process(0b0000_0000_0010_0011, &mut hardware);
+        process(0b0000_0000_0010_0011, &mut hardware).unwrap();
 
         assert_eq!(hardware.registers.get(0), 'H' as u16);
-        assert_eq!(hardware.flags.is_positive(), true);
+        assert!(hardware.flags.is_positive());
+    }
+
+    #[test]
+    fn reference_in_and_halt() {
+        let mut hardware = Hardware::with_console(crate::console::Script::default());
+        for (vector, name) in TrapMode::Reference.handlers() {
+            hardware.traps.register(*vector, name, handler(name).unwrap());
+        }
+
+        // Waiting for the key does not show the prompt twice
+        assert!(matches!(process(0b1111_0000_0010_0011, &mut hardware), Err(TrapError::Blocked)));
+        hardware.console.push(b"y");
+        process(0b1111_0000_0010_0011, &mut hardware).unwrap();
+        assert_eq!(hardware.registers.get(0), 'y' as u16);
+
+        process(0b1111_0000_0010_0101, &mut hardware).unwrap();
+        assert!(hardware.halted);
+        assert_eq!(hardware.console.output, b"\nInput a character> y\n\n\n--- Halting the LC-3 ---\n\n");
+    }
+
+    #[test]
+    fn halt() {
+        let mut hardware = setup_default_test();
+        process(0b0000_0000_0010_0101, &mut hardware).unwrap();
+
+        assert!(hardware.halted);
+        assert_eq!(hardware.next(), None);
     }
 
     #[test]
@@ -129,9 +580,136 @@ mod tests {
             'H' as u16, 'e' as u16, 'l' as u16, 'l' as u16, 'o' as u16, ' ' as u16,
             'W' as u16, 'o' as u16, 'r' as u16, 'l' as u16, 'd' as u16, '!' as u16,
             0x0000,
-        ]);
-        process(0b0000_0000_0010_0100, &mut hardware);
+        ]).unwrap();
+        process(0b0000_0000_0010_0100, &mut hardware).unwrap();
+
+        assert_eq!(hardware.console.output, b"Hello World!");
+    }
+
+    #[test]
+    fn configure() {
+        let mut hardware = setup_default_test();
+        hardware.traps.configure("; extra traps\nx26 print-decimal\nx30 random ; R0 below R0\n\nx25 none").unwrap();
+
+        assert_eq!(hardware.traps.name(0x26), Some("print-decimal"));
+        assert_eq!(hardware.traps.name(0x30), Some("random"));
+        assert_eq!(hardware.traps.name(0x21), Some("out"));
+        assert!(hardware.traps.get(0x25).is_none());
+
+        assert_eq!(hardware.traps.configure("x26 print-hex"), Err((1, "unknown trap handler print-hex".to_string())));
+        assert_eq!(hardware.traps.configure("\nx100 random"), Err((2, "invalid trap vector x100, expected x00-xFF".to_string())));
+        assert_eq!(hardware.traps.configure("x26"), Err((1, "expected a vector and a handler, got `x26`".to_string())));
+    }
+
+    #[test]
+    fn print_decimal() {
+        let mut hardware = setup_default_test();
+        hardware.traps.configure("x26 print-decimal").unwrap();
+        hardware.registers.set(0, -1234i16 as u16);
+        process(0b1111_0000_0010_0110, &mut hardware).unwrap();
+
+        assert_eq!(hardware.console.output, b"-1234");
+    }
+
+    #[test]
+    fn read_line() {
+        let mut hardware = setup_test_with_input("Hello World!\nrest");
+        hardware.traps.configure("x27 read-line").unwrap();
+        hardware.registers.set(0, 0x4000);
+        hardware.registers.set(1, 5);
+        process(0b1111_0000_0010_0111, &mut hardware).unwrap();
+
+        assert_eq!(hardware.registers.get(1), 5);
+        assert_eq!((0x4000..0x4006).map(|address| hardware.memory.get(address)).collect::<Vec<_>>(), b"Hello\0".map(u16::from));
+        assert_eq!(hardware.console.output, b"Hello World!\n");
+
+        hardware.on_eof = OnEof::Value(0);
+        hardware.registers.set(1, 5);
+        process(0b1111_0000_0010_0111, &mut hardware).unwrap();
+        assert_eq!(hardware.registers.get(1), 4);
+        assert_eq!(hardware.memory.get(0x4003), 't' as u16);
+    }
+
+    #[test]
+    fn random() {
+        let mut hardware = setup_default_test();
+        hardware.traps.configure("x30 random").unwrap();
+        for _ in 0..100 {
+            hardware.registers.set(0, 6);
+            process(0b1111_0000_0011_0000, &mut hardware).unwrap();
+            assert!(hardware.registers.get(0) < 6);
+        }
+    }
+
+    #[test]
+    fn vector_table() {
+        let mut hardware = setup_default_test();
+        hardware.traps.configure("x25 none").unwrap();
+        hardware.memory.set(0x0025, 0x0400);
+        hardware.program_counter.set(0x3001);
+        process(0b1111_0000_0010_0101, &mut hardware).unwrap();
+
+        assert_eq!(hardware.program_counter.get(), 0x0400);
+        assert_eq!(hardware.registers.get(7), 0x3001);
+        assert!(!hardware.halted);
+    }
+
+    #[test]
+    fn unregistered() {
+        let mut hardware = setup_default_test();
+        hardware.symbols.insert("MAIN", 0x3000);
+        hardware.program_counter.set(0x3003);
+        let err = process(0b1111_0000_0010_0110, &mut hardware).unwrap_err();
+
+        assert!(matches!(err, TrapError::Unregistered { vector: 0x26, .. }));
+        assert_eq!(err.to_string(), "TRAP x26 has no host handler and no routine in the vector table, fault at MAIN+2 (x3002)");
+    }
+
+    #[test]
+    fn file_traps() {
+        let root = crate::utils::test_directory("file-traps");
+        let mut hardware = setup_default_test();
+        hardware.sandbox = Some(crate::sandbox::Sandbox::new(&root).unwrap());
+        for (vector, name) in FILE_TRAPS {
+            hardware.traps.register(vector, name, handler(name).unwrap());
+        }
+        hardware.memory.load(0x4000, &"save.txt\0".bytes().map(u16::from).collect::<Vec<_>>()).unwrap();
+        hardware.memory.load(0x4100, &"score".bytes().map(u16::from).collect::<Vec<_>>()).unwrap();
+
+        hardware.registers.set(0, 0x4000);
+        hardware.registers.set(1, 1);
+        process(0b1111_0000_0100_0000, &mut hardware).unwrap();
+        let handle = hardware.registers.get(0);
+        assert_eq!(handle, 0);
+
+        hardware.registers.set(1, 0x4100);
+        hardware.registers.set(2, 5);
+        process(0b1111_0000_0100_0010, &mut hardware).unwrap();
+        assert_eq!(hardware.registers.get(0), 5);
+        hardware.registers.set(0, handle);
+        process(0b1111_0000_0100_0011, &mut hardware).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("save.txt")).unwrap(), "score");
+
+        hardware.registers.set(0, 0x4000);
+        hardware.registers.set(1, 0);
+        process(0b1111_0000_0100_0000, &mut hardware).unwrap();
+        hardware.registers.set(1, 2);
+        hardware.registers.set(2, 0);
+        process(0b1111_0000_0100_0100, &mut hardware).unwrap();
+        assert_eq!(hardware.registers.get(1), 2);
+        hardware.registers.set(0, handle);
+        hardware.registers.set(1, 0x4200);
+        hardware.registers.set(2, 10);
+        process(0b1111_0000_0100_0001, &mut hardware).unwrap();
+        assert_eq!(hardware.registers.get(0), 3);
+        assert_eq!((0x4200..0x4203).map(|address| hardware.memory.get(address)).collect::<Vec<_>>(), b"ore".map(u16::from));
 
-        assert_eq!(hardware.io.1, b"Hello World!");
+        hardware.memory.load(0x4000, &"../x\0".bytes().map(u16::from).collect::<Vec<_>>()).unwrap();
+        hardware.registers.set(0, 0x4000);
+        hardware.registers.set(1, 0);
+        process(0b1111_0000_0100_0000, &mut hardware).unwrap();
+        assert_eq!(hardware.registers.get(0), FileError::Denied.code());
+        assert!(hardware.flags.is_negative());
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
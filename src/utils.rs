@@ -24,6 +24,24 @@ pub fn register_at(value: u16, at: u16) -> u16 {
     (value >> at) & 0b0000_0000_0000_0111
 }
 
+#[allow(dead_code)]
+pub fn setup_default_test() -> crate::hardware::Hardware<crate::console::Buffer> {
+    crate::hardware::Hardware::with_console(crate::console::Buffer::new(b""))
+}
+// A fresh directory for one test, tests run in parallel and other runs may share the temp directory. The test removes
+// it when it is done.
+#[allow(dead_code)]
+pub fn test_directory(name: &str) -> std::path::PathBuf {
+    let directory = std::env::temp_dir().join(format!("lc3-rust-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+#[allow(dead_code)]
+pub fn setup_test_with_input(input: &'static str) -> crate::hardware::Hardware<crate::console::Buffer> {
+    crate::hardware::Hardware::with_console(crate::console::Buffer::new(input.as_bytes()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,12 +80,3 @@ mod tests {
         assert_eq!(register_at(0b0000_1010_0000_0000, 9), 0b0000_0000_0000_0101);
     }
 }
-
-#[allow(dead_code)]
-pub fn setup_default_test() -> crate::hardware::Hardware<&'static [u8], Vec<u8>> {
-    crate::hardware::Hardware::default_with_io(("".as_bytes(), Vec::new()))
-}
-#[allow(dead_code)]
-pub fn setup_test_with_input(input: &'static str) -> crate::hardware::Hardware<&'static [u8], Vec<u8>> {
-    crate::hardware::Hardware::default_with_io((&input.as_bytes(), Vec::new()))
-}